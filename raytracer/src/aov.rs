@@ -0,0 +1,192 @@
+use palette::Srgb;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::integrator::Integrator;
+use crate::point3d::Point3D;
+use crate::ray::Ray;
+use crate::raytracer::hit_world_object;
+use crate::sphere::Sphere;
+
+#[cfg(test)]
+use crate::camera::Camera;
+#[cfg(test)]
+use crate::integrator::IntegratorKind;
+#[cfg(test)]
+use crate::materials::Lambertian;
+#[cfg(test)]
+use crate::materials::Material;
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+// Arbitrary output variables: debugging views of the data at the first
+// hit, rendered as false colors. Rays that miss the scene are black.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Aov {
+    // shading normal, mapped from [-1, 1] to [0, 1]
+    Normal,
+    // world position, repeating every unit
+    Position,
+    // distance along the viewing direction, white at the camera and black
+    // at `far`
+    Depth { far: f64 },
+    // material color, before any lighting
+    Albedo,
+    // texture coordinates in red and green
+    Uv,
+    // a distinct color for each object in the scene
+    ObjectId,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 6] = [
+        Aov::Normal,
+        Aov::Position,
+        Aov::Depth { far: 10.0 },
+        Aov::Albedo,
+        Aov::Uv,
+        Aov::ObjectId,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Normal => "normal",
+            Aov::Position => "position",
+            Aov::Depth { .. } => "depth",
+            Aov::Albedo => "albedo",
+            Aov::Uv => "u/v",
+            Aov::ObjectId => "object id",
+        }
+    }
+}
+
+fn fract(value: f64) -> f32 {
+    (value - value.floor()) as f32
+}
+
+// Evenly spread hues, so that neighbouring indices get distinct colors.
+fn index_color(index: usize) -> Srgb {
+    let hue = fract(index as f64 * 0.618_033_988_75) * 6.0;
+    let channel = |offset: f32| {
+        let h = (hue + offset) % 6.0;
+        (((h - 3.0).abs() - 1.0).clamp(0.0, 1.0) * 0.8 + 0.2).min(1.0)
+    };
+    Srgb::new(channel(0.0), channel(4.0), channel(2.0))
+}
+
+impl Integrator for Aov {
+    fn ray_color(&self, ray: &Ray, scene: &Config, _lights: &[Sphere]) -> Srgb {
+        let (index, hit_record) = match hit_world_object(&scene.objects, ray, 0.001, f64::MAX) {
+            Some(hit) => hit,
+            None => return Srgb::new(0.0, 0.0, 0.0),
+        };
+        let color = match self {
+            Aov::Normal => {
+                let n = hit_record.normal * 0.5 + Point3D::new(0.5, 0.5, 0.5);
+                Srgb::new(n.x() as f32, n.y() as f32, n.z() as f32)
+            }
+            Aov::Position => Srgb::new(
+                fract(hit_record.point.x()),
+                fract(hit_record.point.y()),
+                fract(hit_record.point.z()),
+            ),
+            Aov::Depth { far } => {
+                let camera = &scene.camera;
+                let view =
+                    (camera.lower_left_corner + camera.horizontal * 0.5 + camera.vertical * 0.5
+                        - camera.origin)
+                        .unit_vector();
+                let depth = (hit_record.point - camera.origin).dot(&view);
+                let d = (1.0 - depth / far).clamp(0.0, 1.0) as f32;
+                Srgb::new(d, d, d)
+            }
+            Aov::Albedo => hit_record.material.albedo(&hit_record),
+            Aov::Uv => Srgb::new(hit_record.u as f32, hit_record.v as f32, 0.0),
+            Aov::ObjectId => index_color(index),
+        };
+        // the renderer gamma corrects every pixel; square the values so the
+        // image holds them unchanged
+        Srgb::new(
+            color.red * color.red,
+            color.green * color.green,
+            color.blue * color.blue,
+        )
+    }
+}
+
+#[cfg(test)]
+fn test_scene() -> Config {
+    Config {
+        width: 80,
+        height: 60,
+        samples_per_pixel: 1,
+        max_depth: 2,
+        spectral: false,
+        sky: None,
+        fog: None,
+        volumes: Vec::new(),
+        camera: Camera::new(
+            Point3D::new(0.0, 0.0, -3.0),
+            Point3D::new(0.0, 0.0, 0.0),
+            Point3D::new(0.0, 1.0, 0.0),
+            20.0,
+            1.333,
+        ),
+        integrator: IntegratorKind::Aov(Aov::Normal),
+        objects: vec![
+            Sphere::new(
+                Point3D::new(0.0, 0.0, 0.0),
+                1.0,
+                Material::Lambertian(Lambertian::new(Srgb::new(0.8, 0.4, 0.2))),
+            ),
+            Sphere::new(
+                Point3D::new(0.0, 0.0, 5.0),
+                1.0,
+                Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+            ),
+        ],
+    }
+}
+
+#[test]
+fn test_aov() {
+    let scene = test_scene();
+    let r = Ray::new(Point3D::new(0.0, 0.0, -3.0), Point3D::new(0.0, 0.0, 1.0));
+
+    // the sphere faces the camera, so its normal is -z
+    assert_eq!(
+        Aov::Normal.ray_color(&r, &scene, &[]),
+        Srgb::new(0.25, 0.25, 0.0)
+    );
+    // the hit point is 2 units in front of the camera
+    let depth = Aov::Depth { far: 4.0 }.ray_color(&r, &scene, &[]);
+    assert_approx_eq!(depth.red, 0.25);
+    let albedo = Aov::Albedo.ray_color(&r, &scene, &[]);
+    assert_approx_eq!(albedo.red, 0.64);
+    assert_approx_eq!(albedo.green, 0.16);
+    assert_approx_eq!(albedo.blue, 0.04);
+    let id = Aov::ObjectId.ray_color(&r, &scene, &[]);
+    assert_eq!(id.red.sqrt(), index_color(0).red);
+    assert_ne!(index_color(0), index_color(1));
+
+    // misses are black
+    let r = Ray::new(Point3D::new(0.0, 0.0, -3.0), Point3D::new(0.0, 1.0, 0.0));
+    for aov in Aov::ALL {
+        assert_eq!(aov.ray_color(&r, &scene, &[]), Srgb::new(0.0, 0.0, 0.0));
+    }
+}
+
+#[test]
+fn test_aov_to_json() {
+    let aov = IntegratorKind::Aov(Aov::Depth { far: 10.0 });
+    let serialized = serde_json::to_string(&aov).unwrap();
+    assert_eq!(serialized, "{\"Aov\":{\"Depth\":{\"far\":10.0}}}");
+    assert_eq!(
+        serde_json::from_str::<IntegratorKind>(&serialized).unwrap(),
+        aov
+    );
+    assert_eq!(
+        serde_json::to_string(&IntegratorKind::Aov(Aov::Normal)).unwrap(),
+        "{\"Aov\":\"Normal\"}"
+    );
+}
@@ -0,0 +1,707 @@
+use palette::Srgb;
+use rand::Rng;
+use std::f64::consts::PI;
+
+use crate::color;
+use crate::config::Config;
+use crate::integrator::sky_color;
+use crate::integrator::Integrator;
+use crate::materials::Scatterable;
+use crate::point3d::Point3D;
+use crate::ray::HitRecord;
+use crate::ray::Ray;
+use crate::raytracer::hit_world;
+use crate::sphere::Sphere;
+
+#[cfg(test)]
+use crate::camera::Camera;
+#[cfg(test)]
+use crate::integrator::region_colors;
+#[cfg(test)]
+use crate::integrator::IntegratorKind;
+#[cfg(test)]
+use crate::integrator::PathTracer;
+#[cfg(test)]
+use crate::materials::Lambertian;
+#[cfg(test)]
+use crate::materials::Light;
+#[cfg(test)]
+use crate::materials::Material;
+#[cfg(test)]
+use crate::materials::{Conductor, OrenNayar, Principled, Sheen};
+#[cfg(test)]
+use std::fs;
+
+// Bidirectional path tracer (Veach 1997, Ch. 10). A camera subpath and a
+// light subpath are traced independently and every pair of their vertices
+// is connected, weighting each strategy with the balance heuristic.
+//
+// Lights are the spheres with a `Light` material; they emit their radiance
+// on their outer side. The sky is only reachable from the camera side,
+// so paths escaping the scene are always weighted one. Camera subpaths are
+// never a single vertex, i.e. light subpaths are not splatted onto the image.
+//
+// Surfaces are connected through `Scatterable::eval` and `pdf`, unless their
+// material only has delta lobes; vertices sampled from a delta lobe are
+// never used for a connection.
+//
+// Fog, volumes, subsurface scattering and spectral rendering aren't
+// supported, so `IntegratorKind::build` refuses scenes with any of them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BidirectionalPathTracer {}
+
+impl BidirectionalPathTracer {
+    pub fn new() -> BidirectionalPathTracer {
+        BidirectionalPathTracer {}
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+    Sky,
+}
+
+#[derive(Clone, Copy)]
+enum Bsdf<'a> {
+    None,
+    Surface(HitRecord<'a>),
+    Emitter,
+}
+
+#[derive(Clone, Copy)]
+struct Vertex<'a> {
+    kind: VertexKind,
+    // for `Sky` vertices, the direction of the escaping ray
+    point: Point3D,
+    // geometric normal: facing `wo` on surfaces, outwards on emitters
+    normal: Point3D,
+    // unit vector towards the previous vertex of the subpath
+    wo: Point3D,
+    bsdf: Bsdf<'a>,
+    // radiance emitted by a `Sky` vertex or an emitter
+    le: Srgb,
+    beta: Srgb,
+    delta: bool,
+    // area densities of sampling this vertex from the previous vertex of
+    // its own subpath, and from the next vertex when traced the other way
+    pdf_fwd: f64,
+    pdf_rev: f64,
+}
+
+impl<'a> Vertex<'a> {
+    fn new(kind: VertexKind, point: Point3D, normal: Point3D, beta: Srgb) -> Vertex<'a> {
+        Vertex {
+            kind,
+            point,
+            normal,
+            wo: Point3D::new(0.0, 0.0, 0.0),
+            bsdf: Bsdf::None,
+            le: color::black(),
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn is_emitter(&self) -> bool {
+        matches!(self.bsdf, Bsdf::Emitter) || self.kind == VertexKind::Sky
+    }
+
+    fn is_connectible(&self) -> bool {
+        match self.kind {
+            VertexKind::Light => true,
+            VertexKind::Surface => match self.bsdf {
+                Bsdf::Surface(hit_record) => !hit_record.material.is_delta(),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    // Radiance leaving an emitter towards `next`.
+    fn le(&self, next: &Vertex) -> Srgb {
+        if self.kind == VertexKind::Sky {
+            return self.le;
+        }
+        if (next.point - self.point).dot(&self.normal) > 0.0 {
+            self.le
+        } else {
+            color::black()
+        }
+    }
+
+    // BSDF value for light scattered from `next` towards `wo`.
+    fn f(&self, next: &Vertex) -> Srgb {
+        let wi = (next.point - self.point).unit_vector();
+        match &self.bsdf {
+            Bsdf::Surface(hit_record) => hit_record.material.eval(hit_record, &wi, &self.wo),
+            _ => color::black(),
+        }
+    }
+
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        if next.kind == VertexKind::Sky {
+            return pdf;
+        }
+        let w = next.point - self.point;
+        let dist_squared = w.length_squared();
+        if dist_squared == 0.0 {
+            return 0.0;
+        }
+        let cosine = next.normal.dot(&w.unit_vector()).abs();
+        pdf * cosine / dist_squared
+    }
+
+    // Area density at `next` of emitting a light subpath from this vertex.
+    fn pdf_light(&self, next: &Vertex) -> f64 {
+        let cosine = (next.point - self.point).unit_vector().dot(&self.normal);
+        if cosine <= 0.0 {
+            return 0.0;
+        }
+        self.convert_density(cosine / PI, next)
+    }
+
+    // Area density of choosing this point when sampling a light.
+    fn pdf_light_origin(&self, lights: &[Sphere]) -> f64 {
+        lights
+            .iter()
+            .find(|l| {
+                ((self.point - l.center).length() - l.radius).abs() < 1e-6 * l.radius.max(1.0)
+            })
+            .map(|l| 1.0 / (lights.len() as f64 * 4.0 * PI * l.radius * l.radius))
+            .unwrap_or(0.0)
+    }
+
+    // Area density at `next` of sampling it from this vertex, reached from
+    // `prev` (or the vertex's own `wo` if `prev` is not given).
+    fn pdf(&self, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        if self.kind == VertexKind::Light {
+            return self.pdf_light(next);
+        }
+        let wo = match prev {
+            Some(prev) => (prev.point - self.point).unit_vector(),
+            None => self.wo,
+        };
+        let wi = (next.point - self.point).unit_vector();
+        match &self.bsdf {
+            Bsdf::Surface(hit_record) => {
+                let pdf = hit_record.material.pdf(hit_record, &wi, &wo);
+                self.convert_density(pdf, next)
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+fn remap0(pdf: f64) -> f64 {
+    if pdf != 0.0 {
+        pdf
+    } else {
+        1.0
+    }
+}
+
+// Cosine weighted direction around `normal`.
+fn sample_cosine(normal: &Point3D) -> Point3D {
+    let direction = *normal + Point3D::random_unit_vector();
+    if direction.near_zero() {
+        *normal
+    } else {
+        direction.unit_vector()
+    }
+}
+
+// Returns the geometry term between two vertices, or zero if they cannot
+// see each other.
+fn geometry(scene: &Config, a: &Vertex, b: &Vertex) -> f64 {
+    let w = b.point - a.point;
+    let distance = w.length();
+    let direction = w / distance;
+    let shadow_ray = Ray::new(a.point, direction);
+    if hit_world(&scene.objects, &shadow_ray, 0.001, distance - 0.001).is_some() {
+        return 0.0;
+    }
+    a.normal.dot(&direction).abs() * b.normal.dot(&direction).abs() / (distance * distance)
+}
+
+fn sample_light<'a>(lights: &[Sphere]) -> (Vertex<'a>, f64) {
+    let mut rng = rand::thread_rng();
+    let light = &lights[rng.gen_range(0..lights.len())];
+    let normal = Point3D::random_unit_vector();
+    let point = light.center + normal * light.radius;
+    let pdf = 1.0 / (lights.len() as f64 * 4.0 * PI * light.radius * light.radius);
+    let mut vertex = Vertex::new(VertexKind::Light, point, normal, color::black());
+    vertex.le = light
+        .material
+        .emission(&light.surface_hit(normal))
+        .unwrap_or_else(color::black);
+    (vertex, pdf)
+}
+
+fn random_walk<'a>(
+    scene: &'a Config,
+    ray: Ray,
+    beta: Srgb,
+    pdf: f64,
+    max_vertices: usize,
+    path: &mut Vec<Vertex<'a>>,
+) {
+    let mut ray = ray;
+    let mut beta = beta;
+    let mut pdf_fwd = pdf;
+    while path.len() < max_vertices {
+        let prev = path.len() - 1;
+        let hit_record = match hit_world(&scene.objects, &ray, 0.001, f64::MAX) {
+            Some(hit_record) => hit_record,
+            None => {
+                // only camera subpaths gather light from the sky
+                if path[0].kind == VertexKind::Camera {
+                    let direction = ray.direction.unit_vector();
+                    let mut vertex = Vertex::new(VertexKind::Sky, direction, -direction, beta);
+                    vertex.le = sky_color(&ray, scene);
+                    vertex.pdf_fwd = pdf_fwd;
+                    path.push(vertex);
+                }
+                break;
+            }
+        };
+        let wo = (-ray.direction).unit_vector();
+        let mut vertex = Vertex::new(
+            VertexKind::Surface,
+            hit_record.point,
+            hit_record.normal,
+            beta,
+        );
+        vertex.wo = wo;
+        vertex.pdf_fwd = path[prev].convert_density(pdf_fwd, &vertex);
+        vertex.bsdf = match hit_record.material.is_light() {
+            true => Bsdf::Emitter,
+            false => Bsdf::Surface(hit_record),
+        };
+        if let Bsdf::Emitter = vertex.bsdf {
+            vertex.le = hit_record
+                .material
+                .emission(&hit_record)
+                .unwrap_or_else(color::black);
+            if !hit_record.front_face {
+                vertex.normal = -vertex.normal;
+            }
+            path.push(vertex);
+            break;
+        }
+        path.push(vertex);
+        if path.len() >= max_vertices {
+            break;
+        }
+
+        let sample = match hit_record.material.sample(&ray, &hit_record) {
+            Some(sample) => sample,
+            None => break,
+        };
+        beta = color::mul(beta, sample.weight);
+        let pdf_rev = match sample.delta {
+            true => {
+                path.last_mut().unwrap().delta = true;
+                pdf_fwd = 0.0;
+                0.0
+            }
+            false => {
+                pdf_fwd = sample.pdf;
+                hit_record.material.pdf(&hit_record, &wo, &sample.wi)
+            }
+        };
+        let current = path.len() - 1;
+        path[prev].pdf_rev = path[current].convert_density(pdf_rev, &path[prev]);
+        ray = Ray::new(hit_record.point, sample.wi);
+    }
+}
+
+fn generate_camera_subpath<'a>(
+    scene: &'a Config,
+    ray: &Ray,
+    max_vertices: usize,
+) -> Vec<Vertex<'a>> {
+    let mut path = Vec::with_capacity(max_vertices);
+    let camera = Vertex::new(
+        VertexKind::Camera,
+        ray.origin,
+        Point3D::new(0.0, 0.0, 0.0),
+        Srgb::new(1.0, 1.0, 1.0),
+    );
+    path.push(camera);
+    random_walk(
+        scene,
+        *ray,
+        Srgb::new(1.0, 1.0, 1.0),
+        1.0,
+        max_vertices,
+        &mut path,
+    );
+    path
+}
+
+fn generate_light_subpath<'a>(
+    scene: &'a Config,
+    lights: &[Sphere],
+    max_vertices: usize,
+) -> Vec<Vertex<'a>> {
+    let mut path = Vec::with_capacity(max_vertices);
+    if lights.is_empty() || max_vertices == 0 {
+        return path;
+    }
+    let (mut light, pdf_pos) = sample_light(lights);
+    let direction = sample_cosine(&light.normal);
+    let pdf_dir = direction.dot(&light.normal) / PI;
+    light.beta = color::scale(light.le, (1.0 / pdf_pos) as f32);
+    light.pdf_fwd = pdf_pos;
+    path.push(light);
+    // Le * cos / (pdf_pos * pdf_dir)
+    let beta = color::scale(
+        light.le,
+        (direction.dot(&light.normal) / (pdf_pos * pdf_dir)) as f32,
+    );
+    random_walk(
+        scene,
+        Ray::new(light.point, direction),
+        beta,
+        pdf_dir,
+        max_vertices,
+        &mut path,
+    );
+    path
+}
+
+fn mis_weight(
+    lights: &[Sphere],
+    light_path: &[Vertex],
+    camera_path: &[Vertex],
+    sampled: Option<Vertex>,
+    s: usize,
+    t: usize,
+) -> f64 {
+    if s + t == 2 || camera_path[t - 1].kind == VertexKind::Sky {
+        return 1.0;
+    }
+    let mut light_path = light_path[..s].to_vec();
+    let mut camera_path = camera_path[..t].to_vec();
+    if let Some(sampled) = sampled {
+        light_path[0] = sampled;
+    }
+
+    // update the reverse densities of the vertices around the connection
+    let pt = camera_path[t - 1];
+    camera_path[t - 1].pdf_rev = if s > 0 {
+        light_path[s - 1].pdf(light_path.get(s.wrapping_sub(2)), &pt)
+    } else {
+        pt.pdf_light_origin(lights)
+    };
+    camera_path[t - 2].pdf_rev = if s > 0 {
+        pt.pdf(Some(&light_path[s - 1]), &camera_path[t - 2])
+    } else {
+        pt.pdf_light(&camera_path[t - 2])
+    };
+    if s > 0 {
+        let qs = light_path[s - 1];
+        light_path[s - 1].pdf_rev = pt.pdf(Some(&camera_path[t - 2]), &qs);
+        light_path[s - 1].delta = false;
+        if s > 1 {
+            light_path[s - 2].pdf_rev = qs.pdf(Some(&pt), &light_path[s - 2]);
+        }
+    }
+    camera_path[t - 1].delta = false;
+
+    // sum the relative densities of the other strategies for this path;
+    // strategies with a single camera vertex are never sampled
+    let mut sum = 0.0;
+    let mut ri = 1.0;
+    for i in (2..t).rev() {
+        ri *= remap0(camera_path[i].pdf_rev) / remap0(camera_path[i].pdf_fwd);
+        if !camera_path[i].delta && !camera_path[i - 1].delta {
+            sum += ri;
+        }
+    }
+    ri = 1.0;
+    for i in (0..s).rev() {
+        ri *= remap0(light_path[i].pdf_rev) / remap0(light_path[i].pdf_fwd);
+        let delta_light_vertex = i > 0 && light_path[i - 1].delta;
+        if !light_path[i].delta && !delta_light_vertex {
+            sum += ri;
+        }
+    }
+    1.0 / (1.0 + sum)
+}
+
+// Contribution of the path made of the first `s` light and `t` camera
+// subpath vertices, without the MIS weight.
+fn connect<'a>(
+    scene: &Config,
+    lights: &[Sphere],
+    light_path: &[Vertex<'a>],
+    camera_path: &[Vertex<'a>],
+    s: usize,
+    t: usize,
+) -> (Srgb, Option<Vertex<'a>>) {
+    let pt = &camera_path[t - 1];
+    if s == 0 {
+        if pt.is_emitter() {
+            return (color::mul(pt.beta, pt.le(&camera_path[t - 2])), None);
+        }
+        return (color::black(), None);
+    }
+    if !pt.is_connectible() {
+        return (color::black(), None);
+    }
+    if s == 1 {
+        let (mut sampled, pdf) = sample_light(lights);
+        sampled.beta = color::scale(sampled.le(pt), (1.0 / pdf) as f32);
+        sampled.pdf_fwd = pdf;
+        let l = color::mul(color::mul(pt.beta, pt.f(&sampled)), sampled.beta);
+        if color::is_black(l) {
+            return (l, Some(sampled));
+        }
+        return (
+            color::scale(l, geometry(scene, pt, &sampled) as f32),
+            Some(sampled),
+        );
+    }
+    let qs = &light_path[s - 1];
+    if !qs.is_connectible() {
+        return (color::black(), None);
+    }
+    let l = color::mul(color::mul(qs.beta, qs.f(pt)), color::mul(pt.f(qs), pt.beta));
+    if color::is_black(l) {
+        return (l, None);
+    }
+    (color::scale(l, geometry(scene, qs, pt) as f32), None)
+}
+
+impl Integrator for BidirectionalPathTracer {
+    fn ray_color(&self, ray: &Ray, scene: &Config, lights: &[Sphere]) -> Srgb {
+        // paths have as many edges as the path tracer traces rays at most
+        let camera_path = generate_camera_subpath(scene, ray, scene.max_depth + 1);
+        let light_path = generate_light_subpath(scene, lights, scene.max_depth);
+
+        let mut l = color::black();
+        for t in 2..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                if s + t - 1 > scene.max_depth {
+                    continue;
+                }
+                let (contribution, sampled) =
+                    connect(scene, lights, &light_path, &camera_path, s, t);
+                if color::is_black(contribution) {
+                    continue;
+                }
+                let weight = mis_weight(lights, &light_path, &camera_path, sampled, s, t);
+                l = color::add(l, color::scale(contribution, weight as f32));
+            }
+        }
+        l
+    }
+}
+
+#[cfg(test)]
+fn average_color(scene: &Config, integrator: &dyn Integrator, samples: usize) -> Srgb {
+    let lights: Vec<Sphere> = scene
+        .objects
+        .iter()
+        .filter(|s| matches!(s.material, Material::Light(_)))
+        .cloned()
+        .collect();
+    let mut rng = rand::thread_rng();
+    let mut sum = color::black();
+    for _ in 0..samples {
+        let r = scene.camera.get_ray(rng.gen::<f64>(), rng.gen::<f64>());
+        sum = color::add(sum, integrator.ray_color(&r, scene, &lights));
+    }
+    color::scale(sum, 1.0 / samples as f32)
+}
+
+#[cfg(test)]
+fn assert_similar(a: Srgb, b: Srgb, tolerance: f32) {
+    for (x, y) in [(a.red, b.red), (a.green, b.green), (a.blue, b.blue)] {
+        assert!(
+            (x - y).abs() <= tolerance * x.max(y).max(0.05),
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+}
+
+#[test]
+fn test_bdpt_diffuse_light() {
+    // a diffuse floor lit by a small light and no sky, which is only
+    // rendered by connecting to the light
+    let light = Sphere::new(
+        Point3D::new(0.0, 1.0, 0.0),
+        0.25,
+        Material::Light(Light::new()),
+    );
+    let scene = Config {
+        width: 10,
+        height: 10,
+        samples_per_pixel: 1,
+        max_depth: 4,
+        spectral: false,
+        sky: None,
+        fog: None,
+        volumes: Vec::new(),
+        camera: Camera::new(
+            Point3D::new(0.0, 2.0, 3.0),
+            Point3D::new(0.0, 0.0, 0.0),
+            Point3D::new(0.0, 1.0, 0.0),
+            60.0,
+            1.0,
+        ),
+        integrator: IntegratorKind::Bidirectional,
+        objects: vec![
+            Sphere::new(
+                Point3D::new(0.0, -1000.0, 0.0),
+                1000.0,
+                Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+            ),
+            light.clone(),
+        ],
+    };
+    let lights = vec![light];
+
+    // directly below the light the floor receives E = pi * (r / d)^2 and
+    // reflects albedo / pi of it
+    let r = Ray::new(Point3D::new(0.0, 0.5, 0.0), Point3D::new(0.0, -1.0, 0.0));
+    let mut sum = 0.0;
+    let samples = 20000;
+    for _ in 0..samples {
+        sum += BidirectionalPathTracer::new()
+            .ray_color(&r, &scene, &lights)
+            .red;
+    }
+    let expected = 0.5 / PI * PI * 0.25 * 0.25;
+    assert!(((sum / samples as f32) as f64 - expected).abs() < 0.1 * expected);
+}
+
+#[test]
+fn test_bdpt_matches_path_tracer() {
+    // every scene in data/, region by region, against the path tracer with
+    // and without light rays
+    let mut paths: Vec<_> = fs::read_dir("data")
+        .expect("Unable to read data/")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+    for path in paths {
+        let json = fs::read(&path).expect("Unable to read file");
+        let mut scene = serde_json::from_slice::<Config>(&json).expect("Unable to parse json");
+        scene.max_depth = 8;
+        let actual = region_colors(&scene, &BidirectionalPathTracer::new(), 3, 3, 20000);
+        for tracer in [PathTracer::without_light_rays(), PathTracer::new()] {
+            let expected = region_colors(&scene, &tracer, 3, 3, 20000);
+            for (e, a) in expected.iter().zip(&actual) {
+                assert_similar(*e, *a, 0.05);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_bdpt_glossy_materials() {
+    // glossy and rough diffuse surfaces lit by a light only, which are
+    // connected to it through their BSDFs
+    let scene = Config {
+        width: 10,
+        height: 10,
+        samples_per_pixel: 1,
+        max_depth: 4,
+        spectral: false,
+        sky: None,
+        fog: None,
+        volumes: Vec::new(),
+        camera: Camera::new(
+            Point3D::new(0.0, 1.0, 3.0),
+            Point3D::new(0.0, 0.0, 0.0),
+            Point3D::new(0.0, 1.0, 0.0),
+            60.0,
+            1.0,
+        ),
+        integrator: IntegratorKind::Bidirectional,
+        objects: vec![
+            Sphere::new(
+                Point3D::new(0.0, -1000.5, 0.0),
+                1000.0,
+                Material::OrenNayar(OrenNayar::new(Srgb::new(0.5, 0.5, 0.5), 20.0)),
+            ),
+            Sphere::new(
+                Point3D::new(-0.5, 0.0, 0.0),
+                0.5,
+                Material::Conductor(Conductor::gold(0.3)),
+            ),
+            Sphere::new(
+                Point3D::new(0.5, 0.0, 0.0),
+                0.5,
+                Material::Principled(Principled::new(Srgb::new(0.2, 0.4, 0.8))),
+            ),
+            Sphere::new(
+                Point3D::new(0.0, 1.5, 0.5),
+                0.3,
+                Material::Light(Light::new().with_intensity(4.0)),
+            ),
+        ],
+    };
+    let expected = average_color(&scene, &PathTracer::without_light_rays(), 200000);
+    let actual = average_color(&scene, &BidirectionalPathTracer::new(), 200000);
+    assert_similar(expected, actual, 0.05);
+}
+
+#[test]
+fn test_bdpt_rough_diffuse_materials() {
+    // Oren-Nayar and sheen surfaces are connected to the light through
+    // their BRDFs, like Lambertian ones
+    let scene = Config {
+        width: 10,
+        height: 10,
+        samples_per_pixel: 1,
+        max_depth: 4,
+        spectral: false,
+        sky: None,
+        fog: None,
+        volumes: Vec::new(),
+        camera: Camera::new(
+            Point3D::new(0.0, 1.0, 3.0),
+            Point3D::new(0.0, 0.0, 0.0),
+            Point3D::new(0.0, 1.0, 0.0),
+            60.0,
+            1.0,
+        ),
+        integrator: IntegratorKind::Bidirectional,
+        objects: vec![
+            Sphere::new(
+                Point3D::new(0.0, -1000.5, 0.0),
+                1000.0,
+                Material::OrenNayar(OrenNayar::new(Srgb::new(0.5, 0.5, 0.5), 20.0)),
+            ),
+            Sphere::new(
+                Point3D::new(0.0, 0.0, 0.0),
+                0.5,
+                Material::Sheen(Sheen::new(
+                    Srgb::new(0.3, 0.05, 0.1),
+                    Srgb::new(1.0, 0.8, 0.9),
+                    0.5,
+                )),
+            ),
+            Sphere::new(
+                Point3D::new(0.0, 1.5, 0.5),
+                0.3,
+                Material::Light(Light::new().with_intensity(4.0)),
+            ),
+        ],
+    };
+    let expected = average_color(&scene, &PathTracer::without_light_rays(), 200000);
+    let actual = average_color(&scene, &BidirectionalPathTracer::new(), 200000);
+    assert_similar(expected, actual, 0.05);
+}
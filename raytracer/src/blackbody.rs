@@ -0,0 +1,79 @@
+use palette::Srgb;
+
+use crate::color;
+
+// Color of thermal emission, from Planck's law integrated against the CIE
+// 1931 color matching functions.
+
+// Multi-lobe gaussian fit of the CIE 1931 color matching functions (Wyman,
+// Sloan and Shirley, 2013), with the wavelength in nanometers.
+pub fn cie_xyz(lambda: f64) -> (f64, f64, f64) {
+    let g = |x: f64, mu: f64, sigma1: f64, sigma2: f64| {
+        let t = (x - mu) / if x < mu { sigma1 } else { sigma2 };
+        (-0.5 * t * t).exp()
+    };
+    let x = 1.056 * g(lambda, 599.8, 37.9, 31.0) + 0.362 * g(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * g(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * g(lambda, 568.8, 46.9, 40.5) + 0.286 * g(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * g(lambda, 437.0, 11.8, 36.0) + 0.681 * g(lambda, 459.0, 26.0, 13.8);
+    (x, y, z)
+}
+
+// Spectral radiance of a black body, up to a constant factor.
+fn planck(lambda: f64, kelvin: f64) -> f64 {
+    let lambda = lambda * 1e-9;
+    // second radiation constant h * c / k, in meter kelvin
+    let c2 = 1.4388e-2;
+    1.0 / (lambda.powi(5) * ((c2 / (lambda * kelvin)).exp() - 1.0))
+}
+
+fn blackbody_xyz(kelvin: f64) -> (f64, f64, f64) {
+    let mut xyz = (0.0, 0.0, 0.0);
+    let mut lambda = 380.0;
+    while lambda <= 780.0 {
+        let (x, y, z) = cie_xyz(lambda);
+        let b = planck(lambda, kelvin);
+        xyz = (xyz.0 + x * b, xyz.1 + y * b, xyz.2 + z * b);
+        lambda += 5.0;
+    }
+    xyz
+}
+
+// Luminance of a black body, relative to one at 1000 K.
+pub fn blackbody_luminance(kelvin: f64) -> f64 {
+    if kelvin <= 0.0 {
+        return 0.0;
+    }
+    blackbody_xyz(kelvin).1 / blackbody_xyz(1000.0).1
+}
+
+// Linear color of a black body, with a luminance of one.
+pub fn blackbody_color(kelvin: f64) -> Srgb {
+    if kelvin <= 0.0 {
+        return color::black();
+    }
+    let (x, y, z) = blackbody_xyz(kelvin);
+    let (x, z) = (x / y, z / y);
+    Srgb::new(
+        (3.2406 * x - 1.5372 - 0.4986 * z).max(0.0) as f32,
+        (-0.9689 * x + 1.8758 + 0.0415 * z).max(0.0) as f32,
+        (0.0557 * x - 0.2040 + 1.0570 * z).max(0.0) as f32,
+    )
+}
+
+#[test]
+fn test_blackbody() {
+    // low temperatures glow red, high temperatures blue
+    let ember = blackbody_color(1500.0);
+    assert!(ember.red > ember.green && ember.green > ember.blue);
+    let sky = blackbody_color(12000.0);
+    assert!(sky.blue > sky.red);
+    // D65 is close to a 6500 K black body, which is close to white
+    let white = blackbody_color(6500.0);
+    assert!((white.red - white.blue).abs() < 0.15);
+    assert!((color::luminance(white) - 1.0).abs() < 0.05);
+
+    assert_eq!(blackbody_luminance(1000.0), 1.0);
+    assert!(blackbody_luminance(2000.0) > 100.0);
+    assert_eq!(blackbody_color(0.0), color::black());
+}
@@ -1,5 +1,8 @@
+use palette::Srgb;
 use serde::{Deserialize, Serialize};
 
+use crate::blackbody::blackbody_color;
+use crate::color;
 use crate::point3d::Point3D;
 use crate::ray::Ray;
 
@@ -24,6 +27,10 @@ pub struct Camera {
     vup: Point3D,
     vfov: f64, // vertical field-of-view in degrees
     aspect: f64,
+    // Color temperature in kelvin which is rendered as white, e.g. 3200
+    // for a scene lit by tungsten lights.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    white_balance: Option<f64>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -33,11 +40,17 @@ pub struct CameraParams {
     pub vup: Point3D,
     pub vfov: f64, // vertical field-of-view in degrees
     pub aspect: f64,
+    #[serde(default)]
+    pub white_balance: Option<f64>,
 }
 
 impl From<CameraParams> for Camera {
     fn from(p: CameraParams) -> Self {
-        Camera::new(p.look_from, p.look_at, p.vup, p.vfov, p.aspect)
+        let camera = Camera::new(p.look_from, p.look_at, p.vup, p.vfov, p.aspect);
+        match p.white_balance {
+            Some(kelvin) => camera.with_white_balance(kelvin),
+            None => camera,
+        }
     }
 }
 
@@ -73,6 +86,27 @@ impl Camera {
             vup,
             vfov,
             aspect,
+            white_balance: None,
+        }
+    }
+
+    pub fn with_white_balance(self, kelvin: f64) -> Camera {
+        Camera {
+            white_balance: Some(kelvin),
+            ..self
+        }
+    }
+
+    // Scales the channels of a linear color so that a black body at the
+    // white balance temperature comes out white.
+    pub fn white_balance(&self, color: Srgb) -> Srgb {
+        match self.white_balance.map(blackbody_color) {
+            Some(white) if !color::is_black(white) => Srgb::new(
+                color.red / white.red.max(1e-3),
+                color.green / white.green.max(1e-3),
+                color.blue / white.blue.max(1e-3),
+            ),
+            _ => color,
         }
     }
 
@@ -143,3 +177,30 @@ fn test_to_json() {
     assert_eq!(camera.horizontal, c.horizontal);
     assert_eq!(camera.vertical, c.vertical);
 }
+
+#[test]
+fn test_camera_white_balance() {
+    let camera = Camera::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        Point3D::new(0.0, 0.0, -1.0),
+        Point3D::new(0.0, 1.0, 0.0),
+        90.0,
+        1.0,
+    );
+    let color = Srgb::new(0.5, 0.25, 1.0);
+    assert_eq!(camera.white_balance(color), color);
+
+    let camera = camera.with_white_balance(3200.0);
+    let white = camera.white_balance(blackbody_color(3200.0));
+    assert_approx_eq!(white.red, 1.0);
+    assert_approx_eq!(white.green, 1.0);
+    assert_approx_eq!(white.blue, 1.0);
+    // a daylight white looks blue under a tungsten white balance
+    let daylight = camera.white_balance(Srgb::new(1.0, 1.0, 1.0));
+    assert!(daylight.blue > daylight.red);
+
+    let json = r#"{"look_from":{"x":0.0,"y":0.0,"z":0.0},"look_at":{"x":0.0,"y":0.0,"z":-1.0},"vup":{"x":0.0,"y":1.0,"z":0.0},"vfov":90.0,"aspect":1.0,"white_balance":3200.0}"#;
+    let parsed: Camera = serde_json::from_str(json).unwrap();
+    assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    assert_eq!(parsed.white_balance(color), camera.white_balance(color));
+}
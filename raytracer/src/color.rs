@@ -0,0 +1,40 @@
+use palette::Srgb;
+
+// Component-wise arithmetic on colors. The renderer keeps linear radiance
+// values in `Srgb` and only applies gamma correction when writing pixels.
+
+pub fn black() -> Srgb {
+    Srgb::new(0.0, 0.0, 0.0)
+}
+
+pub fn add(a: Srgb, b: Srgb) -> Srgb {
+    Srgb::new(a.red + b.red, a.green + b.green, a.blue + b.blue)
+}
+
+pub fn mul(a: Srgb, b: Srgb) -> Srgb {
+    Srgb::new(a.red * b.red, a.green * b.green, a.blue * b.blue)
+}
+
+pub fn scale(a: Srgb, k: f32) -> Srgb {
+    Srgb::new(a.red * k, a.green * k, a.blue * k)
+}
+
+pub fn is_black(a: Srgb) -> bool {
+    a.red == 0.0 && a.green == 0.0 && a.blue == 0.0
+}
+
+pub fn luminance(a: Srgb) -> f32 {
+    0.2126 * a.red + 0.7152 * a.green + 0.0722 * a.blue
+}
+
+#[test]
+fn test_color_arithmetic() {
+    let a = Srgb::new(0.5, 0.25, 1.0);
+    let b = Srgb::new(2.0, 4.0, 0.0);
+    assert_eq!(add(a, b), Srgb::new(2.5, 4.25, 1.0));
+    assert_eq!(mul(a, b), Srgb::new(1.0, 1.0, 0.0));
+    assert_eq!(scale(a, 2.0), Srgb::new(1.0, 0.5, 2.0));
+    assert!(is_black(black()));
+    assert!(!is_black(a));
+    assert_eq!(luminance(Srgb::new(1.0, 1.0, 1.0)), 1.0);
+}
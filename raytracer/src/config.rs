@@ -3,62 +3,257 @@ use palette::Srgb;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
+use std::f64::consts::PI;
 use std::fs::File;
+use std::io;
 use std::io::BufReader;
 
 use crate::camera::Camera;
+use crate::color;
+use crate::daylight::Daylight;
+use crate::daylight::PhysicalSky;
+use crate::distribution::Distribution2D;
+use crate::hdr::load_hdr_image;
+use crate::integrator::IntegratorKind;
 use crate::materials::Glass;
 use crate::materials::Lambertian;
 use crate::materials::Material;
 use crate::materials::Metal;
+use crate::medium::Fog;
 use crate::point3d::Point3D;
 use crate::sphere::Sphere;
+use crate::volume::Volume;
 
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
 #[cfg(test)]
 use std::fs;
 
 #[serde_with::serde_as]
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(from = "SkyParams")]
 pub struct Sky {
     // If provided, the sky will be rendered using the equirectangular
     // projected texture loaded from an image file at this path. Else,
-    // a light blue colored sky will be used.
+    // a light blue colored sky will be used. Radiance (.hdr) and portable
+    // float map (.pfm) images keep their full dynamic range; JPEG images
+    // are converted from the display gamma to linear values.
     #[serde_as(as = "TextureOptionPixelsAsPath")]
-    pub texture: Option<(Vec<u8>, usize, usize, String)>,
+    pub texture: Option<(Vec<f32>, usize, usize, String)>,
+    // Rotation of the sky around the vertical axis, in degrees.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub rotation: f64,
+    // Multiplier applied to the radiance of the sky.
+    #[serde(skip_serializing_if = "is_default_intensity")]
+    pub intensity: f32,
+    // Without a texture, an analytic daylight model with a sun can be
+    // used instead of the blue gradient.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub physical: Option<PhysicalSky>,
+    #[serde(skip)]
+    daylight: Option<Daylight>,
+    // Distribution of the texture's brightness over the image, for
+    // sampling directions towards bright parts of the sky.
+    #[serde(skip)]
+    distribution: Option<Distribution2D>,
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Deserialize)]
+pub struct SkyParams {
+    #[serde_as(as = "TextureOptionPixelsAsPath")]
+    pub texture: Option<(Vec<f32>, usize, usize, String)>,
+    #[serde(default)]
+    pub rotation: f64,
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+    #[serde(default)]
+    pub physical: Option<PhysicalSky>,
+}
+
+impl From<SkyParams> for Sky {
+    fn from(p: SkyParams) -> Self {
+        Sky::new(p.texture, p.rotation, p.intensity, p.physical)
+    }
+}
+
+fn is_zero(value: &f64) -> bool {
+    *value == 0.0
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+fn is_default_intensity(value: &f32) -> bool {
+    *value == default_intensity()
 }
 
 impl Sky {
+    pub fn new(
+        texture: Option<(Vec<f32>, usize, usize, String)>,
+        rotation: f64,
+        intensity: f32,
+        physical: Option<PhysicalSky>,
+    ) -> Sky {
+        // rows near the poles cover a smaller solid angle
+        let distribution = texture.as_ref().map(|(pixels, width, height, _)| {
+            let func: Vec<f64> = pixels
+                .chunks(3)
+                .enumerate()
+                .map(|(i, p)| {
+                    let theta = PI * ((i / width) as f64 + 0.5) / *height as f64;
+                    color::luminance(Srgb::new(p[0], p[1], p[2])).max(0.0) as f64 * theta.sin()
+                })
+                .collect();
+            Distribution2D::new(&func, *width, *height)
+        });
+        Sky {
+            texture,
+            rotation,
+            intensity,
+            physical,
+            daylight: physical.as_ref().map(Daylight::new),
+            distribution,
+        }
+    }
+
     pub fn new_default_sky() -> Sky {
-        Sky { texture: None }
+        Sky::new(None, 0.0, default_intensity(), None)
+    }
+
+    // Equirectangular (latitude-longitude) coordinates of a direction: u
+    // goes around the horizon, with the center of the image towards -z,
+    // and v goes from straight up (0) to straight down (1).
+    pub fn direction_to_uv(&self, direction: &Point3D) -> (f64, f64) {
+        let d = direction.unit_vector();
+        let phi = d.x().atan2(-d.z()) - self.rotation.to_radians();
+        let u = (phi / (2.0 * PI) + 0.5).rem_euclid(1.0);
+        let v = d.y().clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+
+    // Inverse of `direction_to_uv`.
+    pub fn uv_to_direction(&self, u: f64, v: f64) -> Point3D {
+        let phi = (u - 0.5) * 2.0 * PI + self.rotation.to_radians();
+        let theta = v * PI;
+        Point3D::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        )
+    }
+
+    // Whether directions can be sampled proportionally to the brightness
+    // of the sky, which is the case for textures and the daylight model.
+    pub fn is_importance_sampled(&self) -> bool {
+        self.distribution.is_some() || self.daylight.is_some()
+    }
+
+    // Samples a direction towards the sky from two uniform numbers,
+    // returning it with its density with respect to solid angle.
+    pub fn sample_direction(&self, u1: f64, u2: f64) -> Option<(Point3D, f64)> {
+        let distribution = match (&self.distribution, &self.daylight) {
+            (Some(distribution), _) => distribution,
+            (None, Some(daylight)) => return Some(daylight.sample_direction(u1, u2)),
+            (None, None) => return None,
+        };
+        let ((u, v), pdf) = distribution.sample(u1, u2);
+        let sin_theta = (v * PI).sin();
+        if pdf == 0.0 || sin_theta == 0.0 {
+            return None;
+        }
+        Some((
+            self.uv_to_direction(u, v),
+            pdf / (2.0 * PI * PI * sin_theta),
+        ))
+    }
+
+    // Density of `sample_direction` returning a direction.
+    pub fn pdf(&self, direction: &Point3D) -> f64 {
+        match (&self.distribution, &self.daylight) {
+            (Some(distribution), _) => {
+                let (u, v) = self.direction_to_uv(direction);
+                let sin_theta = (v * PI).sin();
+                if sin_theta == 0.0 {
+                    return 0.0;
+                }
+                distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+            }
+            (None, Some(daylight)) => daylight.pdf(direction),
+            (None, None) => 0.0,
+        }
+    }
+
+    // Radiance arriving from the sky along a direction.
+    pub fn color(&self, direction: &Point3D) -> Srgb {
+        let color = match (&self.texture, &self.daylight) {
+            (Some((pixels, width, height, _)), _) => {
+                let (u, v) = self.direction_to_uv(direction);
+                let x = ((u * *width as f64) as usize).min(*width - 1);
+                let y = ((v * *height as f64) as usize).min(*height - 1);
+                let i = (y * *width + x) * 3;
+                Srgb::new(pixels[i], pixels[i + 1], pixels[i + 2])
+            }
+            (None, Some(daylight)) => daylight.radiance(direction),
+            (None, None) => {
+                let t = (0.5 * (direction.unit_vector().y() + 1.0)).clamp(0.0, 1.0) as f32;
+                Srgb::new(
+                    (1.0 - t) * 1.0 + t * 0.5,
+                    (1.0 - t) * 1.0 + t * 0.7,
+                    (1.0 - t) * 1.0 + t * 1.0,
+                )
+            }
+        };
+        color::scale(color, self.intensity)
     }
 }
 
-fn load_texture_image(path: &str) -> (Vec<u8>, usize, usize, String) {
-    let file = File::open(path).expect(path);
+fn load_texture_image(path: &str) -> Result<(Vec<f32>, usize, usize, String), io::Error> {
+    let lower = path.to_lowercase();
+    if lower.ends_with(".hdr") || lower.ends_with(".pfm") {
+        let (pixels, width, height) = load_hdr_image(path)?;
+        return Ok((pixels, width, height, path.to_string()));
+    }
+    let file = File::open(path)?;
     let mut decoder = Decoder::new(BufReader::new(file));
-    let pixels = decoder.decode().expect("failed to decode image");
+    let pixels = decoder
+        .decode()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
     let metadata = decoder.info().unwrap();
-    (
+    // 8-bit images are stored with the same gamma 2 the renderer writes
+    let pixels = pixels
+        .iter()
+        .map(|p| {
+            let value = *p as f32 / 255.0;
+            value * value
+        })
+        .collect();
+    Ok((
         pixels,
         metadata.width as usize,
         metadata.height as usize,
         path.to_string(),
-    )
+    ))
 }
 
 serde_with::serde_conv!(
     TextureOptionPixelsAsPath,
-    Option<(Vec<u8>, usize, usize, String)>,
-    |texture: &Option<(Vec<u8>, usize, usize, String)>| {
+    Option<(Vec<f32>, usize, usize, String)>,
+    |texture: &Option<(Vec<f32>, usize, usize, String)>| {
         match texture {
             Some(tuple) => tuple.3.clone(),
             None => "".to_string(),
         }
     },
-    |value: &str| -> Result<_, std::convert::Infallible> {
+    |value: &str| -> Result<_, io::Error> {
         match value {
             "" => Ok(None),
-            _ => Ok(Some(load_texture_image(value))),
+            _ => Ok(Some(load_texture_image(value)?)),
         }
     }
 );
@@ -69,8 +264,22 @@ pub struct Config {
     pub height: usize,
     pub samples_per_pixel: u32,
     pub max_depth: usize,
+    // Trace a single wavelength per camera ray instead of RGB colors, for
+    // dispersion in glass.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub spectral: bool,
     pub sky: Option<Sky>,
+    // Medium filling the whole scene, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fog: Option<Fog>,
+    // Voxel grid volumes, such as clouds or smoke.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<Volume>,
     pub camera: Camera,
+    // Light transport algorithm used to render the scene; the path tracer
+    // unless specified otherwise.
+    #[serde(default, skip_serializing_if = "IntegratorKind::is_default")]
+    pub integrator: IntegratorKind,
     pub objects: Vec<Sphere>,
 }
 
@@ -81,7 +290,10 @@ fn test_to_json() {
         height: 100,
         samples_per_pixel: 1,
         max_depth: 1,
+        spectral: false,
         sky: Some(Sky::new_default_sky()),
+        fog: None,
+        volumes: Vec::new(),
         camera: Camera::new(
             Point3D::new(0.0, 0.0, 0.0),
             Point3D::new(0.0, 0.0, -1.0),
@@ -89,6 +301,7 @@ fn test_to_json() {
             90.0,
             1.0,
         ),
+        integrator: IntegratorKind::Path,
         objects: vec![Sphere::new(
             Point3D::new(0.0, 0.0, -1.0),
             0.5,
@@ -108,7 +321,10 @@ fn test_sky_perms_to_from_json() {
         height: 100,
         samples_per_pixel: 1,
         max_depth: 1,
+        spectral: false,
         sky: None,
+        fog: None,
+        volumes: Vec::new(),
         camera: Camera::new(
             Point3D::new(0.0, 0.0, 0.0),
             Point3D::new(0.0, 0.0, -1.0),
@@ -116,6 +332,7 @@ fn test_sky_perms_to_from_json() {
             90.0,
             1.0,
         ),
+        integrator: IntegratorKind::Path,
         objects: vec![Sphere::new(
             Point3D::new(0.0, 0.0, -1.0),
             0.5,
@@ -146,6 +363,51 @@ fn test_sky_perms_to_from_json() {
     )
 }
 
+#[test]
+fn test_sky_direction_to_uv() {
+    let mut sky = Sky::new_default_sky();
+    let (u, v) = sky.direction_to_uv(&Point3D::new(0.0, 0.0, -1.0));
+    assert_approx_eq!(u, 0.5);
+    assert_approx_eq!(v, 0.5);
+    let (_, v) = sky.direction_to_uv(&Point3D::new(0.0, 2.0, 0.0));
+    assert_approx_eq!(v, 0.0);
+    let (u, _) = sky.direction_to_uv(&Point3D::new(1.0, 0.0, 0.0));
+    assert_approx_eq!(u, 0.75);
+
+    // rotating the sky by 90 degrees brings +x to the center
+    sky.rotation = 90.0;
+    let (u, _) = sky.direction_to_uv(&Point3D::new(1.0, 0.0, 0.0));
+    assert_approx_eq!(u, 0.5);
+}
+
+#[test]
+fn test_sky_hdr_texture() {
+    let mut bytes = b"PF\n2 1\n-1.0\n".to_vec();
+    for value in [8.0f32, 4.0, 2.0, 0.5, 0.25, 0.125] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    fs::write("/tmp/test_sky.pfm", bytes).unwrap();
+    let sky = serde_json::from_str::<Sky>(
+        "{\"texture\":\"/tmp/test_sky.pfm\",\"rotation\":0.0,\"intensity\":2.0}",
+    )
+    .unwrap();
+    // values above 1.0 are kept, and scaled by the intensity
+    assert_eq!(
+        sky.color(&Point3D::new(-1.0, 0.0, 0.0)),
+        Srgb::new(16.0, 8.0, 4.0)
+    );
+    assert_eq!(
+        sky.color(&Point3D::new(1.0, 0.0, 0.0)),
+        Srgb::new(1.0, 0.5, 0.25)
+    );
+    assert_eq!(
+        serde_json::to_string(&sky).unwrap(),
+        "{\"texture\":\"/tmp/test_sky.pfm\",\"intensity\":2.0}"
+    );
+
+    assert!(serde_json::from_str::<Sky>("{\"texture\":\"/tmp/missing.hdr\"}").is_err());
+}
+
 fn _make_cover_world() -> Vec<Sphere> {
     let mut world = Vec::new();
 
@@ -232,7 +494,10 @@ fn test_cover_scene_to_json() {
         height: 600,
         samples_per_pixel: 64,
         max_depth: 50,
+        spectral: false,
         sky: Some(Sky::new_default_sky()),
+        fog: None,
+        volumes: Vec::new(),
         camera: Camera::new(
             Point3D::new(13.0, 2.0, 3.0),
             Point3D::new(0.0, 0.0, 0.0),
@@ -240,6 +505,7 @@ fn test_cover_scene_to_json() {
             20.0,
             (800.0 / 600.0) as f64,
         ),
+        integrator: IntegratorKind::Path,
         objects: _make_cover_world(),
     };
     let serialized = serde_json::to_string_pretty(&config).unwrap();
@@ -0,0 +1,284 @@
+use palette::Srgb;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+use crate::color;
+use crate::point3d::Point3D;
+
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+// Parameters of the analytic daylight model. Angles are in degrees; the
+// azimuth turns from -z (0) towards +x (90), like the sky texture.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct PhysicalSky {
+    pub sun_elevation: f64,
+    pub sun_azimuth: f64,
+    // haziness of the atmosphere, from 2 (clear) to 10 (hazy)
+    pub turbidity: f64,
+    pub ground_albedo: f32,
+}
+
+impl PhysicalSky {
+    pub fn new(
+        sun_elevation: f64,
+        sun_azimuth: f64,
+        turbidity: f64,
+        ground_albedo: f32,
+    ) -> PhysicalSky {
+        PhysicalSky {
+            sun_elevation,
+            sun_azimuth,
+            turbidity,
+            ground_albedo,
+        }
+    }
+}
+
+// Radiance is expressed in units of 10,000 cd/m^2, which puts the zenith of
+// a clear sky with the sun high up at about one.
+const LUMINANCE_SCALE: f64 = 0.1;
+// mean angular radius of the sun
+const SUN_ANGULAR_RADIUS: f64 = 0.2725 * PI / 180.0;
+// luminance of the sun outside of the atmosphere, in kcd/m^2
+const SUN_LUMINANCE: f64 = 2.0e6;
+
+// Perez et al. luminance distribution, with the coefficients A to E.
+fn perez(coefficients: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coefficients;
+    (1.0 + a * (b / cos_theta.max(1e-3)).exp())
+        * (1.0 + c * (d * gamma).exp() + e * gamma.cos() * gamma.cos())
+}
+
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Srgb {
+    if y <= 0.0 {
+        return color::black();
+    }
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+    Srgb::new(
+        (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0) as f32,
+        (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0) as f32,
+        (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0) as f32,
+    )
+}
+
+// Clear sky model of Preetham, Shirley and Smits, "A Practical Analytic
+// Model for Daylight" (1999), with a sun disk attenuated by Rayleigh and
+// aerosol scattering, and a diffuse ground below the horizon.
+#[derive(Debug, Clone)]
+pub struct Daylight {
+    sun_direction: Point3D,
+    sun_radiance: Srgb,
+    coefficients: [[f64; 5]; 3],
+    // zenith values of x, y and Y, divided by the Perez function at the
+    // zenith so that the sky matches them there
+    zenith: [f64; 3],
+    ground: Srgb,
+}
+
+impl Daylight {
+    pub fn new(params: &PhysicalSky) -> Daylight {
+        let t = params.turbidity;
+        let elevation = params.sun_elevation.clamp(0.0, 90.0).to_radians();
+        let azimuth = params.sun_azimuth.to_radians();
+        let sun_direction = Point3D::new(
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            -elevation.cos() * azimuth.cos(),
+        );
+        let theta_s = PI / 2.0 - elevation;
+
+        let coefficients = [
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (th, th2, th3) = (theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith_x = t * t * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th)
+            + t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394)
+            + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let zenith_y = t * t * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th)
+            + t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516)
+            + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+        let zenith = [
+            zenith_x / perez(&coefficients[0], 1.0, theta_s),
+            zenith_y / perez(&coefficients[1], 1.0, theta_s),
+            zenith_luminance.max(0.0) * LUMINANCE_SCALE / perez(&coefficients[2], 1.0, theta_s),
+        ];
+
+        // transmittance of the atmosphere along the path of the sun's light,
+        // at the wavelengths (in micrometers) of red, green and blue
+        let air_mass = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+        let beta = 0.04608 * t - 0.04586;
+        let transmittance = |lambda: f64| {
+            let rayleigh = (-0.008735 * lambda.powf(-4.08) * air_mass).exp();
+            let aerosol = (-beta * lambda.powf(-1.3) * air_mass).exp();
+            (SUN_LUMINANCE * LUMINANCE_SCALE * rayleigh * aerosol) as f32
+        };
+        let sun_radiance = Srgb::new(
+            transmittance(0.68),
+            transmittance(0.55),
+            transmittance(0.45),
+        );
+
+        let mut daylight = Daylight {
+            sun_direction,
+            sun_radiance,
+            coefficients,
+            zenith,
+            ground: color::black(),
+        };
+        daylight.ground = color::scale(
+            daylight.horizontal_irradiance(),
+            params.ground_albedo / PI as f32,
+        );
+        daylight
+    }
+
+    fn sky_radiance(&self, direction: &Point3D) -> Srgb {
+        let d = direction.unit_vector();
+        let cos_gamma = d.dot(&self.sun_direction).clamp(-1.0, 1.0);
+        let gamma = cos_gamma.acos();
+        let cos_theta = d.y().max(0.0);
+        let x = self.zenith[0] * perez(&self.coefficients[0], cos_theta, gamma);
+        let y = self.zenith[1] * perez(&self.coefficients[1], cos_theta, gamma);
+        let luminance = self.zenith[2] * perez(&self.coefficients[2], cos_theta, gamma);
+        xyy_to_rgb(x, y, luminance)
+    }
+
+    fn cos_sun_radius() -> f64 {
+        SUN_ANGULAR_RADIUS.cos()
+    }
+
+    fn sun_solid_angle() -> f64 {
+        2.0 * PI * (1.0 - Daylight::cos_sun_radius())
+    }
+
+    // Irradiance on an upward facing surface, from the sky and the sun.
+    fn horizontal_irradiance(&self) -> Srgb {
+        let (rows, columns) = (64, 128);
+        let mut irradiance = color::black();
+        for i in 0..rows {
+            let theta = (i as f64 + 0.5) / rows as f64 * PI / 2.0;
+            let solid_angle = theta.sin() * (PI / 2.0 / rows as f64) * (2.0 * PI / columns as f64);
+            for j in 0..columns {
+                let phi = (j as f64 + 0.5) / columns as f64 * 2.0 * PI;
+                let d = Point3D::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                let weight = (theta.cos() * solid_angle) as f32;
+                irradiance = color::add(irradiance, color::scale(self.sky_radiance(&d), weight));
+            }
+        }
+        let sun = (Daylight::sun_solid_angle() * self.sun_direction.y()) as f32;
+        color::add(irradiance, color::scale(self.sun_radiance, sun))
+    }
+
+    // Radiance arriving from a direction: the sun disk, the sky above the
+    // horizon or the ground below it.
+    pub fn radiance(&self, direction: &Point3D) -> Srgb {
+        let d = direction.unit_vector();
+        if d.y() < 0.0 {
+            return self.ground;
+        }
+        if d.dot(&self.sun_direction) >= Daylight::cos_sun_radius() {
+            return color::add(self.sky_radiance(&d), self.sun_radiance);
+        }
+        self.sky_radiance(&d)
+    }
+
+    // Samples the sun disk with probability one half, or else any direction
+    // uniformly, from two uniform numbers.
+    pub fn sample_direction(&self, u1: f64, u2: f64) -> (Point3D, f64) {
+        let direction = if u1 < 0.5 {
+            let cos_theta = 1.0 - 2.0 * u1 * (1.0 - Daylight::cos_sun_radius());
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * u2;
+            let w = self.sun_direction;
+            let (u, v) = w.perpendicular_basis();
+            u * (sin_theta * phi.cos()) + v * (sin_theta * phi.sin()) + w * cos_theta
+        } else {
+            let z = 1.0 - 2.0 * (2.0 * u1 - 1.0);
+            let r = (1.0 - z * z).max(0.0).sqrt();
+            let phi = 2.0 * PI * u2;
+            Point3D::new(r * phi.cos(), z, r * phi.sin())
+        };
+        (direction, self.pdf(&direction))
+    }
+
+    pub fn pdf(&self, direction: &Point3D) -> f64 {
+        let uniform = 0.5 / (4.0 * PI);
+        if direction.unit_vector().dot(&self.sun_direction) >= Daylight::cos_sun_radius() {
+            uniform + 0.5 / Daylight::sun_solid_angle()
+        } else {
+            uniform
+        }
+    }
+}
+
+#[test]
+fn test_daylight_zenith() {
+    let daylight = Daylight::new(&PhysicalSky::new(60.0, 0.0, 3.0, 0.2));
+    // Y_z = (4.0453 T - 4.9710) tan(chi) - 0.2155 T + 2.4192, in kcd/m^2
+    let chi: f64 = (4.0 / 9.0 - 3.0 / 120.0) * (PI - 2.0 * 30f64.to_radians());
+    let expected = (4.0453 * 3.0 - 4.9710) * chi.tan() - 0.2155 * 3.0 + 2.4192;
+    let zenith = daylight.radiance(&Point3D::new(0.0, 1.0, 0.0));
+    assert_approx_eq!(color::luminance(zenith) as f64, expected * 0.1, 0.01);
+    // a clear sky is blue
+    assert!(zenith.blue > zenith.red);
+
+    // the sky is brighter around the sun, and the sun brightest of all
+    let towards_sun = Point3D::new(0.0, 60f64.to_radians().sin(), -60f64.to_radians().cos());
+    let near_sun = Point3D::new(0.0, 50f64.to_radians().sin(), -50f64.to_radians().cos());
+    let away = Point3D::new(0.0, 50f64.to_radians().sin(), 50f64.to_radians().cos());
+    let sun = color::luminance(daylight.radiance(&towards_sun));
+    let near = color::luminance(daylight.radiance(&near_sun));
+    assert!(sun > 1000.0 * near);
+    assert!(near > color::luminance(daylight.radiance(&away)));
+
+    // the ground reflects the light of the sky and the sun
+    let ground = daylight.radiance(&Point3D::new(0.0, -1.0, 0.0));
+    let darker_ground = Daylight::new(&PhysicalSky::new(60.0, 0.0, 3.0, 0.1))
+        .radiance(&Point3D::new(0.0, -1.0, 0.0));
+    assert!(ground.green > 0.0);
+    assert_approx_eq!(ground.green, 2.0 * darker_ground.green);
+}
+
+#[test]
+fn test_daylight_sampling() {
+    let daylight = Daylight::new(&PhysicalSky::new(30.0, 45.0, 4.0, 0.2));
+    let mut in_sun = 0;
+    for _ in 0..1000 {
+        let (direction, pdf) = daylight.sample_direction(rand::random(), rand::random());
+        assert_approx_eq!(direction.length(), 1.0);
+        assert_eq!(pdf, daylight.pdf(&direction));
+        if pdf > 1.0 {
+            in_sun += 1;
+        }
+    }
+    assert!((400..600).contains(&in_sun));
+}
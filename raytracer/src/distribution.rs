@@ -0,0 +1,133 @@
+// Piecewise-constant distributions, used to importance sample tabulated
+// functions such as environment maps (PBRT, section 13.6).
+
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    // `func` holds the (non-negative) function values of equally sized
+    // intervals covering [0, 1].
+    pub fn new(func: &[f64]) -> Distribution1D {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i] / n as f64;
+        }
+        let integral = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate().skip(1) {
+            // a zero function is sampled uniformly
+            *c = match integral {
+                x if x > 0.0 => *c / integral,
+                _ => i as f64 / n as f64,
+            };
+        }
+        Distribution1D {
+            func: func.to_vec(),
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    // Maps a uniform sample to a point in [0, 1), returning the point, its
+    // density and the interval it falls in.
+    pub fn sample(&self, u: f64) -> (f64, f64, usize) {
+        let index = (self.cdf.partition_point(|c| *c <= u).max(1) - 1).min(self.count() - 1);
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let offset = match width {
+            w if w > 0.0 => (u - self.cdf[index]) / w,
+            _ => 0.0,
+        };
+        let x = (index as f64 + offset) / self.count() as f64;
+        (x.min(1.0 - f64::EPSILON), self.pdf_at(index), index)
+    }
+
+    fn pdf_at(&self, index: usize) -> f64 {
+        match self.integral {
+            x if x > 0.0 => self.func[index] / self.integral,
+            _ => 1.0,
+        }
+    }
+
+    pub fn pdf(&self, x: f64) -> f64 {
+        let index = ((x * self.count() as f64) as usize).min(self.count() - 1);
+        self.pdf_at(index)
+    }
+}
+
+// Distribution over [0, 1]^2 of a function tabulated on a grid, with rows
+// along v and columns along u.
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f64], width: usize, height: usize) -> Distribution2D {
+        let conditional: Vec<Distribution1D> = func
+            .chunks(width)
+            .take(height)
+            .map(Distribution1D::new)
+            .collect();
+        let marginal: Vec<f64> = conditional.iter().map(|c| c.integral()).collect();
+        Distribution2D {
+            conditional,
+            marginal: Distribution1D::new(&marginal),
+        }
+    }
+
+    // Returns the sampled point (u, v) and its density.
+    pub fn sample(&self, u1: f64, u2: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, row) = self.marginal.sample(u2);
+        let (u, pdf_u, _) = self.conditional[row].sample(u1);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let height = self.marginal.count();
+        let row = ((v * height as f64) as usize).min(height - 1);
+        self.marginal.pdf(v) * self.conditional[row].pdf(u)
+    }
+}
+
+#[test]
+fn test_distribution_1d() {
+    let d = Distribution1D::new(&[1.0, 3.0, 0.0, 0.0]);
+    assert_eq!(d.integral(), 1.0);
+    // the first quarter holds a quarter of the mass
+    let (x, pdf, index) = d.sample(0.125);
+    assert_eq!((x, pdf, index), (0.125, 1.0, 0));
+    let (x, pdf, index) = d.sample(0.625);
+    assert_eq!((x, pdf, index), (0.375, 3.0, 1));
+    assert_eq!(d.pdf(0.8), 0.0);
+
+    // zero functions are sampled uniformly
+    let d = Distribution1D::new(&[0.0, 0.0]);
+    let (x, pdf, _) = d.sample(0.75);
+    assert_eq!((x, pdf), (0.75, 1.0));
+}
+
+#[test]
+fn test_distribution_2d() {
+    let d = Distribution2D::new(&[0.0, 0.0, 1.0, 3.0], 2, 2);
+    for _ in 0..100 {
+        let ((u, v), pdf) = d.sample(rand::random(), rand::random());
+        // everything is in the bottom row
+        assert!(v >= 0.5);
+        assert_eq!(pdf, d.pdf(u, v));
+    }
+    assert_eq!(d.pdf(0.75, 0.75), 3.0);
+    assert_eq!(d.pdf(0.25, 0.25), 0.0);
+}
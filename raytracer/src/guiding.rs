@@ -0,0 +1,705 @@
+use palette::Srgb;
+use rand::Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::color;
+use crate::config::Config;
+use crate::integrator::Integrator;
+use crate::integrator::PathTracer;
+use crate::point3d::Point3D;
+use crate::ray::Ray;
+use crate::sampler::random_f64;
+use crate::sphere::Sphere;
+
+#[cfg(test)]
+use crate::camera::Camera;
+#[cfg(test)]
+use crate::integrator::IntegratorKind;
+#[cfg(test)]
+use crate::materials::Lambertian;
+#[cfg(test)]
+use crate::materials::Light;
+#[cfg(test)]
+use crate::materials::Material;
+
+// Path guiding with a spatial-directional tree (Müller et al. 2017).
+//
+// A binary tree over the scene's bounds holds, in each leaf, a quadtree
+// over the sphere of directions learned from the light arriving in that
+// region. Training passes render the frame with 1, 2, 4, ... samples per
+// pixel, each guided by what the previous ones learned; the frame is then
+// rendered with `samples_per_pixel` samples. Diffuse bounces pick their
+// direction from the learned distribution with probability 1 -
+// `bsdf_fraction` and cosine distributed otherwise, and are weighted by the
+// density of the mixture, so guiding only changes the noise, not the image
+// it converges to. A region splits once it received `spatial_threshold`
+// times the square root of the pass' samples per pixel light samples.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct PathGuiding {
+    pub training_passes: usize,
+    pub spatial_threshold: usize,
+    pub bsdf_fraction: f64,
+}
+
+impl PathGuiding {
+    pub fn new(training_passes: usize, spatial_threshold: usize, bsdf_fraction: f64) -> Self {
+        PathGuiding {
+            training_passes,
+            spatial_threshold,
+            bsdf_fraction,
+        }
+    }
+}
+
+// directional nodes holding more than this fraction of their tree's energy
+// are subdivided
+const DIRECTIONAL_THRESHOLD: f64 = 0.01;
+const MAX_DIRECTIONAL_DEPTH: usize = 20;
+
+// An f64 that threads add to while a pass is rendered.
+#[derive(Debug, Default)]
+struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    fn new(value: f64) -> Self {
+        AtomicF64(AtomicU64::new(value.to_bits()))
+    }
+
+    fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, value: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+}
+
+impl Clone for AtomicF64 {
+    fn clone(&self) -> Self {
+        AtomicF64::new(self.load())
+    }
+}
+
+// Directions are mapped to the unit square with the area preserving
+// cylindrical mapping (cos theta, phi), so densities on the square and on
+// the sphere differ by a constant 4 pi.
+fn direction_to_square(direction: &Point3D) -> (f64, f64) {
+    let d = direction.unit_vector();
+    let u = ((d.z() + 1.0) / 2.0).clamp(0.0, 1.0);
+    let phi = d.y().atan2(d.x());
+    let v = if phi < 0.0 { phi + 2.0 * PI } else { phi } / (2.0 * PI);
+    (u, v.clamp(0.0, 1.0))
+}
+
+fn square_to_direction(u: f64, v: f64) -> Point3D {
+    let cos_theta = 2.0 * u - 1.0;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Point3D::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+// Quadrant of the unit square a point falls in, numbered 0 and 1 along u
+// then 2 and 3, and the point rescaled to that quadrant.
+fn quadrant(u: f64, v: f64) -> (usize, f64, f64) {
+    let (qu, u) = match u < 0.5 {
+        true => (0, 2.0 * u),
+        false => (1, 2.0 * u - 1.0),
+    };
+    let (qv, v) = match v < 0.5 {
+        true => (0, 2.0 * v),
+        false => (1, 2.0 * v - 1.0),
+    };
+    (qu + 2 * qv, u, v)
+}
+
+#[derive(Debug, Clone, Default)]
+struct QuadNode {
+    // light recorded in each quadrant
+    sums: [AtomicF64; 4],
+    // child node of each quadrant, 0 for quadrants which are leaves
+    children: [usize; 4],
+}
+
+impl QuadNode {
+    fn sums(&self) -> [f64; 4] {
+        [
+            self.sums[0].load(),
+            self.sums[1].load(),
+            self.sums[2].load(),
+            self.sums[3].load(),
+        ]
+    }
+}
+
+// Piecewise constant distribution of directions, refined where most of the
+// light comes from.
+#[derive(Debug, Clone)]
+pub struct DirectionalTree {
+    nodes: Vec<QuadNode>,
+}
+
+impl DirectionalTree {
+    pub fn new() -> Self {
+        DirectionalTree {
+            nodes: vec![QuadNode::default()],
+        }
+    }
+
+    fn total(&self) -> f64 {
+        self.nodes[0].sums().iter().sum()
+    }
+
+    // Whether anything was recorded, without which the distribution is
+    // uniform.
+    pub fn is_trained(&self) -> bool {
+        self.total() > 0.0
+    }
+
+    pub fn record(&self, direction: &Point3D, value: f64) {
+        if !(value > 0.0 && value.is_finite()) {
+            return;
+        }
+        let (mut u, mut v) = direction_to_square(direction);
+        let mut node = 0;
+        loop {
+            let (q, qu, qv) = quadrant(u, v);
+            self.nodes[node].sums[q].add(value);
+            node = self.nodes[node].children[q];
+            if node == 0 {
+                return;
+            }
+            (u, v) = (qu, qv);
+        }
+    }
+
+    // Density of a direction, with respect to solid angle.
+    pub fn pdf(&self, direction: &Point3D) -> f64 {
+        let (mut u, mut v) = direction_to_square(direction);
+        let mut density = 1.0;
+        let mut node = 0;
+        loop {
+            let sums = self.nodes[node].sums();
+            let total: f64 = sums.iter().sum();
+            if total <= 0.0 {
+                break;
+            }
+            let (q, qu, qv) = quadrant(u, v);
+            density *= 4.0 * sums[q] / total;
+            node = self.nodes[node].children[q];
+            if node == 0 {
+                break;
+            }
+            (u, v) = (qu, qv);
+        }
+        density / (4.0 * PI)
+    }
+
+    pub fn sample(&self, u1: f64, u2: f64) -> Point3D {
+        let (mut u1, mut u2) = (u1, u2);
+        let (mut u, mut v, mut size) = (0.0, 0.0, 1.0);
+        let mut node = 0;
+        loop {
+            let s = self.nodes[node].sums();
+            let total: f64 = s.iter().sum();
+            if total <= 0.0 {
+                break;
+            }
+            // pick a column along u, then a quadrant within it
+            let p_left = (s[0] + s[2]) / total;
+            let qu = match u1 < p_left {
+                true => {
+                    u1 /= p_left;
+                    0
+                }
+                false => {
+                    u1 = ((u1 - p_left) / (1.0 - p_left)).min(1.0 - f64::EPSILON);
+                    1
+                }
+            };
+            let column = s[qu] + s[qu + 2];
+            let p_bottom = match column {
+                x if x > 0.0 => s[qu] / x,
+                _ => 0.5,
+            };
+            let qv = match u2 < p_bottom {
+                true => {
+                    u2 /= p_bottom;
+                    0
+                }
+                false => {
+                    u2 = ((u2 - p_bottom) / (1.0 - p_bottom)).min(1.0 - f64::EPSILON);
+                    1
+                }
+            };
+            size /= 2.0;
+            u += qu as f64 * size;
+            v += qv as f64 * size;
+            node = self.nodes[node].children[qu + 2 * qv];
+            if node == 0 {
+                break;
+            }
+        }
+        square_to_direction(u + u1 * size, v + u2 * size)
+    }
+
+    // An empty tree, subdivided where this one holds a large share of the
+    // recorded light.
+    pub fn refined(&self) -> DirectionalTree {
+        let mut tree = DirectionalTree { nodes: Vec::new() };
+        let root = &self.nodes[0];
+        self.refine(&mut tree.nodes, root.sums(), root.children, self.total(), 1);
+        tree
+    }
+
+    fn refine(
+        &self,
+        nodes: &mut Vec<QuadNode>,
+        sums: [f64; 4],
+        children: [usize; 4],
+        total: f64,
+        depth: usize,
+    ) -> usize {
+        let index = nodes.len();
+        nodes.push(QuadNode::default());
+        if total <= 0.0 || depth >= MAX_DIRECTIONAL_DEPTH {
+            return index;
+        }
+        for q in 0..4 {
+            if sums[q] / total <= DIRECTIONAL_THRESHOLD {
+                continue;
+            }
+            // light within quadrants that were leaves is spread evenly
+            let (child_sums, child_children) = match children[q] {
+                0 => ([sums[q] / 4.0; 4], [0; 4]),
+                child => (self.nodes[child].sums(), self.nodes[child].children),
+            };
+            let child = self.refine(nodes, child_sums, child_children, total, depth + 1);
+            nodes[index].children[q] = child;
+        }
+        index
+    }
+}
+
+impl Default for DirectionalTree {
+    fn default() -> Self {
+        DirectionalTree::new()
+    }
+}
+
+#[derive(Debug)]
+struct SpatialLeaf {
+    // distribution learned in previous passes, sampled in the current one
+    sampling: DirectionalTree,
+    // distribution recorded in the current pass
+    building: DirectionalTree,
+    samples: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SpatialNode {
+    Leaf(usize),
+    // split in half along the axis, with both halves
+    Children(usize, usize, usize),
+}
+
+// Binary tree over the scene's bounds, with a directional tree per leaf.
+#[derive(Debug)]
+pub struct SdTree {
+    min: Point3D,
+    max: Point3D,
+    nodes: Vec<SpatialNode>,
+    leaves: Vec<SpatialLeaf>,
+}
+
+fn axis_value(p: &Point3D, axis: usize) -> f64 {
+    match axis {
+        0 => p.x(),
+        1 => p.y(),
+        _ => p.z(),
+    }
+}
+
+fn set_axis_value(p: &mut Point3D, axis: usize, value: f64) {
+    match axis {
+        0 => p.set_x(value),
+        1 => p.set_y(value),
+        _ => p.set_z(value),
+    }
+}
+
+impl SdTree {
+    pub fn new(scene: &Config) -> SdTree {
+        let mut min = Point3D::new(f64::MAX, f64::MAX, f64::MAX);
+        let mut max = Point3D::new(f64::MIN, f64::MIN, f64::MIN);
+        for sphere in &scene.objects {
+            let r = Point3D::new(sphere.radius, sphere.radius, sphere.radius);
+            let (lo, hi) = (sphere.center - r, sphere.center + r);
+            min = Point3D::new(
+                min.x().min(lo.x()),
+                min.y().min(lo.y()),
+                min.z().min(lo.z()),
+            );
+            max = Point3D::new(
+                max.x().max(hi.x()),
+                max.y().max(hi.y()),
+                max.z().max(hi.z()),
+            );
+        }
+        if scene.objects.is_empty() {
+            min = Point3D::new(-1.0, -1.0, -1.0);
+            max = Point3D::new(1.0, 1.0, 1.0);
+        }
+        SdTree {
+            min,
+            max,
+            nodes: vec![SpatialNode::Leaf(0)],
+            leaves: vec![SpatialLeaf {
+                sampling: DirectionalTree::new(),
+                building: DirectionalTree::new(),
+                samples: AtomicUsize::new(0),
+            }],
+        }
+    }
+
+    fn leaf(&self, point: &Point3D) -> &SpatialLeaf {
+        let (mut min, mut max) = (self.min, self.max);
+        let mut node = 0;
+        loop {
+            match self.nodes[node] {
+                SpatialNode::Leaf(leaf) => return &self.leaves[leaf],
+                SpatialNode::Children(axis, left, right) => {
+                    let middle = (axis_value(&min, axis) + axis_value(&max, axis)) / 2.0;
+                    if axis_value(point, axis) < middle {
+                        set_axis_value(&mut max, axis, middle);
+                        node = left;
+                    } else {
+                        set_axis_value(&mut min, axis, middle);
+                        node = right;
+                    }
+                }
+            }
+        }
+    }
+
+    // Distribution of the light arriving at a point, as learned so far.
+    pub fn guide(&self, point: &Point3D) -> &DirectionalTree {
+        &self.leaf(point).sampling
+    }
+
+    // Records the light arriving at a point from a direction, estimated by
+    // a sample of the given density.
+    pub fn record(&self, point: &Point3D, direction: &Point3D, radiance: Srgb, pdf: f64) {
+        let leaf = self.leaf(point);
+        leaf.samples.fetch_add(1, Ordering::Relaxed);
+        leaf.building
+            .record(direction, color::luminance(radiance) as f64 / pdf);
+    }
+
+    // The tree for the next pass: what was recorded becomes the guide,
+    // regions which received more than `threshold` samples are split and
+    // recording starts over in refined directional trees.
+    pub fn refined(&self, threshold: usize) -> SdTree {
+        let mut tree = SdTree {
+            min: self.min,
+            max: self.max,
+            nodes: Vec::new(),
+            leaves: Vec::new(),
+        };
+        self.split(&mut tree, 0, 0, threshold);
+        for leaf in tree.leaves.iter_mut() {
+            leaf.building = leaf.sampling.refined();
+        }
+        tree
+    }
+
+    fn split(&self, tree: &mut SdTree, node: usize, depth: usize, threshold: usize) -> usize {
+        let index = tree.nodes.len();
+        match self.nodes[node] {
+            SpatialNode::Children(axis, left, right) => {
+                tree.nodes.push(SpatialNode::Leaf(0));
+                let left = self.split(tree, left, depth + 1, threshold);
+                let right = self.split(tree, right, depth + 1, threshold);
+                tree.nodes[index] = SpatialNode::Children(axis, left, right);
+            }
+            SpatialNode::Leaf(leaf) => {
+                let leaf = &self.leaves[leaf];
+                let samples = leaf.samples.load(Ordering::Relaxed);
+                tree.split_leaf(&leaf.building, samples, depth, threshold);
+            }
+        }
+        index
+    }
+
+    // Adds a leaf with the given guide, split in halves until each of them
+    // would have received at most `threshold` samples.
+    fn split_leaf(
+        &mut self,
+        guide: &DirectionalTree,
+        samples: usize,
+        depth: usize,
+        threshold: usize,
+    ) -> usize {
+        let index = self.nodes.len();
+        if samples <= threshold || depth >= 60 {
+            self.nodes.push(SpatialNode::Leaf(self.leaves.len()));
+            self.leaves.push(SpatialLeaf {
+                sampling: guide.clone(),
+                building: DirectionalTree::new(),
+                samples: AtomicUsize::new(0),
+            });
+            return index;
+        }
+        self.nodes.push(SpatialNode::Leaf(0));
+        let left = self.split_leaf(guide, samples / 2, depth + 1, threshold);
+        let right = self.split_leaf(guide, samples / 2, depth + 1, threshold);
+        self.nodes[index] = SpatialNode::Children(depth % 3, left, right);
+        index
+    }
+}
+
+// What the path tracer needs to guide its diffuse bounces.
+#[derive(Debug, Clone)]
+pub struct Guiding {
+    tree: Arc<SdTree>,
+    bsdf_fraction: f64,
+}
+
+impl Guiding {
+    pub fn new(tree: Arc<SdTree>, bsdf_fraction: f64) -> Self {
+        Guiding {
+            tree,
+            bsdf_fraction,
+        }
+    }
+
+    // Samples the direction of a diffuse bounce at a point, returning it
+    // with the density of the mixture it was sampled from.
+    pub fn sample(&self, point: &Point3D, normal: &Point3D) -> (Point3D, f64) {
+        let guide = self.tree.guide(point);
+        let direction = if guide.is_trained() && random_f64() >= self.bsdf_fraction {
+            guide.sample(random_f64(), random_f64())
+        } else {
+            let direction = *normal + Point3D::random_unit_vector();
+            match direction.near_zero() {
+                true => *normal,
+                false => direction.unit_vector(),
+            }
+        };
+        (direction, self.pdf(point, normal, &direction))
+    }
+
+    pub fn pdf(&self, point: &Point3D, normal: &Point3D, direction: &Point3D) -> f64 {
+        let bsdf_pdf = (direction.unit_vector().dot(normal) / PI).max(0.0);
+        let guide = self.tree.guide(point);
+        match guide.is_trained() {
+            true => {
+                self.bsdf_fraction * bsdf_pdf + (1.0 - self.bsdf_fraction) * guide.pdf(direction)
+            }
+            false => bsdf_pdf,
+        }
+    }
+
+    pub fn record(&self, point: &Point3D, direction: &Point3D, radiance: Srgb, pdf: f64) {
+        self.tree.record(point, direction, radiance, pdf);
+    }
+}
+
+fn render_pass(scene: &Config, lights: &[Sphere], tracer: &PathTracer, samples: u32) -> Vec<Srgb> {
+    (0..scene.height)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            let mut rng = rand::thread_rng();
+            (0..scene.width)
+                .map(|x| {
+                    let mut sum = color::black();
+                    for _ in 0..samples {
+                        let u = (x as f64 + rng.gen::<f64>()) / (scene.width as f64 - 1.0);
+                        let v = (scene.height as f64 - (y as f64 + rng.gen::<f64>()))
+                            / (scene.height as f64 - 1.0);
+                        let r = scene.camera.get_ray(u, v);
+                        sum = color::add(sum, tracer.ray_color(&r, scene, lights));
+                    }
+                    color::scale(sum, 1.0 / samples.max(1) as f32)
+                })
+                .collect::<Vec<Srgb>>()
+        })
+        .collect()
+}
+
+impl Integrator for PathGuiding {
+    // Single rays have nothing to learn from, and are path traced.
+    fn ray_color(&self, ray: &Ray, scene: &Config, lights: &[Sphere]) -> Srgb {
+        PathTracer::with_lights(lights).ray_color(ray, scene, lights)
+    }
+
+    fn render_frame(&self, scene: &Config, lights: &[Sphere]) -> Option<Vec<Srgb>> {
+        let mut tree = SdTree::new(scene);
+        for pass in 0..self.training_passes {
+            let samples = 1u32 << pass.min(16);
+            let guiding = Guiding::new(Arc::new(tree), self.bsdf_fraction);
+            let tracer = PathTracer::with_lights(lights).guided(guiding.clone());
+            render_pass(scene, lights, &tracer, samples);
+            let threshold = self.spatial_threshold as f64 * (samples as f64).sqrt();
+            tree = guiding.tree.refined(threshold as usize);
+        }
+        let guiding = Guiding::new(Arc::new(tree), self.bsdf_fraction);
+        let tracer = PathTracer::with_lights(lights).guided(guiding);
+        Some(render_pass(scene, lights, &tracer, scene.samples_per_pixel))
+    }
+}
+
+#[test]
+fn test_direction_mapping() {
+    for d in [
+        Point3D::new(0.3, -0.5, 0.8),
+        Point3D::new(-1.0, 0.2, -0.1),
+        Point3D::new(0.0, -1.0, 0.0),
+    ] {
+        let (u, v) = direction_to_square(&d);
+        let back = square_to_direction(u, v);
+        assert!((back - d.unit_vector()).length() < 1e-9);
+    }
+}
+
+#[test]
+fn test_directional_tree() {
+    // untrained trees are uniform over the sphere
+    let tree = DirectionalTree::new();
+    assert_eq!(tree.pdf(&Point3D::new(0.0, 1.0, 0.0)), 1.0 / (4.0 * PI));
+
+    // light mostly arriving from above refines the tree around it
+    let up = Point3D::new(0.0, 0.0, 1.0);
+    for _ in 0..1000 {
+        tree.record(&(up + Point3D::random_in_unit_sphere() * 0.1), 1.0);
+        tree.record(&Point3D::random_unit_vector(), 0.1);
+    }
+    let tree = tree.refined();
+    assert!(tree.nodes.len() > 1);
+    for _ in 0..1000 {
+        tree.record(&(up + Point3D::random_in_unit_sphere() * 0.1), 1.0);
+        tree.record(&Point3D::random_unit_vector(), 0.1);
+    }
+    assert!(tree.pdf(&up) > 10.0 * tree.pdf(&-up));
+
+    // the density integrates to one and matches the sampled directions;
+    // cells of a power of two grid never straddle quadtree nodes
+    let steps = 512;
+    let cell = 4.0 * PI / (steps * steps) as f64;
+    let mut integral = 0.0;
+    let mut near_up = 0.0;
+    for i in 0..steps {
+        for j in 0..steps {
+            let u = (i as f64 + 0.5) / steps as f64;
+            let v = (j as f64 + 0.5) / steps as f64;
+            let p = tree.pdf(&square_to_direction(u, v)) * cell;
+            integral += p;
+            if u > 0.96875 {
+                near_up += p;
+            }
+        }
+    }
+    assert!((integral - 1.0).abs() < 1e-6, "{}", integral);
+    let samples = 20000;
+    let sampled = (0..samples)
+        .filter(|_| tree.sample(rand::random(), rand::random()).z() > 0.9375)
+        .count();
+    let fraction = sampled as f64 / samples as f64;
+    assert!(
+        (fraction - near_up).abs() < 0.02,
+        "{} != {}",
+        fraction,
+        near_up
+    );
+}
+
+#[cfg(test)]
+fn test_scene() -> Config {
+    Config {
+        width: 8,
+        height: 6,
+        samples_per_pixel: 256,
+        max_depth: 4,
+        spectral: false,
+        sky: None,
+        fog: None,
+        volumes: Vec::new(),
+        camera: Camera::new(
+            Point3D::new(0.0, 1.0, 3.0),
+            Point3D::new(0.0, 0.0, 0.0),
+            Point3D::new(0.0, 1.0, 0.0),
+            60.0,
+            8.0 / 6.0,
+        ),
+        integrator: IntegratorKind::PathGuiding(PathGuiding::new(6, 10, 0.5)),
+        objects: vec![
+            Sphere::new(
+                Point3D::new(0.0, -1000.0, 0.0),
+                1000.0,
+                Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+            ),
+            Sphere::new(
+                Point3D::new(0.0, 0.5, 0.0),
+                0.5,
+                Material::Lambertian(Lambertian::new(Srgb::new(0.8, 0.8, 0.8))),
+            ),
+            Sphere::new(
+                Point3D::new(1.5, 2.0, -1.0),
+                0.3,
+                Material::Light(Light::new()),
+            ),
+        ],
+    }
+}
+
+// Mean over `runs` independent renders of the average luminance of their
+// frames, and the standard error of that mean.
+#[cfg(test)]
+fn mean_luminance(runs: usize, render: impl Fn() -> Vec<Srgb>) -> (f64, f64) {
+    let values: Vec<f64> = (0..runs)
+        .map(|_| {
+            let frame = render();
+            let sum: f64 = frame.iter().map(|c| color::luminance(*c) as f64).sum();
+            sum / frame.len() as f64
+        })
+        .collect();
+    let mean = values.iter().sum::<f64>() / runs as f64;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (runs - 1) as f64;
+    (mean, (variance / runs as f64).sqrt())
+}
+
+#[test]
+fn test_path_guiding_converges_to_path_tracer() {
+    let scene = test_scene();
+    let lights = vec![scene.objects[2].clone()];
+    let params = PathGuiding::new(6, 10, 0.5);
+    assert_eq!(params.render_frame(&scene, &lights).unwrap().len(), 48);
+    let runs = 128;
+    let (guided, guided_error) =
+        mean_luminance(runs, || params.render_frame(&scene, &lights).unwrap());
+
+    let tracer = PathTracer::with_lights(&lights);
+    let (reference, reference_error) = mean_luminance(runs, || {
+        render_pass(&scene, &lights, &tracer, scene.samples_per_pixel)
+    });
+
+    // both estimates are unbiased, so they differ by less than four
+    // standard errors of the difference, which is small enough to catch a
+    // bias of a few percent
+    let error = (guided_error * guided_error + reference_error * reference_error).sqrt();
+    assert!(error < 0.01 * reference, "{} {}", error, reference);
+    assert!(
+        (guided - reference).abs() < 4.0 * error,
+        "{} != {} +- {}",
+        guided,
+        reference,
+        error
+    );
+}
@@ -0,0 +1,269 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+// Loaders for high dynamic range images, used for environment maps. Both
+// return linear RGB values, three per pixel, starting with the top row.
+
+pub fn load_hdr_image(path: &str) -> Result<(Vec<f32>, usize, usize), Error> {
+    let bytes = fs::read(path)?;
+    if path.to_lowercase().ends_with(".pfm") {
+        decode_pfm(&bytes)
+    } else {
+        decode_radiance(&bytes)
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+// Reads a line of the header, without the trailing newline.
+fn read_line<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a str, Error> {
+    let start = *pos;
+    let end = bytes[start..]
+        .iter()
+        .position(|b| *b == b'\n')
+        .map(|i| start + i)
+        .ok_or_else(|| invalid("unexpected end of header"))?;
+    *pos = end + 1;
+    std::str::from_utf8(&bytes[start..end]).map_err(|_| invalid("header is not text"))
+}
+
+fn parse_number<T: std::str::FromStr>(token: Option<&str>) -> Result<T, Error> {
+    token
+        .and_then(|t| t.parse().ok())
+        .ok_or_else(|| invalid("malformed image size"))
+}
+
+// Number of bytes in an image of the given size, which must not be empty.
+fn image_bytes(width: usize, height: usize, bytes_per_pixel: usize) -> Result<usize, Error> {
+    if width == 0 || height == 0 {
+        return Err(invalid("empty image"));
+    }
+    width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(bytes_per_pixel))
+        .ok_or_else(|| invalid("image too large"))
+}
+
+// Fewest bytes a scanline of the given width is stored in: four bytes per
+// pixel, or runs of 127 pixels for each channel if it can be run length
+// encoded.
+fn min_scanline_bytes(width: usize) -> usize {
+    match (8..0x8000).contains(&width) {
+        true => 4 + 4 * 2 * width.div_ceil(127),
+        false => width * 4,
+    }
+}
+
+// Radiance RGBE (.hdr), flat or with run length encoded scanlines.
+fn decode_radiance(bytes: &[u8]) -> Result<(Vec<f32>, usize, usize), Error> {
+    let mut pos = 0;
+    let magic = read_line(bytes, &mut pos)?;
+    if !magic.starts_with("#?") {
+        return Err(invalid("not a Radiance HDR file"));
+    }
+    loop {
+        let line = read_line(bytes, &mut pos)?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(invalid("unsupported Radiance HDR format"));
+            }
+        }
+    }
+    // only the standard orientation, top to bottom and left to right
+    let resolution = read_line(bytes, &mut pos)?;
+    let mut tokens = resolution.split_whitespace();
+    if tokens.next() != Some("-Y") {
+        return Err(invalid("unsupported Radiance HDR orientation"));
+    }
+    let height: usize = parse_number(tokens.next())?;
+    if tokens.next() != Some("+X") {
+        return Err(invalid("unsupported Radiance HDR orientation"));
+    }
+    let width: usize = parse_number(tokens.next())?;
+    image_bytes(width, height, 4)?;
+    // nothing is allocated for scanlines the file is too short to hold
+    if height * min_scanline_bytes(width) > bytes.len() - pos {
+        return Err(invalid("truncated Radiance HDR file"));
+    }
+
+    let mut rgbe = vec![0u8; width * 4];
+    let mut pixels = Vec::new();
+    for _ in 0..height {
+        read_scanline(bytes, &mut pos, &mut rgbe)?;
+        for p in rgbe.chunks(4) {
+            if p[3] == 0 {
+                pixels.extend_from_slice(&[0.0, 0.0, 0.0]);
+            } else {
+                let f = 2f32.powi(p[3] as i32 - 136);
+                pixels.push((p[0] as f32 + 0.5) * f);
+                pixels.push((p[1] as f32 + 0.5) * f);
+                pixels.push((p[2] as f32 + 0.5) * f);
+            }
+        }
+    }
+    Ok((pixels, width, height))
+}
+
+fn read_scanline(bytes: &[u8], pos: &mut usize, rgbe: &mut [u8]) -> Result<(), Error> {
+    let width = rgbe.len() / 4;
+    let truncated = || invalid("truncated Radiance HDR file");
+    let head = bytes.get(*pos..*pos + 4).ok_or_else(truncated)?;
+    let rle = (8..0x8000).contains(&width)
+        && head[0] == 2
+        && head[1] == 2
+        && ((head[2] as usize) << 8 | head[3] as usize) == width;
+    if !rle {
+        let flat = bytes.get(*pos..*pos + width * 4).ok_or_else(truncated)?;
+        rgbe.copy_from_slice(flat);
+        *pos += width * 4;
+        return Ok(());
+    }
+    *pos += 4;
+    // each channel is encoded separately, as runs or literal bytes
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let count = *bytes.get(*pos).ok_or_else(truncated)? as usize;
+            *pos += 1;
+            if count > 128 {
+                let value = *bytes.get(*pos).ok_or_else(truncated)?;
+                *pos += 1;
+                if x + count - 128 > width {
+                    return Err(invalid("bad run length in Radiance HDR file"));
+                }
+                for _ in 0..count - 128 {
+                    rgbe[x * 4 + channel] = value;
+                    x += 1;
+                }
+            } else {
+                if count == 0 || x + count > width {
+                    return Err(invalid("bad run length in Radiance HDR file"));
+                }
+                let literal = bytes.get(*pos..*pos + count).ok_or_else(truncated)?;
+                for value in literal {
+                    rgbe[x * 4 + channel] = *value;
+                    x += 1;
+                }
+                *pos += count;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Portable float map (.pfm), color ("PF") or grayscale ("Pf"). Rows are
+// stored bottom to top.
+fn decode_pfm(bytes: &[u8]) -> Result<(Vec<f32>, usize, usize), Error> {
+    let mut pos = 0;
+    let channels = match read_line(bytes, &mut pos)?.trim() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid("not a PFM file")),
+    };
+    let size = read_line(bytes, &mut pos)?;
+    let mut tokens = size.split_whitespace();
+    let width: usize = parse_number(tokens.next())?;
+    let height: usize = parse_number(tokens.next())?;
+    let scale: f32 = parse_number(Some(read_line(bytes, &mut pos)?.trim()))?;
+    let little_endian = scale < 0.0;
+    let size = image_bytes(width, height, channels * 4)?;
+
+    let data = bytes
+        .get(pos..pos.saturating_add(size))
+        .ok_or_else(|| invalid("truncated PFM file"))?;
+    let values: Vec<f32> = data
+        .chunks(4)
+        .map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            if little_endian {
+                f32::from_le_bytes(b)
+            } else {
+                f32::from_be_bytes(b)
+            }
+        })
+        .collect();
+    let mut pixels = Vec::with_capacity(size / channels / 4 * 3);
+    for row in values.chunks(width * channels).rev() {
+        for p in row.chunks(channels) {
+            match channels {
+                1 => pixels.extend_from_slice(&[p[0], p[0], p[0]]),
+                _ => pixels.extend_from_slice(p),
+            }
+        }
+    }
+    Ok((pixels, width, height))
+}
+
+#[test]
+fn test_decode_radiance_flat() {
+    let mut bytes = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n".to_vec();
+    bytes.extend_from_slice(&[128, 64, 0, 129, 0, 0, 0, 0]);
+    let (pixels, width, height) = decode_radiance(&bytes).unwrap();
+    assert_eq!((width, height), (2, 1));
+    let half_step = 1.0 / 256.0;
+    assert_eq!(
+        pixels,
+        vec![1.0 + half_step, 0.5 + half_step, half_step, 0.0, 0.0, 0.0]
+    );
+}
+
+#[test]
+fn test_decode_radiance_bad_size() {
+    assert!(decode_radiance(b"#?RADIANCE\n\n-Y 1 +X 0\n").is_err());
+    assert!(decode_radiance(b"#?RADIANCE\n\n-Y 0 +X 1\n").is_err());
+    let huge = format!("#?RADIANCE\n\n-Y {} +X {}\n", usize::MAX, usize::MAX / 2);
+    assert!(decode_radiance(huge.as_bytes()).is_err());
+    // a tiny file can't hold a huge image, flat or run length encoded
+    assert!(decode_radiance(b"#?RADIANCE\n\n-Y 100000 +X 100000\n").is_err());
+    assert!(decode_radiance(b"#?RADIANCE\n\n-Y 100000 +X 1000\n\x02\x02\x03\xe8").is_err());
+}
+
+#[test]
+fn test_decode_radiance_rle() {
+    let mut bytes = b"#?RADIANCE\n\n-Y 1 +X 8\n".to_vec();
+    bytes.extend_from_slice(&[2, 2, 0, 8]);
+    // red: a run of 8; green: 8 literals; blue: two runs; exponent: a run
+    bytes.extend_from_slice(&[136, 10]);
+    bytes.extend_from_slice(&[8, 0, 1, 2, 3, 4, 5, 6, 7]);
+    bytes.extend_from_slice(&[132, 0, 132, 255]);
+    bytes.extend_from_slice(&[136, 136]);
+    let (pixels, width, height) = decode_radiance(&bytes).unwrap();
+    assert_eq!((width, height), (8, 1));
+    assert_eq!(pixels.len(), 24);
+    assert_eq!(pixels[0], 10.5);
+    assert_eq!(pixels[3 * 7 + 1], 7.5);
+    assert_eq!(pixels[2], 0.5);
+    assert_eq!(pixels[3 * 7 + 2], 255.5);
+
+    assert!(decode_radiance(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn test_decode_pfm() {
+    let mut bytes = b"PF\n1 2\n-1.0\n".to_vec();
+    for value in [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    let (pixels, width, height) = decode_pfm(&bytes).unwrap();
+    assert_eq!((width, height), (1, 2));
+    // bottom row first in the file, top row first in memory
+    assert_eq!(pixels, vec![4.0, 5.0, 6.0, 1.0, 2.0, 3.0]);
+
+    let mut bytes = b"Pf\n1 1\n1.0\n".to_vec();
+    bytes.extend_from_slice(&0.5f32.to_be_bytes());
+    assert_eq!(decode_pfm(&bytes).unwrap().0, vec![0.5, 0.5, 0.5]);
+}
+
+#[test]
+fn test_decode_pfm_bad_size() {
+    assert!(decode_pfm(b"PF\n0 1\n-1.0\n").is_err());
+    assert!(decode_pfm(b"Pf\n1 0\n-1.0\n").is_err());
+    let huge = format!("PF\n{} {}\n-1.0\n", usize::MAX / 2, 3);
+    assert!(decode_pfm(huge.as_bytes()).is_err());
+    assert!(decode_pfm(b"PF\n100000 100000\n-1.0\n").is_err());
+}
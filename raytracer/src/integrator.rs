@@ -0,0 +1,1367 @@
+use palette::Srgb;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use thiserror::Error;
+
+use crate::aov::Aov;
+use crate::bdpt::BidirectionalPathTracer;
+use crate::color;
+use crate::config::Config;
+use crate::config::Sky;
+use crate::guiding::Guiding;
+use crate::guiding::PathGuiding;
+use crate::irradiance_cache::IrradianceCacheIntegrator;
+use crate::irradiance_cache::IrradianceCaching;
+use crate::light_bvh::LightBvh;
+use crate::materials::Material;
+use crate::materials::Scatterable;
+use crate::materials::Subsurface;
+use crate::medium::HenyeyGreenstein;
+use crate::mlt::Metropolis;
+use crate::photon_map::PhotonMapIntegrator;
+use crate::photon_map::PhotonMapping;
+use crate::point3d::Point3D;
+use crate::ray::HitRecord;
+use crate::ray::Ray;
+use crate::raytracer::hit_world;
+use crate::sampler::random_f64;
+use crate::spectrum::at_wavelength;
+use crate::sphere::Sphere;
+use crate::volume::VolumeEvent;
+
+#[cfg(test)]
+use crate::camera::Camera;
+#[cfg(test)]
+use crate::materials::Coated;
+#[cfg(test)]
+use crate::materials::Conductor;
+#[cfg(test)]
+use crate::materials::Lambertian;
+#[cfg(test)]
+use crate::materials::Light;
+#[cfg(test)]
+use crate::materials::Shaded;
+#[cfg(test)]
+use crate::medium::Fog;
+#[cfg(test)]
+use crate::shader::{Node, ShaderGraph};
+#[cfg(test)]
+use crate::volume::Volume;
+#[cfg(test)]
+use crate::volume::VoxelGrid;
+#[cfg(test)]
+use rayon::prelude::*;
+
+// An integrator computes the color seen along a camera ray. The renderer
+// is agnostic of the light transport algorithm, so that cheap preview or
+// debugging integrators can be swapped in for the full path tracer.
+pub trait Integrator: Sync {
+    fn ray_color(&self, ray: &Ray, scene: &Config, lights: &[Sphere]) -> Srgb;
+
+    // Integrators which cannot render pixels independently of each other
+    // return the whole frame instead, as linear colors in row-major order.
+    fn render_frame(&self, _scene: &Config, _lights: &[Sphere]) -> Option<Vec<Srgb>> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+pub enum IntegratorKind {
+    #[default]
+    Path,
+    Direct,
+    AmbientOcclusion(AmbientOcclusion),
+    Bidirectional,
+    PhotonMapping(PhotonMapping),
+    Metropolis(Metropolis),
+    PathGuiding(PathGuiding),
+    IrradianceCaching(IrradianceCaching),
+    Aov(Aov),
+}
+
+impl IntegratorKind {
+    pub fn is_default(&self) -> bool {
+        *self == IntegratorKind::default()
+    }
+
+    // Debug views render values rather than light, which are neither white
+    // balanced nor sampled by wavelength.
+    pub fn renders_light(&self) -> bool {
+        !matches!(self, IntegratorKind::Aov(_))
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            IntegratorKind::Path => "path tracing",
+            IntegratorKind::Direct => "direct lighting",
+            IntegratorKind::AmbientOcclusion(_) => "ambient occlusion",
+            IntegratorKind::Bidirectional => "bidirectional path tracing",
+            IntegratorKind::PhotonMapping(_) => "photon mapping",
+            IntegratorKind::Metropolis(_) => "Metropolis light transport",
+            IntegratorKind::PathGuiding(_) => "path guiding",
+            IntegratorKind::IrradianceCaching(_) => "irradiance caching",
+            IntegratorKind::Aov(aov) => aov.name(),
+        }
+    }
+
+    // Features of the scene which the integrator would leave out of the
+    // image, rendering a different one than the path tracer.
+    fn unsupported(&self) -> &'static [Feature] {
+        match self {
+            IntegratorKind::Bidirectional => &[
+                Feature::Fog,
+                Feature::Volumes,
+                Feature::Spectral,
+                Feature::Subsurface,
+            ],
+            IntegratorKind::PhotonMapping(_) => &[
+                Feature::Fog,
+                Feature::Volumes,
+                Feature::Spectral,
+                Feature::Subsurface,
+            ],
+            IntegratorKind::IrradianceCaching(_) => &[Feature::Subsurface],
+            _ => &[],
+        }
+    }
+
+    // Creates the integrator for a frame, running any pass that has to
+    // happen before the first camera ray is traced. Fails for scenes with
+    // features the integrator can't render.
+    pub fn build(
+        &self,
+        scene: &Config,
+        lights: &[Sphere],
+    ) -> Result<Box<dyn Integrator>, IntegratorError> {
+        if let Some(feature) = self.unsupported().iter().find(|f| f.is_used_by(scene)) {
+            return Err(IntegratorError::Unsupported(self.name(), feature.name()));
+        }
+        Ok(match self {
+            IntegratorKind::Path => Box::new(PathTracer::with_lights(lights)),
+            IntegratorKind::Direct => Box::new(DirectLighting::with_lights(lights)),
+            IntegratorKind::AmbientOcclusion(ao) => Box::new(*ao),
+            IntegratorKind::Bidirectional => Box::new(BidirectionalPathTracer::new()),
+            IntegratorKind::PhotonMapping(params) => {
+                Box::new(PhotonMapIntegrator::new(params, scene, lights))
+            }
+            IntegratorKind::Metropolis(params) => Box::new(*params),
+            IntegratorKind::PathGuiding(params) => Box::new(*params),
+            IntegratorKind::IrradianceCaching(params) => {
+                Box::new(IrradianceCacheIntegrator::new(params, scene, lights))
+            }
+            IntegratorKind::Aov(aov) => Box::new(*aov),
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum IntegratorError {
+    #[error("{0} doesn't support {1}")]
+    Unsupported(&'static str, &'static str),
+}
+
+// Parts of a scene which not every integrator renders.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Feature {
+    Fog,
+    Volumes,
+    Spectral,
+    Subsurface,
+}
+
+impl Feature {
+    fn name(&self) -> &'static str {
+        match self {
+            Feature::Fog => "fog",
+            Feature::Volumes => "volumes",
+            Feature::Spectral => "spectral rendering",
+            Feature::Subsurface => "subsurface scattering",
+        }
+    }
+
+    fn is_used_by(&self, scene: &Config) -> bool {
+        match self {
+            Feature::Fog => scene.fog.is_some(),
+            Feature::Volumes => !scene.volumes.is_empty(),
+            Feature::Spectral => scene.spectral,
+            Feature::Subsurface => scene.objects.iter().any(|o| o.material.has_subsurface()),
+        }
+    }
+}
+
+fn clamp(value: f32) -> f32 {
+    value.clamp(0.0, 1.0)
+}
+
+pub fn sky_color(ray: &Ray, scene: &Config) -> Srgb {
+    match &scene.sky {
+        None => Srgb::new(0.0, 0.0, 0.0),
+        Some(sky) => sky.color(&ray.direction),
+    }
+}
+
+// Unidirectional path tracer with the occasional explicit bounce towards
+// the lights in the scene. A light ray stands in for the light the
+// scattered ray would find by hitting a light, so with or without them the
+// tracer converges to the same image as `BidirectionalPathTracer`.
+#[derive(Debug, Clone)]
+pub struct PathTracer {
+    light_rays: bool,
+    light_bvh: Option<LightBvh>,
+    guiding: Option<Guiding>,
+}
+
+impl PathTracer {
+    pub fn new() -> PathTracer {
+        PathTracer {
+            light_rays: true,
+            light_bvh: None,
+            guiding: None,
+        }
+    }
+
+    pub fn without_light_rays() -> PathTracer {
+        PathTracer {
+            light_rays: false,
+            light_bvh: None,
+            guiding: None,
+        }
+    }
+
+    // Picks the light sampled at each point by importance rather than
+    // uniformly, which pays off in scenes with many lights.
+    pub fn with_lights(lights: &[Sphere]) -> PathTracer {
+        PathTracer {
+            light_rays: true,
+            light_bvh: Some(LightBvh::new(lights)),
+            guiding: None,
+        }
+    }
+
+    // Samples diffuse bounces from a learned distribution of the incoming
+    // light as well, and records the light they find.
+    pub fn guided(self, guiding: Guiding) -> PathTracer {
+        PathTracer {
+            guiding: Some(guiding),
+            ..self
+        }
+    }
+
+    // Light arriving along a ray, leaving out the lights when hit directly:
+    // the caller samples those itself.
+    pub fn indirect_radiance(&self, ray: &Ray, scene: &Config, lights: &[Sphere]) -> Srgb {
+        let depth = scene.max_depth.saturating_sub(1);
+        self.trace(ray, scene, lights, depth, depth, Scattered::Medium)
+    }
+
+    fn trace(
+        &self,
+        ray: &Ray,
+        scene: &Config,
+        lights: &[Sphere],
+        max_depth: usize,
+        depth: usize,
+        previous: Scattered,
+    ) -> Srgb {
+        let mut rng = rand::thread_rng();
+
+        if depth == 0 {
+            return Srgb::new(0.0, 0.0, 0.0);
+        }
+        let hit = hit_world(&scene.objects, ray, 0.001, f64::MAX);
+        // rays inside translucent objects walk through them, instead of the
+        // fog and the volumes
+        if let Some(hit_record) = hit.filter(|h| !h.front_face) {
+            if let Some(subsurface) = hit_record.material.subsurface(&hit_record) {
+                return self.walk_subsurface(&subsurface, ray, scene, lights, max_depth, depth);
+            }
+        }
+        let t_max = hit.as_ref().map_or(f64::INFINITY, |h| h.t);
+        match sample_medium(scene, ray, t_max) {
+            Some(MediumEvent::Scatter(t, weight, phase)) => {
+                let scattered_at =
+                    Ray::new(ray.at(t), ray.direction).with_wavelength(ray.wavelength);
+                let color =
+                    self.scatter_in_medium(phase, &scattered_at, scene, lights, max_depth, depth);
+                return color::scale(color, weight as f32);
+            }
+            Some(MediumEvent::Absorb(_, emitted)) => return emitted,
+            None => {}
+        }
+        match hit {
+            Some(hit_record) => {
+                // emitters seen from a medium were sampled there
+                if let Some(emitted) = hit_record.material.emission(&hit_record) {
+                    return match previous {
+                        Scattered::Medium | Scattered::Lit(_) => Srgb::new(0.0, 0.0, 0.0),
+                        _ => at_wavelength(emitted, ray.wavelength),
+                    };
+                }
+                let diffuse = matches!(
+                    hit_record.material,
+                    Material::Lambertian(_) | Material::Texture(_)
+                );
+
+                // surfaces which can evaluate their BSDF also sample the
+                // sky texture directly; the scattered ray then only gets
+                // its share of the sky under multiple importance sampling
+                let sky_sampled = !hit_record.material.is_delta()
+                    && scene
+                        .sky
+                        .as_ref()
+                        .is_some_and(|sky| sky.is_importance_sampled());
+                let sky_light = match (sky_sampled, &scene.sky) {
+                    (true, Some(sky)) => {
+                        let guiding = self.guiding.as_ref().filter(|_| diffuse);
+                        sample_sky(sky, &hit_record, ray, scene, guiding)
+                    }
+                    _ => Srgb::new(0.0, 0.0, 0.0),
+                };
+
+                let sample = match hit_record.material.sample(ray, &hit_record) {
+                    Some(sample) => sample,
+                    // don't bother bouncing absorbed rays towards lights
+                    // (they would be absorbed in the opposite direction).
+                    None => return sky_light,
+                };
+                let albedo = sample.weight;
+                let prob = match hit_record.material {
+                    Material::Glass(_) => 0.05,
+                    _ => 0.1,
+                };
+                // the light ray replaces the light the scattered ray finds
+                // on a light, which keeps both choices unbiased
+                let light_sampled = self.light_rays
+                    && !hit_record.material.is_delta()
+                    && !lights.is_empty()
+                    && rng.gen::<f64>() > (1.0 - lights.len() as f64 * prob)
+                    && depth + 2 > max_depth;
+                let direct = match light_sampled {
+                    true => {
+                        // one light stands in for the average over all of
+                        // them, so the cost doesn't grow with their number
+                        let normal = match hit_record.material {
+                            Material::Lambertian(_) | Material::Texture(_) => {
+                                Some(&hit_record.normal)
+                            }
+                            _ => None,
+                        };
+                        match pick_light(&self.light_bvh, lights, &hit_record.point, normal) {
+                            Some((light, probability)) => {
+                                sample_light_at_surface(&hit_record, ray, scene, light, probability)
+                            }
+                            None => Srgb::new(0.0, 0.0, 0.0),
+                        }
+                    }
+                    false => Srgb::new(0.0, 0.0, 0.0),
+                };
+
+                // guided diffuse bounces pick their direction from a
+                // mixture with the learned distribution, and are
+                // weighted by its density
+                let mut throughput = albedo;
+                let mut scatter_pdf = match sample.delta {
+                    true => None,
+                    false => Some(sample.pdf),
+                };
+                let scattered_ray = match (&self.guiding, diffuse) {
+                    (Some(guiding), true) => {
+                        let (direction, pdf) =
+                            guiding.sample(&hit_record.point, &hit_record.normal);
+                        let cosine = direction.dot(&hit_record.normal).max(0.0);
+                        throughput = color::scale(albedo, (cosine / PI / pdf) as f32);
+                        scatter_pdf = Some(pdf);
+                        Ray::new(hit_record.point, direction)
+                    }
+                    _ => Ray::new(hit_record.point, sample.wi),
+                }
+                .with_wavelength(ray.wavelength);
+
+                let sky_pdf = scatter_pdf.filter(|_| sky_sampled);
+                // lobes which are only sampled still find the lights
+                let scattered = match (light_sampled && !sample.delta, sky_pdf) {
+                    (true, _) => Scattered::Lit(sky_pdf),
+                    (false, Some(pdf)) => Scattered::Surface(pdf),
+                    (false, None) => Scattered::Other,
+                };
+                let target_color = self.trace(
+                    &scattered_ray,
+                    scene,
+                    lights,
+                    max_depth,
+                    depth - 1,
+                    scattered,
+                );
+                if let (Some(guiding), true, Some(pdf)) = (&self.guiding, diffuse, scatter_pdf) {
+                    guiding.record(
+                        &hit_record.point,
+                        &scattered_ray.direction,
+                        target_color,
+                        pdf,
+                    );
+                }
+                Srgb::new(
+                    direct.red + throughput.red * target_color.red + sky_light.red,
+                    direct.green + throughput.green * target_color.green + sky_light.green,
+                    direct.blue + throughput.blue * target_color.blue + sky_light.blue,
+                )
+            }
+            None => match (&scene.sky, previous) {
+                (Some(sky), Scattered::Surface(bsdf_pdf) | Scattered::Lit(Some(bsdf_pdf))) => {
+                    color::scale(
+                        sky.color(&ray.direction),
+                        power_heuristic(bsdf_pdf, sky.pdf(&ray.direction)) as f32,
+                    )
+                }
+                _ => sky_color(ray, scene),
+            },
+        }
+    }
+}
+
+impl PathTracer {
+    // Continues a path scattered by a medium at the origin of `ray`, which
+    // points along the direction the light was traveling in.
+    fn scatter_in_medium(
+        &self,
+        phase: HenyeyGreenstein,
+        ray: &Ray,
+        scene: &Config,
+        lights: &[Sphere],
+        max_depth: usize,
+        depth: usize,
+    ) -> Srgb {
+        let direct = match pick_light(&self.light_bvh, lights, &ray.origin, None) {
+            Some((light, probability)) => {
+                sample_light_in_medium(phase, ray, scene, light, probability)
+            }
+            None => Srgb::new(0.0, 0.0, 0.0),
+        };
+        let direction = phase.sample(&ray.direction, random_f64(), random_f64());
+        let indirect = self.trace(
+            &Ray::new(ray.origin, direction).with_wavelength(ray.wavelength),
+            scene,
+            lights,
+            max_depth,
+            depth - 1,
+            Scattered::Medium,
+        );
+        color::add(direct, indirect)
+    }
+
+    // Follows the light back through the interior of a subsurface
+    // scattering object, from a ray inside it to where the light entered.
+    // Steps inside don't count towards the depth of the path; instead, walks
+    // whose light is mostly absorbed are ended at random, and the others
+    // weighted up to make up for them.
+    fn walk_subsurface(
+        &self,
+        subsurface: &Subsurface,
+        ray: &Ray,
+        scene: &Config,
+        lights: &[Sphere],
+        max_depth: usize,
+        depth: usize,
+    ) -> Srgb {
+        let mut ray = *ray;
+        let mut throughput = Srgb::new(1.0, 1.0, 1.0);
+        loop {
+            let hit_record = match hit_world(&scene.objects, &ray, 0.001, f64::MAX) {
+                Some(hit_record) if !hit_record.front_face => hit_record,
+                // something else inside the object
+                _ => {
+                    let color = self.trace(&ray, scene, lights, max_depth, depth, Scattered::Other);
+                    return color::mul(throughput, color);
+                }
+            };
+            let (scattered_at, weight) = subsurface.sample_distance(&ray, hit_record.t, throughput);
+            throughput = color::mul(throughput, weight);
+            let survival = throughput.red.max(throughput.green).max(throughput.blue);
+            if survival < 1.0 {
+                if random_f64() >= survival as f64 {
+                    return Srgb::new(0.0, 0.0, 0.0);
+                }
+                throughput = color::scale(throughput, 1.0 / survival);
+            }
+            if let Some(t) = scattered_at {
+                let phase = subsurface.phase_function();
+                let direction = phase.sample(&ray.direction, random_f64(), random_f64());
+                ray = Ray::new(ray.at(t), direction).with_wavelength(ray.wavelength);
+                continue;
+            }
+            let sample = match hit_record.material.sample(&ray, &hit_record) {
+                Some(sample) => sample,
+                None => return Srgb::new(0.0, 0.0, 0.0),
+            };
+            throughput = color::mul(throughput, sample.weight);
+            ray = Ray::new(hit_record.point, sample.wi).with_wavelength(ray.wavelength);
+            // reflected back inside by the surface
+            if sample.wi.dot(&hit_record.normal) > 0.0 {
+                continue;
+            }
+            let color = self.trace(&ray, scene, lights, max_depth, depth - 1, Scattered::Other);
+            return color::mul(throughput, color);
+        }
+    }
+}
+
+// What scattered the ray being traced, which decides how the light it finds
+// is weighted against the light sampled explicitly at that point.
+#[derive(Debug, Clone, Copy)]
+enum Scattered {
+    // the camera, a specular surface or a light ray: nothing was sampled
+    Other,
+    // a surface which also sampled the sky, with the density of the
+    // scattered direction
+    Surface(f64),
+    // the fog or a volume, or anything else which sampled the lights
+    Medium,
+    // a surface which sampled the lights, with the density of the
+    // scattered direction if it also sampled the sky
+    Lit(Option<f64>),
+}
+
+// What light traveling along a ray runs into in the fog or the volumes
+// before the ray parameter `t_max`.
+#[derive(Debug, Clone, Copy)]
+enum MediumEvent {
+    // scattered at `t`, with the weight of the scattered light and the
+    // phase function of the medium
+    Scatter(f64, f64, HenyeyGreenstein),
+    // absorbed at `t`, with the light emitted there
+    Absorb(f64, Srgb),
+}
+
+impl MediumEvent {
+    fn t(&self) -> f64 {
+        match self {
+            MediumEvent::Scatter(t, _, _) | MediumEvent::Absorb(t, _) => *t,
+        }
+    }
+}
+
+// Extinction adds up across media, so the first interaction along the ray is
+// the earliest of the ones sampled in each medium separately.
+fn sample_medium(scene: &Config, ray: &Ray, t_max: f64) -> Option<MediumEvent> {
+    let mut event = scene.fog.as_ref().and_then(|fog| {
+        fog.sample_distance(ray, t_max, random_f64())
+            .map(|t| MediumEvent::Scatter(t, fog.albedo(), fog.phase_function()))
+    });
+    for volume in &scene.volumes {
+        let t_max = event.map_or(t_max, |e| e.t());
+        event = match volume.sample_interaction(ray, t_max) {
+            Some(VolumeEvent::Scatter(t)) => {
+                Some(MediumEvent::Scatter(t, 1.0, volume.phase_function()))
+            }
+            Some(VolumeEvent::Absorb(t, emitted)) => Some(MediumEvent::Absorb(t, emitted)),
+            None => event,
+        };
+    }
+    event
+}
+
+// Fraction of light passing through the fog and the volumes along the ray up
+// to the parameter `t`.
+fn medium_transmittance(scene: &Config, ray: &Ray, t: f64) -> f64 {
+    let fog = scene
+        .fog
+        .as_ref()
+        .map_or(1.0, |fog| fog.transmittance(ray, t));
+    scene.volumes.iter().fold(fog, |transmittance, volume| {
+        transmittance * volume.transmittance(ray, t)
+    })
+}
+
+// Picks a light to sample at a point, with the probability of picking it:
+// by importance if the integrator built a hierarchy over these lights, and
+// uniformly otherwise.
+pub fn pick_light<'a>(
+    light_bvh: &Option<LightBvh>,
+    lights: &'a [Sphere],
+    point: &Point3D,
+    normal: Option<&Point3D>,
+) -> Option<(&'a Sphere, f64)> {
+    match light_bvh {
+        Some(bvh) if bvh.len() == lights.len() => bvh
+            .sample(point, normal, random_f64())
+            .map(|(index, probability)| (&lights[index], probability)),
+        _ if lights.is_empty() => None,
+        _ => {
+            let index = ((random_f64() * lights.len() as f64) as usize).min(lights.len() - 1);
+            Some((&lights[index], 1.0 / lights.len() as f64))
+        }
+    }
+}
+
+// Samples a direction from a point towards a light, uniformly within the
+// cone the light subtends. Returns the direction, the light's radiance
+// along it, its density and the ray parameter at which the light is hit, or
+// None if something else is in the way.
+pub fn sample_light(
+    scene: &Config,
+    origin: &Point3D,
+    light: &Sphere,
+) -> Option<(Point3D, Srgb, f64, f64)> {
+    let to_center = light.center - *origin;
+    let sin_max_squared = light.radius * light.radius / to_center.length_squared();
+    if sin_max_squared >= 1.0 {
+        return None;
+    }
+    let cos_max = (1.0 - sin_max_squared).sqrt();
+    let cos_theta = 1.0 - random_f64() * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * random_f64();
+    let w = to_center.unit_vector();
+    let (u, v) = w.perpendicular_basis();
+    let direction = u * (sin_theta * phi.cos()) + v * (sin_theta * phi.sin()) + w * cos_theta;
+
+    let shadow_ray = Ray::new(*origin, direction);
+    let hit_record = hit_world(&scene.objects, &shadow_ray, 0.001, f64::MAX)?;
+    match hit_record.material.scatter(&shadow_ray, &hit_record) {
+        Some((None, emitted)) => {
+            let pdf = 1.0 / (2.0 * PI * (1.0 - cos_max));
+            Some((direction, emitted, pdf, hit_record.t))
+        }
+        _ => None,
+    }
+}
+
+// Light reaching a point in a medium from a light picked with the given
+// probability.
+fn sample_light_in_medium(
+    phase: HenyeyGreenstein,
+    ray: &Ray,
+    scene: &Config,
+    light: &Sphere,
+    probability: f64,
+) -> Srgb {
+    match sample_light(scene, &ray.origin, light) {
+        Some((direction, emitted, pdf, t)) => {
+            let phase = phase.phase(direction.dot(&ray.direction.unit_vector()));
+            let shadow_ray = Ray::new(ray.origin, direction);
+            let transmittance = medium_transmittance(scene, &shadow_ray, t);
+            color::scale(
+                emitted,
+                (phase * transmittance / (probability * pdf)) as f32,
+            )
+        }
+        None => Srgb::new(0.0, 0.0, 0.0),
+    }
+}
+
+// Light reaching a surface from a light picked with the given probability,
+// towards the viewer along `ray`.
+fn sample_light_at_surface(
+    hit_record: &HitRecord,
+    ray: &Ray,
+    scene: &Config,
+    light: &Sphere,
+    probability: f64,
+) -> Srgb {
+    match sample_light(scene, &hit_record.point, light) {
+        Some((direction, emitted, pdf, t)) => {
+            let wo = -ray.direction.unit_vector();
+            let f = hit_record.material.eval(hit_record, &direction, &wo);
+            let cosine = direction.dot(&hit_record.normal).abs();
+            let shadow_ray = Ray::new(hit_record.point, direction);
+            let transmittance = medium_transmittance(scene, &shadow_ray, t);
+            let f = at_wavelength(f, ray.wavelength);
+            color::scale(
+                color::mul(f, at_wavelength(emitted, ray.wavelength)),
+                (cosine * transmittance / (probability * pdf)) as f32,
+            )
+        }
+        None => Srgb::new(0.0, 0.0, 0.0),
+    }
+}
+
+fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    match pdf * pdf + other_pdf * other_pdf {
+        x if x > 0.0 => pdf * pdf / x,
+        _ => 0.0,
+    }
+}
+
+// Light arriving at a hit from one direction sampled towards the bright
+// parts of the sky, weighted against the same direction having been sampled
+// by the material, or by the guided diffuse bounce.
+fn sample_sky(
+    sky: &Sky,
+    hit_record: &HitRecord,
+    ray: &Ray,
+    scene: &Config,
+    guiding: Option<&Guiding>,
+) -> Srgb {
+    let (direction, sky_pdf) = match sky.sample_direction(random_f64(), random_f64()) {
+        Some(sample) => sample,
+        None => return Srgb::new(0.0, 0.0, 0.0),
+    };
+    let wo = -ray.direction.unit_vector();
+    let f = hit_record.material.eval(hit_record, &direction, &wo);
+    if color::is_black(f) {
+        return Srgb::new(0.0, 0.0, 0.0);
+    }
+    let shadow_ray = Ray::new(hit_record.point, direction);
+    if hit_world(&scene.objects, &shadow_ray, 0.001, f64::MAX).is_some() {
+        return Srgb::new(0.0, 0.0, 0.0);
+    }
+    let transmittance = medium_transmittance(scene, &shadow_ray, f64::INFINITY);
+    let cosine = direction.dot(&hit_record.normal).abs();
+    let scatter_pdf = match guiding {
+        Some(g) => g.pdf(&hit_record.point, &hit_record.normal, &direction),
+        None => hit_record.material.pdf(hit_record, &direction, &wo),
+    };
+    let weight = power_heuristic(sky_pdf, scatter_pdf) * cosine / sky_pdf * transmittance;
+    let f = at_wavelength(f, ray.wavelength);
+    color::scale(color::mul(f, sky.color(&direction)), weight as f32)
+}
+
+impl Default for PathTracer {
+    fn default() -> Self {
+        PathTracer::new()
+    }
+}
+
+impl Integrator for PathTracer {
+    fn ray_color(&self, ray: &Ray, scene: &Config, lights: &[Sphere]) -> Srgb {
+        self.trace(
+            ray,
+            scene,
+            lights,
+            scene.max_depth,
+            scene.max_depth,
+            Scattered::Other,
+        )
+    }
+}
+
+// Direct lighting only: surfaces are lit by the lights and the sky without
+// any indirect bounce. Rays sampled from delta lobes (metal and glass) are
+// followed until they reach another surface, so reflections stay visible.
+#[derive(Debug, Clone, Default)]
+pub struct DirectLighting {
+    light_bvh: Option<LightBvh>,
+}
+
+impl DirectLighting {
+    pub fn new() -> DirectLighting {
+        DirectLighting { light_bvh: None }
+    }
+
+    pub fn with_lights(lights: &[Sphere]) -> DirectLighting {
+        DirectLighting {
+            light_bvh: Some(LightBvh::new(lights)),
+        }
+    }
+
+    fn trace(&self, ray: &Ray, scene: &Config, lights: &[Sphere], depth: usize) -> Srgb {
+        if depth == 0 {
+            return Srgb::new(0.0, 0.0, 0.0);
+        }
+        let hit_record = match hit_world(&scene.objects, ray, 0.001, f64::MAX) {
+            Some(hit_record) => hit_record,
+            None => return sky_color(ray, scene),
+        };
+        // emitters return their own color
+        if let Some(emitted) = hit_record.material.emission(&hit_record) {
+            return at_wavelength(emitted, ray.wavelength);
+        }
+        let sample = hit_record.material.sample(ray, &hit_record);
+        if hit_record.material.is_delta() && sample.is_none() {
+            return Srgb::new(0.0, 0.0, 0.0);
+        }
+
+        // sky: a single bounce that escapes the scene; absorbed samples of
+        // glossy surfaces still see the lights below
+        let mut sky = Srgb::new(0.0, 0.0, 0.0);
+        if let Some(sample) = sample {
+            let albedo = sample.weight;
+            let scattered_ray =
+                Ray::new(hit_record.point, sample.wi).with_wavelength(ray.wavelength);
+            if sample.delta {
+                let color = self.trace(&scattered_ray, scene, lights, depth - 1);
+                return color::mul(albedo, color);
+            }
+            if hit_world(&scene.objects, &scattered_ray, 0.001, f64::MAX).is_none() {
+                sky = color::mul(albedo, sky_color(&scattered_ray, scene));
+            }
+        }
+
+        // lights: one shadow ray towards a light picked by importance,
+        // weighted by the cosine of the incident angle, and by the BSDF
+        // relative to a Lambertian one
+        let mut direct = Srgb::new(0.0, 0.0, 0.0);
+        let picked = pick_light(
+            &self.light_bvh,
+            lights,
+            &hit_record.point,
+            Some(&hit_record.normal),
+        );
+        if let Some((light, probability)) = picked {
+            let to_light = (light.center - hit_record.point).unit_vector();
+            let cosine = to_light.dot(&hit_record.normal);
+            let shadow_ray = Ray::new(hit_record.point, to_light);
+            let wo = -ray.direction.unit_vector();
+            let f = hit_record.material.eval(&hit_record, &to_light, &wo);
+            let reflectance = at_wavelength(color::scale(f, PI as f32), ray.wavelength);
+            if cosine > 0.0 {
+                if let Some(occluder) = hit_world(&scene.objects, &shadow_ray, 0.001, f64::MAX) {
+                    if let Some(emitted) = occluder.material.emission(&occluder) {
+                        let weight = (cosine / (probability * lights.len() as f64)) as f32;
+                        direct = color::mul(reflectance, color::scale(emitted, weight));
+                    }
+                }
+            }
+        }
+
+        let color = color::add(sky, direct);
+        Srgb::new(clamp(color.red), clamp(color.green), clamp(color.blue))
+    }
+}
+
+impl Integrator for DirectLighting {
+    fn ray_color(&self, ray: &Ray, scene: &Config, lights: &[Sphere]) -> Srgb {
+        self.trace(ray, scene, lights, scene.max_depth)
+    }
+}
+
+// Ambient occlusion: the fraction of rays around the first hit that do not
+// hit anything within `distance`. Ignores materials and lights entirely.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct AmbientOcclusion {
+    pub samples: u32,
+    pub distance: f64,
+}
+
+impl AmbientOcclusion {
+    pub fn new(samples: u32, distance: f64) -> AmbientOcclusion {
+        AmbientOcclusion { samples, distance }
+    }
+}
+
+impl Integrator for AmbientOcclusion {
+    fn ray_color(&self, ray: &Ray, scene: &Config, _lights: &[Sphere]) -> Srgb {
+        let hit_record = match hit_world(&scene.objects, ray, 0.001, f64::MAX) {
+            Some(hit_record) => hit_record,
+            None => return Srgb::new(1.0, 1.0, 1.0),
+        };
+        let mut unoccluded = 0;
+        for _ in 0..self.samples {
+            let mut direction = hit_record.normal + Point3D::random_in_unit_sphere();
+            if direction.near_zero() {
+                direction = hit_record.normal;
+            }
+            let occlusion_ray = Ray::new(hit_record.point, direction.unit_vector());
+            if hit_world(&scene.objects, &occlusion_ray, 0.001, self.distance).is_none() {
+                unoccluded += 1;
+            }
+        }
+        let visibility = unoccluded as f32 / self.samples.max(1) as f32;
+        Srgb::new(visibility, visibility, visibility)
+    }
+}
+
+// Average colors over a grid of `columns` by `rows` regions of the image,
+// in row-major order, with `samples` camera rays through each region.
+#[cfg(test)]
+pub fn region_colors(
+    scene: &Config,
+    integrator: &dyn Integrator,
+    columns: usize,
+    rows: usize,
+    samples: usize,
+) -> Vec<Srgb> {
+    let lights: Vec<Sphere> = scene
+        .objects
+        .iter()
+        .filter(|s| s.material.is_light())
+        .cloned()
+        .collect();
+    (0..columns * rows)
+        .into_par_iter()
+        .map(|region| {
+            let mut rng = rand::thread_rng();
+            let mut sum = color::black();
+            for _ in 0..samples {
+                let u = ((region % columns) as f64 + rng.gen::<f64>()) / columns as f64;
+                let v = ((region / columns) as f64 + rng.gen::<f64>()) / rows as f64;
+                let r = scene.camera.get_ray(u, v);
+                sum = color::add(sum, integrator.ray_color(&r, scene, &lights));
+            }
+            color::scale(sum, 1.0 / samples as f32)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn test_scene(objects: Vec<Sphere>) -> Config {
+    Config {
+        width: 80,
+        height: 60,
+        samples_per_pixel: 1,
+        max_depth: 2,
+        spectral: false,
+        sky: Some(Sky::new_default_sky()),
+        fog: None,
+        volumes: Vec::new(),
+        camera: Camera::new(
+            Point3D::new(0.0, 0.0, -3.0),
+            Point3D::new(0.0, 0.0, 0.0),
+            Point3D::new(0.0, 1.0, 0.0),
+            20.0,
+            1.333,
+        ),
+        integrator: IntegratorKind::Path,
+        objects,
+    }
+}
+
+#[test]
+fn test_ray_color() {
+    let r = Ray::new(Point3D::new(0.0, 0.0, 0.0), Point3D::new(1.0, 0.0, 0.0));
+    let scene = test_scene(Vec::new());
+    assert_eq!(
+        PathTracer::new().ray_color(&r, &scene, &[]),
+        Srgb::new(0.75, 0.85, 1.0)
+    );
+}
+
+#[test]
+fn test_path_tracer_samples_sky() {
+    // a dim sky with a small, bright sun above a diffuse or glossy floor
+    let (width, height) = (16, 8);
+    let mut pixels = vec![0.1; width * height * 3];
+    pixels[(width + 5) * 3..(width + 6) * 3].copy_from_slice(&[1000.0, 1000.0, 1000.0]);
+    for material in [
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+        Material::Conductor(Conductor::copper(0.5)),
+    ] {
+        let mut scene = test_scene(vec![Sphere::new(
+            Point3D::new(0.0, -1000.0, 0.0),
+            1000.0,
+            material.clone(),
+        )]);
+        scene.sky = Some(Sky::new(
+            Some((pixels.clone(), width, height, String::new())),
+            30.0,
+            1.0,
+            None,
+        ));
+        let sky = scene.sky.as_ref().unwrap();
+
+        // reference: the sky's radiance reflected towards the zenith,
+        // integrated over the upper hemisphere
+        let up = Point3D::new(0.0, 1.0, 0.0);
+        let hit_record = HitRecord {
+            t: 1.0,
+            point: Point3D::new(0.0, 0.0, 0.0),
+            normal: up,
+            front_face: true,
+            material: &material,
+            u: 0.0,
+            v: 0.0,
+            wavelength: None,
+        };
+        let n = 32;
+        let mut expected = 0.0;
+        for i in 0..width * n {
+            for j in 0..height * n {
+                let u = (i as f64 + 0.5) / (width * n) as f64;
+                let v = (j as f64 + 0.5) / (height * n) as f64;
+                let d = sky.uv_to_direction(u, v);
+                if d.y() > 0.0 {
+                    let solid_angle =
+                        2.0 * PI * PI * (v * PI).sin() / (width * height * n * n) as f64;
+                    let f = material.eval(&hit_record, &d, &up).red as f64;
+                    expected += sky.color(&d).red as f64 * f * d.y() * solid_angle;
+                }
+            }
+        }
+
+        let r = Ray::new(Point3D::new(0.0, 1.0, 0.0), Point3D::new(0.0, -1.0, 0.0));
+        let samples = 20000;
+        let mut sum = 0.0;
+        for _ in 0..samples {
+            sum += PathTracer::without_light_rays()
+                .ray_color(&r, &scene, &[])
+                .red as f64;
+        }
+        let mean = sum / samples as f64;
+        assert!(
+            (mean - expected).abs() < 0.05 * expected,
+            "{:?}: {} != {}",
+            material,
+            mean,
+            expected
+        );
+    }
+}
+
+#[test]
+fn test_path_tracer_fog() {
+    let mut scene = test_scene(vec![Sphere::new(
+        Point3D::new(0.0, 0.0, -5.0),
+        0.5,
+        Material::Light(Light::new()),
+    )]);
+    scene.sky = None;
+    let lights = scene.objects.clone();
+
+    // the light is seen through 4.5 units of absorbing fog
+    scene.fog = Some(Fog::new(0.2, 0.0, 0.0, 0.0));
+    let r = Ray::new(Point3D::new(0.0, 0.0, 0.0), Point3D::new(0.0, 0.0, -1.0));
+    let samples = 10000;
+    let mut sum = 0.0;
+    for _ in 0..samples {
+        sum += PathTracer::new().ray_color(&r, &scene, &lights).red;
+    }
+    let expected = (-0.9f32).exp();
+    assert!((sum / samples as f32 - expected).abs() < 0.05 * expected);
+
+    // scattering fog lights up rays passing by the light
+    let r = Ray::new(Point3D::new(0.0, 1.0, 0.0), Point3D::new(0.0, 0.0, -1.0));
+    scene.fog = Some(Fog::new(0.0, 0.2, 0.0, 0.0));
+    let mut sum = 0.0;
+    for _ in 0..1000 {
+        sum += PathTracer::new().ray_color(&r, &scene, &lights).red;
+    }
+    assert!(sum > 0.0);
+    scene.fog = None;
+    assert_eq!(
+        PathTracer::new().ray_color(&r, &scene, &lights),
+        Srgb::new(0.0, 0.0, 0.0)
+    );
+}
+
+#[test]
+fn test_path_tracer_volume() {
+    let mut scene = test_scene(vec![Sphere::new(
+        Point3D::new(0.0, 0.0, -5.0),
+        0.5,
+        Material::Light(Light::new()),
+    )]);
+    scene.sky = None;
+    let lights = scene.objects.clone();
+
+    // the light is seen through an absorbing box, two units deep
+    let grid = VoxelGrid::new([2, 2, 2], vec![0.5; 8], None);
+    scene.volumes = vec![Volume::new(
+        grid,
+        Point3D::new(-1.0, -1.0, -3.0),
+        Point3D::new(1.0, 1.0, -1.0),
+        1.0,
+        0.0,
+    )];
+    let r = Ray::new(Point3D::new(0.0, 0.0, 0.0), Point3D::new(0.0, 0.0, -1.0));
+    let samples = 10000;
+    let mut sum = 0.0;
+    for _ in 0..samples {
+        sum += PathTracer::new().ray_color(&r, &scene, &lights).red;
+    }
+    let expected = (-1.0f32).exp();
+    assert!((sum / samples as f32 - expected).abs() < 0.05 * expected);
+
+    // and glows once it is hot enough
+    let grid = VoxelGrid::new([2, 2, 2], vec![0.5; 8], Some(vec![1500.0; 8]));
+    scene.volumes[0].grid = grid;
+    scene.volumes[0].emission = 1.0;
+    scene.objects.clear();
+    let mut glow = Srgb::new(0.0, 0.0, 0.0);
+    for _ in 0..100 {
+        glow = color::add(glow, PathTracer::new().ray_color(&r, &scene, &[]));
+    }
+    assert!(glow.red > glow.blue);
+}
+
+#[test]
+fn test_path_tracer_light_bvh() {
+    // a row of lights of different sizes in front of a diffuse sphere
+    let lights: Vec<Sphere> = (0..8)
+        .map(|i| {
+            Sphere::new(
+                Point3D::new(i as f64 - 3.5, 1.5, -4.0),
+                0.1 + 0.05 * i as f64,
+                Material::Light(Light::new()),
+            )
+        })
+        .collect();
+    let mut objects = lights.clone();
+    objects.push(Sphere::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        1.0,
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+    ));
+    let mut scene = test_scene(objects);
+    scene.sky = None;
+
+    // picking lights by importance converges to the same color as picking
+    // them uniformly
+    let r = Ray::new(Point3D::new(0.0, 0.0, -3.0), Point3D::new(0.0, 0.0, 1.0));
+    let samples = 40000;
+    let mean = |integrator: &PathTracer| {
+        (0..samples)
+            .map(|_| integrator.ray_color(&r, &scene, &lights).red)
+            .sum::<f32>()
+            / samples as f32
+    };
+    let uniform = mean(&PathTracer::new());
+    let importance = mean(&PathTracer::with_lights(&lights));
+    assert!(
+        (uniform - importance).abs() < 0.05 * uniform,
+        "{} != {}",
+        uniform,
+        importance
+    );
+}
+
+#[test]
+fn test_direct_lighting() {
+    let light = Sphere::new(
+        Point3D::new(0.0, 0.0, 3.0),
+        0.5,
+        Material::Light(Light::new()),
+    );
+    let scene = test_scene(vec![
+        Sphere::new(
+            Point3D::new(0.0, 0.0, 0.0),
+            1.0,
+            Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+        ),
+        light.clone(),
+    ]);
+    let lights = vec![light];
+    let r = Ray::new(Point3D::new(0.0, 0.0, 2.0), Point3D::new(0.0, 0.0, -1.0));
+    let c = DirectLighting::new().ray_color(&r, &scene, &lights);
+    // the light faces the hit point head-on, so it contributes albedo * 1.0
+    assert!(c.red >= 0.5 && c.green >= 0.5 && c.blue >= 0.5);
+
+    // looking straight at the light returns its emission
+    let r = Ray::new(Point3D::new(0.0, 0.0, 5.0), Point3D::new(0.0, 0.0, -1.0));
+    let c = DirectLighting::new().ray_color(&r, &scene, &lights);
+    assert_eq!(c, Srgb::new(1.0, 1.0, 1.0));
+}
+
+#[test]
+fn test_ambient_occlusion() {
+    let scene = test_scene(vec![Sphere::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        1.0,
+        Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+    )]);
+    let ao = AmbientOcclusion::new(16, 1.0);
+
+    // a lone convex sphere never occludes itself
+    let r = Ray::new(Point3D::new(0.0, 0.0, -3.0), Point3D::new(0.0, 0.0, 1.0));
+    assert_eq!(ao.ray_color(&r, &scene, &[]), Srgb::new(1.0, 1.0, 1.0));
+
+    // misses are unoccluded
+    let r = Ray::new(Point3D::new(0.0, 0.0, -3.0), Point3D::new(0.0, 1.0, 0.0));
+    assert_eq!(ao.ray_color(&r, &scene, &[]), Srgb::new(1.0, 1.0, 1.0));
+}
+
+#[test]
+fn test_integrator_kind_to_json() {
+    assert_eq!(
+        serde_json::to_string(&IntegratorKind::Direct).unwrap(),
+        "\"Direct\""
+    );
+    let ao = IntegratorKind::AmbientOcclusion(AmbientOcclusion::new(8, 0.5));
+    let serialized = serde_json::to_string(&ao).unwrap();
+    assert_eq!(
+        serialized,
+        "{\"AmbientOcclusion\":{\"samples\":8,\"distance\":0.5}}"
+    );
+    assert_eq!(
+        serde_json::from_str::<IntegratorKind>(&serialized).unwrap(),
+        ao
+    );
+}
+
+#[test]
+fn test_integrator_kind_build() {
+    // integrators refuse scenes with features they would leave out, rather
+    // than render a different image than the path tracer
+    let mut fog = test_scene(Vec::new());
+    fog.fog = Some(Fog::new(0.2, 0.0, 0.0, 0.0));
+    let mut volumes = test_scene(Vec::new());
+    volumes.volumes = vec![Volume::new(
+        VoxelGrid::new([2, 2, 2], vec![0.5; 8], None),
+        Point3D::new(-1.0, -1.0, -3.0),
+        Point3D::new(1.0, 1.0, -1.0),
+        1.0,
+        0.0,
+    )];
+    let mut spectral = test_scene(Vec::new());
+    spectral.spectral = true;
+    // translucent materials are found inside other materials too
+    let subsurface = test_scene(vec![Sphere::new(
+        Point3D::new(0.0, 0.0, -1.0),
+        0.5,
+        Material::Coated(Coated::new(
+            Material::Subsurface(Subsurface::new(
+                Srgb::new(1.0, 1.0, 1.0),
+                Srgb::new(0.5, 0.5, 0.5),
+                1.4,
+            )),
+            1.5,
+        )),
+    )]);
+    let scenes = [
+        ("fog", fog),
+        ("volumes", volumes),
+        ("spectral rendering", spectral),
+        ("subsurface scattering", subsurface),
+    ];
+    let unsupported = [
+        (
+            IntegratorKind::Bidirectional,
+            vec![
+                "fog",
+                "volumes",
+                "spectral rendering",
+                "subsurface scattering",
+            ],
+        ),
+        (
+            IntegratorKind::PhotonMapping(PhotonMapping::new(100, 0.1)),
+            vec![
+                "fog",
+                "volumes",
+                "spectral rendering",
+                "subsurface scattering",
+            ],
+        ),
+        (
+            IntegratorKind::IrradianceCaching(IrradianceCaching::new(0.3, 16, 0.05, 2.0)),
+            vec!["subsurface scattering"],
+        ),
+        // Metropolis samples paths with the path tracer, which walks
+        // through translucent materials
+        (
+            IntegratorKind::Metropolis(Metropolis::new(100, 4, 0.3, 0.01)),
+            vec![],
+        ),
+    ];
+    for (feature, scene) in &scenes {
+        assert!(IntegratorKind::Path.build(scene, &[]).is_ok());
+        for (kind, features) in &unsupported {
+            let error = kind.build(scene, &[]).err().map(|e| e.to_string());
+            match features.contains(feature) {
+                true => assert_eq!(
+                    error,
+                    Some(format!("{} doesn't support {}", kind.name(), feature))
+                ),
+                false => assert!(error.is_none(), "{:?}", error),
+            }
+        }
+    }
+    let plain = test_scene(Vec::new());
+    for (kind, _) in &unsupported {
+        assert!(kind.build(&plain, &[]).is_ok());
+    }
+}
+
+#[test]
+fn test_subsurface_furnace() {
+    // a translucent sphere under a uniform white sky gives back all the
+    // light it receives, unless it absorbs some inside
+    let (white, blue_absorbing) = (Srgb::new(1.0, 1.0, 1.0), Srgb::new(1.0, 1.0, 0.7));
+    let mean_free_path = Srgb::new(0.5, 0.25, 0.1);
+    let mut means = Vec::new();
+    for albedo in [white, blue_absorbing] {
+        let mut scene = test_scene(vec![Sphere::new(
+            Point3D::new(0.0, 0.0, 0.0),
+            1.0,
+            Material::Subsurface(Subsurface::new(albedo, mean_free_path, 1.4)),
+        )]);
+        scene.max_depth = 10;
+        scene.sky = Some(Sky::new(
+            Some((vec![1.0; 4 * 2 * 3], 4, 2, String::new())),
+            0.0,
+            1.0,
+            None,
+        ));
+        let r = Ray::new(Point3D::new(0.2, 0.1, -3.0), Point3D::new(0.0, 0.0, 1.0));
+        let samples = 20000;
+        let mut sum = Srgb::new(0.0, 0.0, 0.0);
+        for _ in 0..samples {
+            let color = PathTracer::without_light_rays().ray_color(&r, &scene, &[]);
+            sum = color::add(sum, color::scale(color, 1.0 / samples as f32));
+        }
+        means.push(sum);
+    }
+    for channel in [means[0].red, means[0].green, means[0].blue] {
+        assert!((channel - 1.0).abs() < 0.03, "{:?}", means[0]);
+    }
+    assert!((means[1].red - 1.0).abs() < 0.03, "{:?}", means[1]);
+    assert!(means[1].blue < 0.8 * means[1].red, "{:?}", means[1]);
+}
+
+#[test]
+fn test_shaded_subsurface() {
+    // a graph absorbing blue inside a translucent sphere, which clear
+    // glass would keep all of
+    let graph = ShaderGraph::new()
+        .with_node("yellow", Node::Color([1.0, 1.0, 0.7]))
+        .with_input("albedo", "yellow");
+    let material = Material::Subsurface(Subsurface::new(
+        Srgb::new(1.0, 1.0, 1.0),
+        Srgb::new(0.5, 0.5, 0.5),
+        1.4,
+    ));
+    let mut scene = test_scene(vec![Sphere::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        1.0,
+        Material::Shaded(Shaded::new(material, graph).unwrap()),
+    )]);
+    scene.max_depth = 10;
+    scene.sky = Some(Sky::new(
+        Some((vec![1.0; 4 * 2 * 3], 4, 2, String::new())),
+        0.0,
+        1.0,
+        None,
+    ));
+    let r = Ray::new(Point3D::new(0.2, 0.1, -3.0), Point3D::new(0.0, 0.0, 1.0));
+    let samples = 5000;
+    let mut sum = Srgb::new(0.0, 0.0, 0.0);
+    for _ in 0..samples {
+        let color = PathTracer::without_light_rays().ray_color(&r, &scene, &[]);
+        sum = color::add(sum, color::scale(color, 1.0 / samples as f32));
+    }
+    assert!((sum.red - 1.0).abs() < 0.05, "{:?}", sum);
+    assert!(sum.blue < 0.8 * sum.red, "{:?}", sum);
+}
+
+#[test]
+fn test_subsurface_short_mean_free_path() {
+    // light scatters thousands of times before it gets out of a dense
+    // medium, and still none of it is lost
+    let mean_free_path = Srgb::new(0.005, 0.005, 0.005);
+    let mut scene = test_scene(vec![Sphere::new(
+        Point3D::new(0.0, 0.0, 0.0),
+        0.2,
+        Material::Subsurface(Subsurface::new(
+            Srgb::new(1.0, 1.0, 1.0),
+            mean_free_path,
+            1.4,
+        )),
+    )]);
+    scene.max_depth = 10;
+    scene.sky = Some(Sky::new(
+        Some((vec![1.0; 4 * 2 * 3], 4, 2, String::new())),
+        0.0,
+        1.0,
+        None,
+    ));
+    let r = Ray::new(Point3D::new(0.0, 0.0, -3.0), Point3D::new(0.0, 0.0, 1.0));
+    let samples = 2000;
+    let mut sum = 0.0;
+    for _ in 0..samples {
+        sum += PathTracer::without_light_rays()
+            .ray_color(&r, &scene, &[])
+            .red as f64;
+    }
+    let mean = sum / samples as f64;
+    assert!((mean - 1.0).abs() < 0.03, "{}", mean);
+}
@@ -0,0 +1,494 @@
+use palette::Srgb;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::sync::RwLock;
+
+use crate::color;
+use crate::config::Config;
+use crate::integrator::pick_light;
+use crate::integrator::sample_light;
+use crate::integrator::sky_color;
+use crate::integrator::Integrator;
+use crate::integrator::PathTracer;
+use crate::light_bvh::LightBvh;
+use crate::materials::Material;
+use crate::materials::Scatterable;
+use crate::point3d::Point3D;
+use crate::ray::Ray;
+use crate::raytracer::hit_world;
+use crate::sampler::random_f64;
+use crate::sphere::Sphere;
+
+#[cfg(test)]
+use crate::camera::Camera;
+#[cfg(test)]
+use crate::config::Sky;
+#[cfg(test)]
+use crate::integrator::IntegratorKind;
+#[cfg(test)]
+use crate::materials::Lambertian;
+#[cfg(test)]
+use crate::materials::Light;
+
+// Parameters of the irradiance cache integrator (Ward et al. 1988, with the
+// gradients of Ward and Heckbert 1992).
+//
+// Indirect irradiance on diffuse surfaces is computed from `samples`
+// stratified hemisphere rays at sparse points, and interpolated in between
+// using its gradients. A point reuses the records for which its estimated
+// error, from its distance and the change of normal, stays below `error`;
+// smaller values place records closer together and slow the render down.
+// The spacing of records is kept between `min_spacing` and `max_spacing`.
+// Direct light from the lights is sampled at every hit, and specular
+// surfaces are followed as in the direct lighting integrator. Translucent
+// materials would be seen as clear glass, so `IntegratorKind::build`
+// refuses scenes with subsurface scattering.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct IrradianceCaching {
+    pub error: f64,
+    pub samples: usize,
+    pub min_spacing: f64,
+    pub max_spacing: f64,
+}
+
+impl IrradianceCaching {
+    pub fn new(error: f64, samples: usize, min_spacing: f64, max_spacing: f64) -> Self {
+        IrradianceCaching {
+            error,
+            samples,
+            min_spacing,
+            max_spacing,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IrradianceRecord {
+    point: Point3D,
+    normal: Point3D,
+    irradiance: Srgb,
+    // harmonic mean distance to the surfaces seen from the point
+    distance: f64,
+    // gradients of each color channel with the rotation of the normal and
+    // the translation of the point
+    rotation: [Point3D; 3],
+    translation: [Point3D; 3],
+}
+
+fn channels(c: Srgb) -> [f64; 3] {
+    [c.red as f64, c.green as f64, c.blue as f64]
+}
+
+impl IrradianceRecord {
+    // Weight of the record at a point, which is reused where it exceeds the
+    // inverse of the allowed error.
+    fn weight(&self, point: &Point3D, normal: &Point3D) -> f64 {
+        let offset = *point - self.point;
+        // records in front of the point see a different part of the scene
+        if offset.dot(&(*normal + self.normal)) < -0.1 * self.distance {
+            return 0.0;
+        }
+        let error =
+            offset.length() / self.distance + (1.0 - normal.dot(&self.normal)).max(0.0).sqrt();
+        match error {
+            e if e > 0.0 => 1.0 / e,
+            _ => f64::INFINITY,
+        }
+    }
+
+    // Irradiance extrapolated to a nearby point using the gradients.
+    fn extrapolate(&self, point: &Point3D, normal: &Point3D) -> [f64; 3] {
+        let rotation = self.normal.cross(normal);
+        let offset = *point - self.point;
+        let e = channels(self.irradiance);
+        [0, 1, 2].map(|c| {
+            (e[c] + rotation.dot(&self.rotation[c]) + offset.dot(&self.translation[c])).max(0.0)
+        })
+    }
+}
+
+// Records indexed by a grid whose cells are at least as large as the
+// distance over which a record can be reused.
+#[derive(Debug, Default)]
+struct IrradianceCache {
+    records: Vec<IrradianceRecord>,
+    cells: HashMap<(i64, i64, i64), Vec<usize>>,
+}
+
+// Indirect irradiance cache with a direct lighting pass on top.
+pub struct IrradianceCacheIntegrator {
+    params: IrradianceCaching,
+    cache: RwLock<IrradianceCache>,
+    cell_size: f64,
+    light_bvh: Option<LightBvh>,
+}
+
+impl IrradianceCacheIntegrator {
+    // Creates the integrator and fills the cache from a coarse grid of
+    // pixels first, so that the records don't follow the order in which
+    // the frame is rendered.
+    pub fn new(
+        params: &IrradianceCaching,
+        scene: &Config,
+        lights: &[Sphere],
+    ) -> IrradianceCacheIntegrator {
+        let integrator = IrradianceCacheIntegrator {
+            params: *params,
+            cache: RwLock::new(IrradianceCache::default()),
+            cell_size: (params.error * params.max_spacing).max(1e-6),
+            light_bvh: Some(LightBvh::new(lights)),
+        };
+        let stride = 8;
+        let pixels: Vec<(usize, usize)> = (0..scene.height)
+            .step_by(stride)
+            .flat_map(|y| (0..scene.width).step_by(stride).map(move |x| (x, y)))
+            .collect();
+        pixels.par_iter().for_each(|(x, y)| {
+            let u = (*x as f64 + 0.5) / (scene.width as f64 - 1.0);
+            let v = (scene.height as f64 - (*y as f64 + 0.5)) / (scene.height as f64 - 1.0);
+            integrator.ray_color(&scene.camera.get_ray(u, v), scene, lights);
+        });
+        integrator
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.read().unwrap().records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn cell(&self, point: &Point3D) -> (i64, i64, i64) {
+        (
+            (point.x() / self.cell_size).floor() as i64,
+            (point.y() / self.cell_size).floor() as i64,
+            (point.z() / self.cell_size).floor() as i64,
+        )
+    }
+
+    // Irradiance interpolated from the records which are valid at a point,
+    // if any.
+    fn interpolate(&self, point: &Point3D, normal: &Point3D) -> Option<Srgb> {
+        let cache = self.cache.read().unwrap();
+        let (x, y, z) = self.cell(point);
+        let mut sum = [0.0; 3];
+        let mut total = 0.0;
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let cell = match cache.cells.get(&(x + dx, y + dy, z + dz)) {
+                        Some(cell) => cell,
+                        None => continue,
+                    };
+                    for record in cell.iter().map(|i| &cache.records[*i]) {
+                        let weight = record.weight(point, normal);
+                        if weight <= 1.0 / self.params.error {
+                            continue;
+                        }
+                        // a record right at the point is used as is
+                        let weight = weight.min(1e6);
+                        let e = record.extrapolate(point, normal);
+                        for c in 0..3 {
+                            sum[c] += weight * e[c];
+                        }
+                        total += weight;
+                    }
+                }
+            }
+        }
+        match total > 0.0 {
+            true => Some(Srgb::new(
+                (sum[0] / total) as f32,
+                (sum[1] / total) as f32,
+                (sum[2] / total) as f32,
+            )),
+            false => None,
+        }
+    }
+
+    // Computes a record from stratified, cosine distributed hemisphere rays.
+    fn compute(
+        &self,
+        point: &Point3D,
+        normal: &Point3D,
+        scene: &Config,
+        lights: &[Sphere],
+    ) -> IrradianceRecord {
+        let m = ((self.params.samples as f64 / PI).sqrt().round() as usize).max(1);
+        let n = (self.params.samples / m).max(1);
+        let (tu, tv) = normal.perpendicular_basis();
+        let tracer = PathTracer::without_light_rays();
+
+        // radiance and hit distance of stratum (j, k) at j * n + k
+        let mut radiance = vec![[0.0; 3]; m * n];
+        let mut distance = vec![f64::INFINITY; m * n];
+        for j in 0..m {
+            for k in 0..n {
+                let sin_theta = ((j as f64 + random_f64()) / m as f64).sqrt();
+                let cos_theta = (1.0 - sin_theta * sin_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * (k as f64 + random_f64()) / n as f64;
+                let direction = tu * (sin_theta * phi.cos())
+                    + tv * (sin_theta * phi.sin())
+                    + *normal * cos_theta;
+                let ray = Ray::new(*point, direction);
+                if let Some(hit) = hit_world(&scene.objects, &ray, 0.001, f64::MAX) {
+                    distance[j * n + k] = hit.t * direction.length();
+                }
+                radiance[j * n + k] = channels(tracer.indirect_radiance(&ray, scene, lights));
+            }
+        }
+
+        let mut irradiance = [0.0; 3];
+        let mut inverse_distance = 0.0;
+        for (l, d) in radiance.iter().zip(distance.iter()) {
+            for c in 0..3 {
+                irradiance[c] += PI * l[c] / (m * n) as f64;
+            }
+            inverse_distance += 1.0 / d;
+        }
+        let harmonic_mean = match inverse_distance {
+            x if x > 0.0 => (m * n) as f64 / x,
+            _ => f64::INFINITY,
+        };
+
+        // gradients, from the change of radiance between neighbouring strata
+        let mut rotation = [Point3D::new(0.0, 0.0, 0.0); 3];
+        let mut translation = [Point3D::new(0.0, 0.0, 0.0); 3];
+        let tangent = |phi: f64| tu * phi.cos() + tv * phi.sin();
+        for k in 0..n {
+            let phi = 2.0 * PI * (k as f64 + 0.5) / n as f64;
+            let phi_minus = 2.0 * PI * k as f64 / n as f64;
+            let u_k = tangent(phi);
+            let v_k = tangent(phi + PI / 2.0);
+            let v_minus = tangent(phi_minus + PI / 2.0);
+            let previous = (k + n - 1) % n;
+            for j in 0..m {
+                let sin_minus = (j as f64 / m as f64).sqrt();
+                let sin_plus = ((j + 1) as f64 / m as f64).sqrt();
+                let sin_center = ((j as f64 + 0.5) / m as f64).sqrt();
+                let tan_theta = sin_center / (1.0 - sin_center * sin_center).max(1e-6).sqrt();
+                let here = radiance[j * n + k];
+                for c in 0..3 {
+                    rotation[c] = rotation[c] - v_k * (PI / (m * n) as f64 * tan_theta * here[c]);
+                }
+                if j > 0 {
+                    let below = radiance[(j - 1) * n + k];
+                    let d = distance[j * n + k].min(distance[(j - 1) * n + k]);
+                    let cos_squared = 1.0 - sin_minus * sin_minus;
+                    let factor = 2.0 * PI / n as f64 * sin_minus * cos_squared / d;
+                    for c in 0..3 {
+                        translation[c] = translation[c] + u_k * (factor * (here[c] - below[c]));
+                    }
+                }
+                if n > 1 {
+                    let beside = radiance[j * n + previous];
+                    let d = distance[j * n + k].min(distance[j * n + previous]);
+                    let factor = (sin_plus - sin_minus) / d;
+                    for c in 0..3 {
+                        translation[c] =
+                            translation[c] + v_minus * (factor * (here[c] - beside[c]));
+                    }
+                }
+            }
+        }
+
+        IrradianceRecord {
+            point: *point,
+            normal: *normal,
+            irradiance: Srgb::new(
+                irradiance[0] as f32,
+                irradiance[1] as f32,
+                irradiance[2] as f32,
+            ),
+            distance: harmonic_mean.clamp(self.params.min_spacing, self.params.max_spacing),
+            rotation,
+            translation,
+        }
+    }
+
+    fn indirect_irradiance(
+        &self,
+        point: &Point3D,
+        normal: &Point3D,
+        scene: &Config,
+        lights: &[Sphere],
+    ) -> Srgb {
+        if let Some(irradiance) = self.interpolate(point, normal) {
+            return irradiance;
+        }
+        let record = self.compute(point, normal, scene, lights);
+        let cell = self.cell(point);
+        let mut cache = self.cache.write().unwrap();
+        let index = cache.records.len();
+        cache.records.push(record);
+        cache.cells.entry(cell).or_default().push(index);
+        record.irradiance
+    }
+
+    // Irradiance from one light picked by importance.
+    fn direct_irradiance(
+        &self,
+        point: &Point3D,
+        normal: &Point3D,
+        scene: &Config,
+        lights: &[Sphere],
+    ) -> Srgb {
+        let (light, probability) = match pick_light(&self.light_bvh, lights, point, Some(normal)) {
+            Some(picked) => picked,
+            None => return color::black(),
+        };
+        match sample_light(scene, point, light) {
+            Some((direction, emitted, pdf, _)) => {
+                let cosine = direction.unit_vector().dot(normal);
+                match cosine > 0.0 {
+                    true => color::scale(emitted, (cosine / (probability * pdf)) as f32),
+                    false => color::black(),
+                }
+            }
+            None => color::black(),
+        }
+    }
+
+    fn trace(&self, ray: &Ray, scene: &Config, lights: &[Sphere], depth: usize) -> Srgb {
+        if depth == 0 {
+            return color::black();
+        }
+        let hit_record = match hit_world(&scene.objects, ray, 0.001, f64::MAX) {
+            Some(hit_record) => hit_record,
+            None => return sky_color(ray, scene),
+        };
+        match hit_record.material {
+            Material::Lambertian(_) | Material::Texture(_) => {
+                // both sides of a surface are lit
+                let normal = match hit_record.normal.dot(&ray.direction) > 0.0 {
+                    true => -hit_record.normal,
+                    false => hit_record.normal,
+                };
+                let point = &hit_record.point;
+                let irradiance = color::add(
+                    self.direct_irradiance(point, &normal, scene, lights),
+                    self.indirect_irradiance(point, &normal, scene, lights),
+                );
+                let albedo = hit_record.material.albedo(&hit_record);
+                color::scale(color::mul(albedo, irradiance), (1.0 / PI) as f32)
+            }
+            _ => match hit_record.material.scatter(ray, &hit_record) {
+                Some((Some(scattered), attenuation)) => color::mul(
+                    attenuation,
+                    self.trace(&scattered, scene, lights, depth - 1),
+                ),
+                Some((None, emitted)) => emitted,
+                None => color::black(),
+            },
+        }
+    }
+}
+
+impl Integrator for IrradianceCacheIntegrator {
+    fn ray_color(&self, ray: &Ray, scene: &Config, lights: &[Sphere]) -> Srgb {
+        self.trace(ray, scene, lights, scene.max_depth)
+    }
+}
+
+#[cfg(test)]
+fn test_scene() -> (Config, Vec<Sphere>) {
+    let light = Sphere::new(
+        Point3D::new(1.0, 2.5, 1.0),
+        0.4,
+        Material::Light(Light::new()),
+    );
+    let scene = Config {
+        width: 16,
+        height: 12,
+        samples_per_pixel: 1,
+        max_depth: 4,
+        spectral: false,
+        sky: Some(Sky::new_default_sky()),
+        fog: None,
+        volumes: Vec::new(),
+        camera: Camera::new(
+            Point3D::new(0.0, 1.0, 3.0),
+            Point3D::new(0.0, 0.0, 0.0),
+            Point3D::new(0.0, 1.0, 0.0),
+            60.0,
+            16.0 / 12.0,
+        ),
+        integrator: IntegratorKind::IrradianceCaching(IrradianceCaching::new(0.3, 256, 0.05, 2.0)),
+        objects: vec![
+            Sphere::new(
+                Point3D::new(0.0, -1000.0, 0.0),
+                1000.0,
+                Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+            ),
+            Sphere::new(
+                Point3D::new(0.0, 0.5, 0.0),
+                0.5,
+                Material::Lambertian(Lambertian::new(Srgb::new(0.8, 0.3, 0.3))),
+            ),
+            light.clone(),
+        ],
+    };
+    (scene, vec![light])
+}
+
+#[test]
+fn test_irradiance_record_gradients() {
+    // on a floor lit by a sky brighter on one side, the translational
+    // gradient points towards the bright side
+    let (scene, lights) = test_scene();
+    let params = IrradianceCaching::new(0.3, 1024, 0.05, 2.0);
+    let integrator = IrradianceCacheIntegrator {
+        params,
+        cache: RwLock::new(IrradianceCache::default()),
+        cell_size: 0.6,
+        light_bvh: Some(LightBvh::new(&lights)),
+    };
+    // next to the sphere, the floor sees less of the sky
+    let normal = Point3D::new(0.0, 1.0, 0.0);
+    let record = integrator.compute(&Point3D::new(0.7, 0.0, 0.0), &normal, &scene, &lights);
+    assert!(record.translation[2].x() > 0.0);
+
+    // the extrapolation follows the gradient
+    let further = record.extrapolate(&Point3D::new(0.8, 0.0, 0.0), &normal);
+    assert!(further[2] > channels(record.irradiance)[2]);
+}
+
+#[test]
+fn test_irradiance_cache_matches_path_tracer() {
+    let (scene, lights) = test_scene();
+    let params = IrradianceCaching::new(0.3, 256, 0.05, 2.0);
+    let integrator = IrradianceCacheIntegrator::new(&params, &scene, &lights);
+
+    let pixels = scene.width * scene.height;
+    let mut cached = 0.0;
+    let mut reference = 0.0;
+    let tracer = PathTracer::without_light_rays();
+    for y in 0..scene.height {
+        for x in 0..scene.width {
+            let u = (x as f64 + 0.5) / (scene.width as f64 - 1.0);
+            let v = (scene.height as f64 - (y as f64 + 0.5)) / (scene.height as f64 - 1.0);
+            let r = scene.camera.get_ray(u, v);
+            for _ in 0..16 {
+                cached += color::luminance(integrator.ray_color(&r, &scene, &lights));
+            }
+            for _ in 0..256 {
+                reference += color::luminance(tracer.ray_color(&r, &scene, &lights)) / 16.0;
+            }
+        }
+    }
+    assert!(
+        (cached - reference).abs() < 0.05 * reference,
+        "{} != {}",
+        cached,
+        reference
+    );
+    // far fewer records than pixels
+    assert!(
+        integrator.len() < pixels / 2,
+        "{} records",
+        integrator.len()
+    );
+}
@@ -10,7 +10,9 @@ use std::{env, fs, io};
 use imgui::Ui;
 use serde_json::json;
 
+use crate::aov::Aov;
 use crate::config::Config;
+use crate::integrator::IntegratorKind;
 
 use crate::point3d::Point3D;
 use crate::raytracer::ImguiRender;
@@ -47,6 +49,8 @@ pub struct RayLayer {
     imgui_render: ImguiRender,
     last_rendered_time: f32,
     scene: Config,
+    // integrator from the scene file, restored when leaving a debug view
+    integrator: IntegratorKind,
 }
 
 impl Layer for RayLayer {
@@ -100,6 +104,7 @@ impl RayLayer {
             width: 0.0,
             height: 0.0,
             imgui_render,
+            integrator: scene.integrator,
             scene,
             last_rendered_time: dt,
         }
@@ -135,6 +140,26 @@ impl RayLayer {
                     // control samples
                     ui.slider("samples", 1, 128, &mut self.scene.samples_per_pixel);
 
+                    // render mode: the scene's integrator or a debug view
+                    let mut mode = match self.scene.integrator {
+                        IntegratorKind::Aov(aov) => Aov::ALL
+                            .iter()
+                            .position(|a| a.name() == aov.name())
+                            .map_or(0, |i| i + 1),
+                        _ => 0,
+                    };
+                    let mut modes = vec!["scene"];
+                    modes.extend(Aov::ALL.iter().map(Aov::name));
+                    if ui.combo_simple_string("render mode", &mut mode, &modes) {
+                        self.scene.integrator = match mode {
+                            0 => self.integrator,
+                            i => IntegratorKind::Aov(Aov::ALL[i - 1]),
+                        };
+                    }
+                    if let IntegratorKind::Aov(Aov::Depth { far }) = &mut self.scene.integrator {
+                        ui.slider("far", 0.1, 100.0, far);
+                    }
+
                     // control camera origin
                     let origin = &mut self.scene.camera.origin;
                     let mut camera_origin_x = origin.x();
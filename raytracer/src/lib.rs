@@ -1,11 +1,29 @@
+pub mod aov;
 pub mod app;
+pub mod bdpt;
+pub mod blackbody;
 pub mod camera;
+pub mod color;
 pub mod config;
+pub mod daylight;
+pub mod distribution;
+pub mod guiding;
+pub mod hdr;
 pub mod imgui_image;
+pub mod integrator;
+pub mod irradiance_cache;
 pub mod layer;
+pub mod light_bvh;
 pub mod materials;
+pub mod medium;
+pub mod mlt;
+pub mod photon_map;
 pub mod point3d;
 pub mod ray;
 pub mod raytracer;
+pub mod sampler;
+pub mod shader;
+pub mod spectrum;
 pub mod sphere;
 pub mod texture;
+pub mod volume;
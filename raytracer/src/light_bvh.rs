@@ -0,0 +1,240 @@
+use std::cmp::Ordering;
+
+use crate::color;
+use crate::point3d::Point3D;
+use crate::sphere::Sphere;
+
+#[cfg(test)]
+use crate::materials::Light;
+#[cfg(test)]
+use crate::materials::Material;
+
+// Bounding volume hierarchy over the lights, used to pick one light per
+// shading point with a probability proportional to an estimate of how much
+// it contributes there (Conty Estevez and Kulla, 2018). Picking a light
+// costs one walk down the tree, whatever the number of lights.
+#[derive(Debug, Clone)]
+pub struct LightBvh {
+    nodes: Vec<LightNode>,
+}
+
+#[derive(Debug, Clone)]
+struct LightNode {
+    // bounding sphere of the lights below the node
+    center: Point3D,
+    radius: f64,
+    power: f64,
+    // the light of a leaf, or the indices of the two children
+    content: LightNodeContent,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum LightNodeContent {
+    Light(usize),
+    Children(usize, usize),
+}
+
+fn axis_value(p: &Point3D, axis: usize) -> f64 {
+    match axis {
+        0 => p.x(),
+        1 => p.y(),
+        _ => p.z(),
+    }
+}
+
+impl LightBvh {
+    pub fn new(lights: &[Sphere]) -> LightBvh {
+        let mut bvh = LightBvh { nodes: Vec::new() };
+        let mut indices: Vec<usize> = (0..lights.len()).collect();
+        if !indices.is_empty() {
+            bvh.build(lights, &mut indices);
+        }
+        bvh
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len().div_ceil(2)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // Adds the node for the given lights and its subtree, returning its
+    // index.
+    fn build(&mut self, lights: &[Sphere], indices: &mut [usize]) -> usize {
+        let mut min = [f64::MAX; 3];
+        let mut max = [f64::MIN; 3];
+        for i in indices.iter() {
+            let light = &lights[*i];
+            for axis in 0..3 {
+                let v = axis_value(&light.center, axis);
+                min[axis] = min[axis].min(v - light.radius);
+                max[axis] = max[axis].max(v + light.radius);
+            }
+        }
+        let min = Point3D::new(min[0], min[1], min[2]);
+        let max = Point3D::new(max[0], max[1], max[2]);
+        // the power of a light goes with its area and its radiance
+        let power = indices
+            .iter()
+            .map(|i| {
+                let light = &lights[*i];
+                let luminance = color::luminance(light.material.emitted()) as f64;
+                light.radius * light.radius * luminance
+            })
+            .sum();
+        let index = self.nodes.len();
+        self.nodes.push(LightNode {
+            center: (min + max) / 2.0,
+            radius: (max - min).length() / 2.0,
+            power,
+            content: LightNodeContent::Light(indices[0]),
+        });
+        if indices.len() == 1 {
+            return index;
+        }
+
+        // split at the median center along the axis with the largest extent
+        let extent = max - min;
+        let axis = (0..3)
+            .max_by(|a, b| {
+                axis_value(&extent, *a)
+                    .partial_cmp(&axis_value(&extent, *b))
+                    .unwrap_or(Ordering::Equal)
+            })
+            .unwrap_or(0);
+        let median = indices.len() / 2;
+        indices.select_nth_unstable_by(median, |a, b| {
+            axis_value(&lights[*a].center, axis)
+                .partial_cmp(&axis_value(&lights[*b].center, axis))
+                .unwrap_or(Ordering::Equal)
+        });
+        let (left, right) = indices.split_at_mut(median);
+        let left = self.build(lights, left);
+        let right = self.build(lights, right);
+        self.nodes[index].content = LightNodeContent::Children(left, right);
+        index
+    }
+
+    // Estimated contribution of the lights below a node to a point, from
+    // their power, their distance and, for points on a surface, the largest
+    // cosine of the normal with a direction towards them.
+    fn importance(&self, node: &LightNode, point: &Point3D, normal: Option<&Point3D>) -> f64 {
+        let to_center = node.center - *point;
+        let distance_squared = to_center.length_squared();
+        // don't let points within the bounds favor a node without limit
+        let falloff = distance_squared.max(node.radius * node.radius).max(1e-6);
+        let cosine = match normal {
+            Some(normal) if distance_squared > node.radius * node.radius => {
+                let distance = distance_squared.sqrt();
+                let cos_theta = normal.dot(&to_center) / distance;
+                let sin_bound = node.radius / distance;
+                let cos_bound = (1.0 - sin_bound * sin_bound).sqrt();
+                if cos_theta >= cos_bound {
+                    1.0
+                } else {
+                    // cosine of the angle to the nearest direction in the
+                    // cone bounding the node
+                    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                    (cos_theta * cos_bound + sin_theta * sin_bound).max(0.0)
+                }
+            }
+            _ => 1.0,
+        };
+        node.power * cosine / falloff
+    }
+
+    // Picks a light for a point, and a surface normal there if any, with a
+    // single uniform sample. Returns the index of the light and the
+    // probability of picking it, or None if no light can reach the point.
+    pub fn sample(
+        &self,
+        point: &Point3D,
+        normal: Option<&Point3D>,
+        u: f64,
+    ) -> Option<(usize, f64)> {
+        let mut node = self.nodes.first()?;
+        let mut u = u;
+        let mut probability = 1.0;
+        loop {
+            match node.content {
+                LightNodeContent::Light(light) => return Some((light, probability)),
+                LightNodeContent::Children(left, right) => {
+                    let (left, right) = (&self.nodes[left], &self.nodes[right]);
+                    let left_importance = self.importance(left, point, normal);
+                    let right_importance = self.importance(right, point, normal);
+                    let total = left_importance + right_importance;
+                    if total <= 0.0 {
+                        return None;
+                    }
+                    let p_left = left_importance / total;
+                    if u < p_left {
+                        u /= p_left;
+                        probability *= p_left;
+                        node = left;
+                    } else {
+                        u = ((u - p_left) / (1.0 - p_left)).min(1.0 - f64::EPSILON);
+                        probability *= 1.0 - p_left;
+                        node = right;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn light_row(count: usize) -> Vec<Sphere> {
+    (0..count)
+        .map(|i| {
+            Sphere::new(
+                Point3D::new(i as f64, 1.0, 0.0),
+                0.1,
+                Material::Light(Light::new()),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_light_bvh() {
+    assert!(LightBvh::new(&[]).is_empty());
+    assert_eq!(
+        LightBvh::new(&[]).sample(&Point3D::new(0.0, 0.0, 0.0), None, 0.5),
+        None
+    );
+
+    let lights = light_row(100);
+    let bvh = LightBvh::new(&lights);
+    assert_eq!(bvh.len(), 100);
+
+    // a point right below a light picks that one most often, and the
+    // probabilities returned match how often each light is picked
+    let point = Point3D::new(42.0, 0.0, 0.0);
+    let normal = Point3D::new(0.0, 1.0, 0.0);
+    let samples = 20000;
+    let mut counts = vec![0; lights.len()];
+    let mut probabilities = vec![0.0; lights.len()];
+    for i in 0..samples {
+        let u = (i as f64 + 0.5) / samples as f64;
+        let (light, probability) = bvh.sample(&point, Some(&normal), u).unwrap();
+        counts[light] += 1;
+        probabilities[light] = probability;
+    }
+    assert_eq!(counts.iter().max(), Some(&counts[42]));
+    assert!(counts[42] > 20 * counts[0]);
+    for (count, probability) in counts.iter().zip(probabilities) {
+        if *count > 100 {
+            let frequency = *count as f64 / samples as f64;
+            assert!((frequency - probability).abs() < 0.01);
+        }
+    }
+
+    // lights behind the surface are never picked
+    let normal = Point3D::new(0.0, -1.0, 0.0);
+    assert_eq!(
+        bvh.sample(&Point3D::new(42.0, 0.0, 0.0), Some(&normal), 0.3),
+        None
+    );
+}
@@ -1,17 +1,86 @@
 use jpeg_decoder::Decoder;
 use palette::Srgb;
-use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
+use std::f64::consts::PI;
 use std::fs::File;
+use std::io;
 use std::io::BufReader;
+use std::ops::{Add, Div, Mul, Sub};
 
+use crate::blackbody::blackbody_color;
+use crate::color;
+use crate::medium::HenyeyGreenstein;
 use crate::point3d::Point3D;
 use crate::ray::HitRecord;
 use crate::ray::Ray;
+use crate::sampler::random_f64;
+use crate::shader::{Kind, Program, ShaderError, ShaderGraph};
+use crate::spectrum::at_wavelength;
+use crate::spectrum::reflectance_to_rgb;
+use crate::spectrum::rgb_to_spectrum;
 
+#[cfg(test)]
+use crate::shader::Node;
+
+// Scattering of light at a surface. Directions are unit vectors pointing
+// away from the hit point, `wo` towards where the light leaves (the viewer)
+// and `wi` towards where it arrives from.
 pub trait Scatterable {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)>;
+    // BSDF for light arriving from `wi` and leaving towards `wo`, without
+    // the cosine; black for delta lobes.
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb;
+
+    // Samples `wi` for light leaving towards the origin of the ray, or
+    // returns None if the ray is absorbed.
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample>;
+
+    // Density of `sample` returning `wi`, per unit of solid angle; zero for
+    // delta lobes.
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> f64;
+
+    // Whether all the lobes are delta lobes (mirrors, smooth glass), which
+    // `eval` and `pdf` don't see, so integrators can only follow samples.
+    fn is_delta(&self) -> bool;
+
+    // The scattered ray and its attenuation.
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
+        let sample = self.sample(ray, hit_record)?;
+        let scattered = Ray::new(hit_record.point, sample.wi).with_wavelength(ray.wavelength);
+        Some((Some(scattered), sample.weight))
+    }
+}
+
+// Direction sampled by `Scatterable::sample`.
+#[derive(Debug, Clone, Copy)]
+pub struct BsdfSample {
+    pub wi: Point3D,
+    // BSDF times the cosine of `wi`, divided by the density
+    pub weight: Srgb,
+    pub pdf: f64,
+    // Sampled from a delta lobe, or from one which `eval` and `pdf` leave
+    // out; the density is then zero.
+    pub delta: bool,
+}
+
+impl BsdfSample {
+    fn new(wi: Point3D, weight: Srgb, pdf: f64) -> BsdfSample {
+        BsdfSample {
+            wi,
+            weight,
+            pdf,
+            delta: false,
+        }
+    }
+
+    fn delta(wi: Point3D, weight: Srgb) -> BsdfSample {
+        BsdfSample {
+            wi,
+            weight,
+            pdf: 0.0,
+            delta: true,
+        }
+    }
 }
 
 // https://docs.rs/serde_with/1.9.4/serde_with/macro.serde_conv.html
@@ -35,169 +104,2389 @@ serde_with::serde_conv!(
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Material {
     Lambertian(Lambertian),
+    OrenNayar(OrenNayar),
+    Sheen(Sheen),
     Metal(Metal),
+    Conductor(Conductor),
     Glass(Glass),
+    Principled(Principled),
+    Coated(Coated),
+    Subsurface(Subsurface),
+    ThinFilm(ThinFilm),
+    Mix(Mix),
+    Shaded(Shaded),
     Texture(Texture),
     Light(Light),
 }
 
+impl Material {
+    fn scatterable(&self) -> &dyn Scatterable {
+        match self {
+            Material::Lambertian(l) => l,
+            Material::OrenNayar(o) => o,
+            Material::Sheen(s) => s,
+            Material::Metal(m) => m,
+            Material::Conductor(c) => c,
+            Material::Glass(g) => g,
+            Material::Principled(p) => p,
+            Material::Coated(c) => c,
+            Material::Subsurface(s) => s,
+            Material::ThinFilm(f) => f,
+            Material::Mix(m) => m,
+            Material::Shaded(s) => s,
+            Material::Texture(t) => t,
+            Material::Light(l) => l,
+        }
+    }
+}
+
 impl Scatterable for Material {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        self.scatterable().eval(hit_record, wi, wo)
+    }
+
+    // In spectral mode the weight is the value of its spectrum at the
+    // wavelength of the ray.
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        let sample = self.scatterable().sample(ray, hit_record)?;
+        Some(BsdfSample {
+            weight: at_wavelength(sample.weight, ray.wavelength),
+            ..sample
+        })
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> f64 {
+        self.scatterable().pdf(hit_record, wi, wo)
+    }
+
+    fn is_delta(&self) -> bool {
+        self.scatterable().is_delta()
+    }
+
+    // Emitters don't scatter the ray, they return their radiance.
     fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
+        if let Some(emitted) = self.emission(hit_record) {
+            return Some((None, at_wavelength(emitted, ray.wavelength)));
+        }
+        let sample = self.sample(ray, hit_record)?;
+        let scattered = Ray::new(hit_record.point, sample.wi).with_wavelength(ray.wavelength);
+        Some((Some(scattered), sample.weight))
+    }
+}
+
+impl Material {
+    // Surface color at the hit point, without any scattering.
+    pub fn albedo(&self, hit_record: &HitRecord) -> Srgb {
+        match self {
+            Material::Lambertian(l) => l.albedo,
+            Material::OrenNayar(o) => o.albedo,
+            Material::Sheen(s) => s.albedo,
+            Material::Metal(m) => m.albedo,
+            Material::Conductor(c) => c.reflectance(1.0),
+            Material::Principled(p) => p.base_color,
+            Material::Coated(c) => color::mul(c.base.albedo(hit_record), c.tint),
+            Material::Subsurface(s) => s.albedo,
+            Material::ThinFilm(f) => f.base.albedo(hit_record),
+            Material::Mix(m) => {
+                let weight = m.weight_at(hit_record) as f32;
+                color::add(
+                    color::scale(m.base.albedo(hit_record), 1.0 - weight),
+                    color::scale(m.top.albedo(hit_record), weight),
+                )
+            }
+            Material::Shaded(s) => match s.inputs_at(hit_record.u, hit_record.v) {
+                Some(inputs) => inputs.albedo(),
+                None => s.material.albedo(hit_record),
+            },
+            Material::Texture(t) => t.get_albedo(hit_record.u, hit_record.v),
+            Material::Glass(_) => Srgb::new(1.0, 1.0, 1.0),
+            Material::Light(l) => l.emitted(),
+        }
+    }
+
+    // Radiance emitted by the surface, black for anything but lights. Shaded
+    // lights give their average over the surface.
+    pub fn emitted(&self) -> Srgb {
+        match self {
+            Material::Light(l) => l.emitted(),
+            Material::Shaded(s) => s.emitted,
+            _ => color::black(),
+        }
+    }
+
+    // Radiance emitted at the hit point, or None if the surface isn't a
+    // light.
+    pub fn emission(&self, hit_record: &HitRecord) -> Option<Srgb> {
+        match self {
+            Material::Light(l) => Some(l.emitted()),
+            Material::Shaded(s) if s.material.is_light() => s
+                .inputs_at(hit_record.u, hit_record.v)
+                .and_then(|inputs| inputs.emitted()),
+            _ => None,
+        }
+    }
+
+    // The name of the material in scene files.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Material::Lambertian(_) => "Lambertian",
+            Material::OrenNayar(_) => "OrenNayar",
+            Material::Sheen(_) => "Sheen",
+            Material::Metal(_) => "Metal",
+            Material::Conductor(_) => "Conductor",
+            Material::Glass(_) => "Glass",
+            Material::Principled(_) => "Principled",
+            Material::Coated(_) => "Coated",
+            Material::Subsurface(_) => "Subsurface",
+            Material::ThinFilm(_) => "ThinFilm",
+            Material::Mix(_) => "Mix",
+            Material::Shaded(_) => "Shaded",
+            Material::Texture(_) => "Texture",
+            Material::Light(_) => "Light",
+        }
+    }
+
+    pub fn is_light(&self) -> bool {
+        match self {
+            Material::Light(_) => true,
+            Material::Shaded(s) => s.material.is_light(),
+            _ => false,
+        }
+    }
+
+    // Whether light scatters inside the material, here or in any of the
+    // materials it's made of.
+    pub fn has_subsurface(&self) -> bool {
+        match self {
+            Material::Subsurface(_) => true,
+            Material::Coated(c) => c.base.has_subsurface(),
+            Material::ThinFilm(f) => f.base.has_subsurface(),
+            Material::Mix(m) => m.base.has_subsurface() || m.top.has_subsurface(),
+            Material::Shaded(s) => s.material.has_subsurface(),
+            _ => false,
+        }
+    }
+
+    // The translucent material at the hit point, which the path tracer
+    // walks through.
+    pub fn subsurface(&self, hit_record: &HitRecord) -> Option<Subsurface> {
+        match self {
+            Material::Subsurface(s) => Some(*s),
+            Material::Shaded(s) => match s.inputs_at(hit_record.u, hit_record.v) {
+                Some(Inputs::Subsurface(s)) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+// The parameters of a material driven by a shader graph, copied with the
+// inputs found by the graph at a point. Only materials without nested
+// materials have inputs, so the copy is small: `Coated`, `ThinFilm`, `Mix`
+// and `Texture` have none, and are shaded through the materials inside
+// them instead.
+#[derive(Debug, Clone, Copy)]
+enum Inputs {
+    Lambertian(Lambertian),
+    OrenNayar(OrenNayar),
+    Sheen(Sheen),
+    Metal(Metal),
+    Conductor(Conductor),
+    Glass(Glass),
+    Principled(Principled),
+    Subsurface(Subsurface),
+    Light(Light),
+}
+
+impl Inputs {
+    fn of(material: &Material) -> Option<Inputs> {
+        match material {
+            Material::Lambertian(l) => Some(Inputs::Lambertian(*l)),
+            Material::OrenNayar(o) => Some(Inputs::OrenNayar(*o)),
+            Material::Sheen(s) => Some(Inputs::Sheen(*s)),
+            Material::Metal(m) => Some(Inputs::Metal(*m)),
+            Material::Conductor(c) => Some(Inputs::Conductor(*c)),
+            Material::Glass(g) => Some(Inputs::Glass(*g)),
+            Material::Principled(p) => Some(Inputs::Principled(*p)),
+            Material::Subsurface(s) => Some(Inputs::Subsurface(*s)),
+            Material::Light(l) => Some(Inputs::Light(*l)),
+            _ => None,
+        }
+    }
+
+    fn scatterable(&self) -> &dyn Scatterable {
+        match self {
+            Inputs::Lambertian(l) => l,
+            Inputs::OrenNayar(o) => o,
+            Inputs::Sheen(s) => s,
+            Inputs::Metal(m) => m,
+            Inputs::Conductor(c) => c,
+            Inputs::Glass(g) => g,
+            Inputs::Principled(p) => p,
+            Inputs::Subsurface(s) => s,
+            Inputs::Light(l) => l,
+        }
+    }
+
+    // Same as `Material::albedo`.
+    fn albedo(&self) -> Srgb {
+        match self {
+            Inputs::Lambertian(l) => l.albedo,
+            Inputs::OrenNayar(o) => o.albedo,
+            Inputs::Sheen(s) => s.albedo,
+            Inputs::Metal(m) => m.albedo,
+            Inputs::Conductor(c) => c.reflectance(1.0),
+            Inputs::Glass(_) => white(),
+            Inputs::Principled(p) => p.base_color,
+            Inputs::Subsurface(s) => s.albedo,
+            Inputs::Light(l) => l.emitted(),
+        }
+    }
+
+    fn emitted(&self) -> Option<Srgb> {
+        match self {
+            Inputs::Light(l) => Some(l.emitted()),
+            _ => None,
+        }
+    }
+
+    // Inputs which a shader graph can drive, by name.
+    fn shader_input(&mut self, name: &str) -> Option<ShaderInput<'_>> {
+        use ShaderInput::{Color, Float, Scale};
+        match (self, name) {
+            (Inputs::Lambertian(l), "albedo") => Some(Color(&mut l.albedo)),
+            (Inputs::OrenNayar(o), "albedo") => Some(Color(&mut o.albedo)),
+            (Inputs::OrenNayar(o), "sigma") => Some(Float(&mut o.sigma)),
+            (Inputs::Sheen(s), "albedo") => Some(Color(&mut s.albedo)),
+            (Inputs::Sheen(s), "sheen") => Some(Color(&mut s.sheen)),
+            (Inputs::Sheen(s), "roughness") => Some(Float(&mut s.roughness)),
+            (Inputs::Metal(m), "albedo") => Some(Color(&mut m.albedo)),
+            (Inputs::Metal(m), "fuzz") => Some(Float(&mut m.fuzz)),
+            (Inputs::Conductor(c), "eta") => Some(Color(&mut c.eta)),
+            (Inputs::Conductor(c), "k") => Some(Color(&mut c.k)),
+            (Inputs::Conductor(c), "roughness") => Some(Float(&mut c.roughness)),
+            (Inputs::Conductor(c), "anisotropy") => Some(Float(&mut c.anisotropy)),
+            (Inputs::Glass(g), "roughness") => Some(Float(&mut g.roughness)),
+            (Inputs::Principled(p), "base_color") => Some(Color(&mut p.base_color)),
+            (Inputs::Principled(p), "metallic") => Some(Float(&mut p.metallic)),
+            (Inputs::Principled(p), "roughness") => Some(Float(&mut p.roughness)),
+            (Inputs::Principled(p), "specular") => Some(Float(&mut p.specular)),
+            (Inputs::Principled(p), "sheen") => Some(Float(&mut p.sheen)),
+            (Inputs::Principled(p), "clearcoat") => Some(Float(&mut p.clearcoat)),
+            (Inputs::Principled(p), "clearcoat_roughness") => {
+                Some(Float(&mut p.clearcoat_roughness))
+            }
+            (Inputs::Principled(p), "transmission") => Some(Float(&mut p.transmission)),
+            (Inputs::Subsurface(s), "albedo") => Some(Color(&mut s.albedo)),
+            (Inputs::Light(l), "color") => Some(Color(&mut l.color)),
+            (Inputs::Light(l), "intensity") => Some(Scale(&mut l.intensity)),
+            _ => None,
+        }
+    }
+}
+
+// A parameter of a material, as seen by a shader graph.
+enum ShaderInput<'a> {
+    Float(&'a mut f64),
+    Scale(&'a mut f32),
+    Color(&'a mut Srgb),
+}
+
+impl ShaderInput<'_> {
+    fn kind(&self) -> Kind {
+        match self {
+            ShaderInput::Float(_) | ShaderInput::Scale(_) => Kind::Float,
+            ShaderInput::Color(_) => Kind::Color,
+        }
+    }
+
+    fn set(self, value: [f64; 3]) {
         match self {
-            Material::Lambertian(l) => l.scatter(ray, hit_record),
-            Material::Metal(m) => m.scatter(ray, hit_record),
-            Material::Glass(g) => g.scatter(ray, hit_record),
-            Material::Texture(t) => t.scatter(ray, hit_record),
-            Material::Light(l) => l.scatter(ray, hit_record),
+            ShaderInput::Float(x) => *x = value[0],
+            ShaderInput::Scale(x) => *x = value[0] as f32,
+            ShaderInput::Color(c) => {
+                *c = Srgb::new(value[0] as f32, value[1] as f32, value[2] as f32)
+            }
         }
     }
 }
 
+fn white() -> Srgb {
+    Srgb::new(1.0, 1.0, 1.0)
+}
+
+fn is_white(color: &Srgb) -> bool {
+    *color == white()
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+fn is_default_intensity(intensity: &f32) -> bool {
+    *intensity == default_intensity()
+}
+
+#[serde_with::serde_as]
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
-pub struct Light {}
+pub struct Light {
+    #[serde_as(as = "SrgbAsArray")]
+    #[serde(default = "white", skip_serializing_if = "is_white")]
+    pub color: Srgb,
+    // Color temperature in kelvin, used instead of `color` if given. The
+    // light then has the color of a black body, with a luminance of one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    // Multiplier applied to the radiance of the light.
+    #[serde(
+        default = "default_intensity",
+        skip_serializing_if = "is_default_intensity"
+    )]
+    pub intensity: f32,
+}
 
 impl Light {
     pub fn new() -> Light {
-        Light {}
+        Light {
+            color: white(),
+            temperature: None,
+            intensity: default_intensity(),
+        }
+    }
+
+    pub fn with_color(self, color: Srgb) -> Light {
+        Light { color, ..self }
+    }
+
+    pub fn with_temperature(self, kelvin: f64) -> Light {
+        Light {
+            temperature: Some(kelvin),
+            ..self
+        }
+    }
+
+    pub fn with_intensity(self, intensity: f32) -> Light {
+        Light { intensity, ..self }
+    }
+
+    // Radiance leaving the light.
+    pub fn emitted(&self) -> Srgb {
+        let color = match self.temperature {
+            Some(kelvin) => blackbody_color(kelvin),
+            None => self.color,
+        };
+        color::scale(color, self.intensity)
+    }
+}
+
+// Lights absorb whatever arrives at them.
+impl Scatterable for Light {
+    fn eval(&self, _hit_record: &HitRecord, _wi: &Point3D, _wo: &Point3D) -> Srgb {
+        color::black()
+    }
+
+    fn sample(&self, _ray: &Ray, _hit_record: &HitRecord) -> Option<BsdfSample> {
+        None
+    }
+
+    fn pdf(&self, _hit_record: &HitRecord, _wi: &Point3D, _wo: &Point3D) -> f64 {
+        0.0
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Lambertian {
+    #[serde_as(as = "SrgbAsArray")]
+    pub albedo: Srgb,
+}
+
+impl Lambertian {
+    pub fn new(albedo: Srgb) -> Lambertian {
+        Lambertian { albedo }
+    }
+}
+
+impl Scatterable for Lambertian {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        diffuse(self.albedo, &hit_record.normal, wi, wo)
+    }
+
+    // Scattered rays are cosine distributed around the normal.
+    fn sample(&self, _ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        let wi = cosine_direction(&hit_record.normal);
+        let pdf = cosine_pdf(&hit_record.normal, &wi);
+        Some(BsdfSample::new(wi, self.albedo, pdf))
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, _wo: &Point3D) -> f64 {
+        cosine_pdf(&hit_record.normal, wi)
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+
+// Lambertian BRDF, for directions on the side of the normal.
+fn diffuse(albedo: Srgb, normal: &Point3D, wi: &Point3D, wo: &Point3D) -> Srgb {
+    match normal.dot(wi) > 0.0 && normal.dot(wo) > 0.0 {
+        true => color::scale(albedo, (1.0 / PI) as f32),
+        false => color::black(),
+    }
+}
+
+fn cosine_pdf(normal: &Point3D, wi: &Point3D) -> f64 {
+    normal.dot(wi).max(0.0) / PI
+}
+
+// Cosine distributed unit vector around a normal.
+fn cosine_direction(normal: &Point3D) -> Point3D {
+    let direction = *normal + Point3D::random_unit_vector();
+    match direction.near_zero() {
+        true => *normal,
+        false => direction.unit_vector(),
+    }
+}
+
+// Sine of the angle between a unit vector and the normal.
+fn sin_to_normal(normal: &Point3D, w: &Point3D) -> f64 {
+    let cos = normal.dot(w);
+    (1.0 - cos * cos).max(0.0).sqrt()
+}
+
+// Rough diffuse surface made of V-shaped Lambertian facets (Oren and Nayar,
+// 1994), for clay, concrete or the moon, which look flatter than
+// `Lambertian` as light is retro-reflected. `sigma` is the standard
+// deviation of the facet angles in degrees; 0 is `Lambertian`.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct OrenNayar {
+    #[serde_as(as = "SrgbAsArray")]
+    pub albedo: Srgb,
+    pub sigma: f64,
+}
+
+impl OrenNayar {
+    pub fn new(albedo: Srgb, sigma: f64) -> OrenNayar {
+        OrenNayar { albedo, sigma }
+    }
+}
+
+impl Scatterable for OrenNayar {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        let normal = &hit_record.normal;
+        let (cos_o, cos_i) = (normal.dot(wo), normal.dot(wi));
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return color::black();
+        }
+        let sigma2 = self.sigma.to_radians().powi(2);
+        let a = 1.0 - sigma2 / (2.0 * (sigma2 + 0.33));
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+        let (sin_o, sin_i) = (sin_to_normal(normal, wo), sin_to_normal(normal, wi));
+        // cosine of the azimuth between the directions
+        let cos_phi = match sin_o > 1e-4 && sin_i > 1e-4 {
+            true => {
+                let po = *wo - *normal * cos_o;
+                let pi = *wi - *normal * cos_i;
+                (po.dot(&pi) / (sin_o * sin_i)).max(0.0)
+            }
+            false => 0.0,
+        };
+        // sine of the larger angle, tangent of the smaller one
+        let (sin_alpha, tan_beta) = match cos_i > cos_o {
+            true => (sin_o, sin_i / cos_i),
+            false => (sin_i, sin_o / cos_o),
+        };
+        let scale = (a + b * cos_phi * sin_alpha * tan_beta) / PI;
+        color::scale(self.albedo, scale as f32)
+    }
+
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        sample_cosine_weighted(self, ray, hit_record)
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, _wo: &Point3D) -> f64 {
+        cosine_pdf(&hit_record.normal, wi)
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+
+// Samples a cosine distributed direction, weighted with the BSDF relative
+// to a Lambertian one.
+fn sample_cosine_weighted(
+    material: &dyn Scatterable,
+    ray: &Ray,
+    hit_record: &HitRecord,
+) -> Option<BsdfSample> {
+    let wo = -ray.direction.unit_vector();
+    let wi = cosine_direction(&hit_record.normal);
+    let pdf = cosine_pdf(&hit_record.normal, &wi);
+    if pdf <= 0.0 {
+        return None;
+    }
+    let weight = color::scale(material.eval(hit_record, &wi, &wo), PI as f32);
+    Some(BsdfSample::new(wi, weight, pdf))
+}
+
+// Cloth: a diffuse base under a sheen lobe which brightens grazing angles,
+// as the fibers of velvet or satin catch the light. The sheen uses the
+// "Charlie" distribution of fiber normals (Estevez and Kulla, 2017) with
+// the visibility term of Neubelt and Pettineo (2013); `roughness` goes
+// from 0 (satin, a narrow rim) to 1 (velvet, a broad one).
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Sheen {
+    #[serde_as(as = "SrgbAsArray")]
+    pub albedo: Srgb,
+    #[serde_as(as = "SrgbAsArray")]
+    pub sheen: Srgb,
+    pub roughness: f64,
+}
+
+impl Sheen {
+    pub fn new(albedo: Srgb, sheen: Srgb, roughness: f64) -> Sheen {
+        Sheen {
+            albedo,
+            sheen,
+            roughness,
+        }
+    }
+}
+
+// The sheen is broad enough for cosine distributed directions.
+impl Scatterable for Sheen {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        let normal = &hit_record.normal;
+        let (cos_o, cos_i) = (normal.dot(wo), normal.dot(wi));
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return color::black();
+        }
+        let h = (*wo + *wi).unit_vector();
+        let inverse_alpha = 1.0 / self.roughness.clamp(0.07, 1.0).powi(2);
+        let d = (2.0 + inverse_alpha) * sin_to_normal(normal, &h).powf(inverse_alpha) / (2.0 * PI);
+        let v = 1.0 / (4.0 * (cos_i + cos_o - cos_i * cos_o));
+        color::add(
+            color::scale(self.albedo, (1.0 / PI) as f32),
+            color::scale(self.sheen, (d * v) as f32),
+        )
+    }
+
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        sample_cosine_weighted(self, ray, hit_record)
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, _wo: &Point3D) -> f64 {
+        cosine_pdf(&hit_record.normal, wi)
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Metal {
+    #[serde_as(as = "SrgbAsArray")]
+    pub albedo: Srgb,
+    pub fuzz: f64,
+}
+
+impl Metal {
+    pub fn new(albedo: Srgb, fuzz: f64) -> Metal {
+        Metal { albedo, fuzz }
+    }
+}
+
+impl Metal {
+    // albedo * pdf / cos_i, with the albedo given as a function of the
+    // cosine to the half vector.
+    fn eval_with(
+        &self,
+        hit_record: &HitRecord,
+        wi: &Point3D,
+        wo: &Point3D,
+        albedo: impl Fn(f64) -> Srgb,
+    ) -> Srgb {
+        let cos_i = wi.dot(&hit_record.normal);
+        let pdf = self.pdf(hit_record, wi, wo);
+        if cos_i <= 0.0 || pdf <= 0.0 {
+            return color::black();
+        }
+        let h = (*wi + *wo).unit_vector();
+        color::scale(albedo(wo.dot(&h)), (pdf / cos_i) as f32)
+    }
+}
+
+fn reflect(v: &Point3D, n: &Point3D) -> Point3D {
+    *v - *n * (2.0 * v.dot(n))
+}
+
+// Fuzzy reflections are offset by a point in a ball of radius `fuzz`
+// around the mirrored direction; rays which would leave below the surface
+// are absorbed.
+impl Scatterable for Metal {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        self.eval_with(hit_record, wi, wo, |_| self.albedo)
+    }
+
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        let reflected = reflect(&ray.direction.unit_vector(), &hit_record.normal);
+        let direction = reflected + Point3D::random_in_unit_sphere() * self.fuzz;
+        if direction.dot(&hit_record.normal) <= 0.0 {
+            return None;
+        }
+        let wi = direction.unit_vector();
+        match self.is_delta() {
+            true => Some(BsdfSample::delta(wi, self.albedo)),
+            false => {
+                let pdf = self.pdf(hit_record, &wi, &-ray.direction.unit_vector());
+                Some(BsdfSample::new(wi, self.albedo, pdf))
+            }
+        }
+    }
+
+    // Density of the directions through the ball, which are those within
+    // its radius of the mirrored direction, weighted by the length of the
+    // chord they cut through it.
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> f64 {
+        if self.is_delta() || wi.dot(&hit_record.normal) <= 0.0 {
+            return 0.0;
+        }
+        let cos_alpha = reflect(&-*wo, &hit_record.normal).dot(wi);
+        let half_chord_squared = self.fuzz * self.fuzz - (1.0 - cos_alpha * cos_alpha);
+        if half_chord_squared <= 0.0 {
+            return 0.0;
+        }
+        let half_chord = half_chord_squared.sqrt();
+        let far = cos_alpha + half_chord;
+        let near = (cos_alpha - half_chord).max(0.0);
+        if far <= 0.0 {
+            return 0.0;
+        }
+        (far.powi(3) - near.powi(3)) / (4.0 * PI * self.fuzz.powi(3))
+    }
+
+    fn is_delta(&self) -> bool {
+        self.fuzz <= 0.0
+    }
+}
+
+// Trowbridge-Reitz (GGX) distribution of microfacet normals, with
+// roughnesses along the tangent and the bitangent of the surface. Vectors
+// are in the local frame of the surface, with the normal along z.
+#[derive(Debug, Clone, Copy)]
+struct Ggx {
+    alpha_x: f64,
+    alpha_y: f64,
+}
+
+impl Ggx {
+    // Perceptual roughness and anisotropy (0 to 1), remapped as in the
+    // Disney BRDF. Perfectly smooth surfaces are approximated by very
+    // narrow lobes.
+    fn new(roughness: f64, anisotropy: f64) -> Ggx {
+        let aspect = (1.0 - 0.9 * anisotropy.clamp(0.0, 1.0)).sqrt();
+        let alpha = roughness * roughness;
+        Ggx {
+            alpha_x: (alpha / aspect).max(1e-6),
+            alpha_y: (alpha * aspect).max(1e-6),
+        }
+    }
+
+    // Lobes this narrow are handled as mirrors.
+    fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < 1e-3
+    }
+
+    // Density of microfacet normals.
+    fn d(&self, h: &Point3D) -> f64 {
+        if h.z() <= 0.0 {
+            return 0.0;
+        }
+        let x = h.x() / self.alpha_x;
+        let y = h.y() / self.alpha_y;
+        let e = x * x + y * y + h.z() * h.z();
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    // Smith's auxiliary function for the masking of a direction.
+    fn lambda(&self, w: &Point3D) -> f64 {
+        let z2 = w.z() * w.z();
+        if z2 == 0.0 {
+            return f64::INFINITY;
+        }
+        let x = self.alpha_x * w.x();
+        let y = self.alpha_y * w.y();
+        ((1.0 + (x * x + y * y) / z2).sqrt() - 1.0) / 2.0
+    }
+
+    fn g1(&self, w: &Point3D) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Height-correlated masking and shadowing of a pair of directions.
+    fn g(&self, wo: &Point3D, wi: &Point3D) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Samples a microfacet normal visible from `wo`, proportionally to its
+    // projected area (Heitz, 2018).
+    fn sample_visible_normal(&self, wo: &Point3D, u1: f64, u2: f64) -> Point3D {
+        // stretch the view direction to the hemisphere configuration
+        let vh = Point3D::new(self.alpha_x * wo.x(), self.alpha_y * wo.y(), wo.z()).unit_vector();
+        let length_squared = vh.x() * vh.x() + vh.y() * vh.y();
+        let t1 = match length_squared > 0.0 {
+            true => Point3D::new(-vh.y(), vh.x(), 0.0) / length_squared.sqrt(),
+            false => Point3D::new(1.0, 0.0, 0.0),
+        };
+        let t2 = vh.cross(&t1);
+        // uniform point on the projected disk, squashed to the visible half
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        Point3D::new(
+            self.alpha_x * nh.x(),
+            self.alpha_y * nh.y(),
+            nh.z().max(1e-6),
+        )
+        .unit_vector()
+    }
+
+    // Density of `sample_visible_normal` returning `h`.
+    fn visible_normal_pdf(&self, wo: &Point3D, h: &Point3D) -> f64 {
+        self.g1(wo) * wo.dot(h).max(0.0) * self.d(h) / wo.z()
+    }
+}
+
+// Tangent and bitangent at a point on a sphere, with the tangent along
+// increasing `u`, i.e. around the vertical axis.
+fn tangent_frame(normal: &Point3D) -> (Point3D, Point3D) {
+    let tangent = Point3D::new(0.0, 1.0, 0.0).cross(normal);
+    if tangent.length_squared() < 1e-12 {
+        return normal.perpendicular_basis();
+    }
+    let tangent = tangent.unit_vector();
+    (tangent, normal.cross(&tangent))
+}
+
+fn to_local(v: &Point3D, frame: &(Point3D, Point3D), normal: &Point3D) -> Point3D {
+    Point3D::new(v.dot(&frame.0), v.dot(&frame.1), v.dot(normal))
+}
+
+fn to_world(v: &Point3D, frame: &(Point3D, Point3D), normal: &Point3D) -> Point3D {
+    frame.0 * v.x() + frame.1 * v.y() + *normal * v.z()
+}
+
+// Fresnel reflectance of a conductor with the complex index of refraction
+// eta + i k, for unpolarized light.
+fn conductor_fresnel(cos_theta: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = cos_theta * cos_theta;
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_theta * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+// Metal with a GGX microfacet distribution, reflecting light according to
+// its complex index of refraction (eta + i k) for red, green and blue.
+// Rays are reflected about microfacet normals sampled from the visible
+// distribution, so their weight stays at most one; rays which would leave
+// below the surface are absorbed, which loses a little energy on very rough
+// surfaces.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Conductor {
+    #[serde_as(as = "SrgbAsArray")]
+    pub eta: Srgb,
+    #[serde_as(as = "SrgbAsArray")]
+    pub k: Srgb,
+    pub roughness: f64,
+    // Stretches the highlights along the tangent, from 0 to 1.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub anisotropy: f64,
+}
+
+fn is_zero(value: &f64) -> bool {
+    *value == 0.0
+}
+
+impl Conductor {
+    pub fn new(eta: Srgb, k: Srgb, roughness: f64) -> Conductor {
+        Conductor {
+            eta,
+            k,
+            roughness,
+            anisotropy: 0.0,
+        }
+    }
+
+    pub fn gold(roughness: f64) -> Conductor {
+        Conductor::new(
+            Srgb::new(0.143, 0.374, 1.442),
+            Srgb::new(3.983, 2.385, 1.603),
+            roughness,
+        )
+    }
+
+    pub fn copper(roughness: f64) -> Conductor {
+        Conductor::new(
+            Srgb::new(0.200, 0.924, 1.102),
+            Srgb::new(3.912, 2.452, 2.142),
+            roughness,
+        )
+    }
+
+    pub fn aluminum(roughness: f64) -> Conductor {
+        Conductor::new(
+            Srgb::new(1.657, 0.880, 0.521),
+            Srgb::new(9.224, 6.270, 4.837),
+            roughness,
+        )
+    }
+
+    pub fn with_anisotropy(self, anisotropy: f64) -> Conductor {
+        Conductor { anisotropy, ..self }
+    }
+
+    // Fresnel reflectance for light arriving at the given cosine to the
+    // (micro)surface normal.
+    pub fn reflectance(&self, cos_theta: f64) -> Srgb {
+        let f = |eta: f32, k: f32| conductor_fresnel(cos_theta, eta as f64, k as f64) as f32;
+        Srgb::new(
+            f(self.eta.red, self.k.red),
+            f(self.eta.green, self.k.green),
+            f(self.eta.blue, self.k.blue),
+        )
+    }
+}
+
+impl Conductor {
+    fn ggx(&self) -> Ggx {
+        Ggx::new(self.roughness, self.anisotropy)
+    }
+
+    // reflectance * D * G / (4 cos_o cos_i), with the reflectance given
+    // as a function of the cosine to the microfacet normal.
+    fn eval_with(
+        &self,
+        hit_record: &HitRecord,
+        wi: &Point3D,
+        wo: &Point3D,
+        reflectance: impl Fn(f64) -> Srgb,
+    ) -> Srgb {
+        let ggx = self.ggx();
+        let normal = hit_record.normal;
+        let frame = tangent_frame(&normal);
+        let (wi, wo) = (to_local(wi, &frame, &normal), to_local(wo, &frame, &normal));
+        if ggx.is_smooth() || wi.z() <= 0.0 || wo.z() <= 0.0 {
+            return color::black();
+        }
+        let h = (wo + wi).unit_vector();
+        let f = ggx.d(&h) * ggx.g(&wo, &wi) / (4.0 * wo.z() * wi.z());
+        color::scale(reflectance(wo.dot(&h)), f as f32)
+    }
+
+    fn sample_with(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        reflectance: impl Fn(f64) -> Srgb,
+    ) -> Option<BsdfSample> {
+        let normal = hit_record.normal;
+        let frame = tangent_frame(&normal);
+        let wo = to_local(&-ray.direction.unit_vector(), &frame, &normal);
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let ggx = self.ggx();
+        if ggx.is_smooth() {
+            let wi = reflect(&ray.direction.unit_vector(), &normal);
+            return Some(BsdfSample::delta(wi, reflectance(wo.z())));
+        }
+        let h = ggx.sample_visible_normal(&wo, random_f64(), random_f64());
+        let cos_theta = wo.dot(&h);
+        let wi = h * (2.0 * cos_theta) - wo;
+        if wi.z() <= 0.0 {
+            return None;
+        }
+        // reflectance * D * G / (4 cos_o cos_i) * cos_i / pdf
+        let weight = ggx.g(&wo, &wi) / ggx.g1(&wo);
+        let attenuation = color::scale(reflectance(cos_theta), weight as f32);
+        let pdf = ggx.visible_normal_pdf(&wo, &h) / (4.0 * cos_theta);
+        Some(BsdfSample::new(
+            to_world(&wi, &frame, &normal),
+            attenuation,
+            pdf,
+        ))
+    }
+}
+
+impl Scatterable for Conductor {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        self.eval_with(hit_record, wi, wo, |cos_theta| self.reflectance(cos_theta))
+    }
+
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        self.sample_with(ray, hit_record, |cos_theta| self.reflectance(cos_theta))
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> f64 {
+        let ggx = self.ggx();
+        let normal = hit_record.normal;
+        let frame = tangent_frame(&normal);
+        let (wi, wo) = (to_local(wi, &frame, &normal), to_local(wo, &frame, &normal));
+        if ggx.is_smooth() || wi.z() <= 0.0 || wo.z() <= 0.0 {
+            return 0.0;
+        }
+        let h = (wo + wi).unit_vector();
+        ggx.visible_normal_pdf(&wo, &h) / (4.0 * wo.dot(&h))
+    }
+
+    fn is_delta(&self) -> bool {
+        self.ggx().is_smooth()
+    }
+}
+
+// Index of refraction varying with the wavelength, which splits white
+// light into colors in spectral mode. Wavelengths are in micrometers.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum Dispersion {
+    // n = a + b / wavelength^2
+    Cauchy { a: f64, b: f64 },
+    // n^2 = 1 + sum of b * wavelength^2 / (wavelength^2 - c)
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Dispersion {
+    // Borosilicate crown glass, common in lenses.
+    pub fn bk7() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653],
+        }
+    }
+
+    // Dense flint glass, which disperses more, for prisms.
+    pub fn sf11() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [1.73759695, 0.313747346, 1.89878101],
+            c: [0.013188707, 0.0623068142, 155.23629],
+        }
+    }
+
+    pub fn diamond() -> Dispersion {
+        Dispersion::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.030625, 0.011236, 0.0],
+        }
+    }
+
+    // Index of refraction at a wavelength in nanometers.
+    pub fn index_of_refraction(&self, lambda: f64) -> f64 {
+        let l2 = (lambda / 1000.0) * (lambda / 1000.0);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let n2 = 1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>();
+                n2.sqrt()
+            }
+        }
+    }
+}
+
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Glass {
+    pub index_of_refraction: f64,
+    // Used instead of `index_of_refraction` in spectral mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dispersion: Option<Dispersion>,
+    // Frosted glass refracts about GGX microfacet normals; 0 is smooth.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub roughness: f64,
+    // Color of light which traveled a distance of one inside the glass;
+    // light going further is absorbed more (Beer-Lambert law).
+    #[serde_as(as = "Option<SrgbAsArray>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub absorption: Option<Srgb>,
+}
+
+impl Glass {
+    pub fn new(index_of_refraction: f64) -> Glass {
+        Glass {
+            index_of_refraction,
+            dispersion: None,
+            roughness: 0.0,
+            absorption: None,
+        }
+    }
+
+    pub fn with_roughness(self, roughness: f64) -> Glass {
+        Glass { roughness, ..self }
+    }
+
+    pub fn with_absorption(self, color: Srgb) -> Glass {
+        Glass {
+            absorption: Some(color),
+            ..self
+        }
+    }
+
+    pub fn with_dispersion(self, dispersion: Dispersion) -> Glass {
+        Glass {
+            dispersion: Some(dispersion),
+            ..self
+        }
+    }
+
+    // Index of refraction for a ray, which depends on its wavelength if it
+    // has one.
+    pub fn index_of_refraction_for(&self, ray: &Ray) -> f64 {
+        self.index_of_refraction_at(ray.wavelength)
+    }
+
+    fn index_of_refraction_at(&self, wavelength: Option<f64>) -> f64 {
+        match (&self.dispersion, wavelength) {
+            (Some(dispersion), Some(lambda)) => dispersion.index_of_refraction(lambda),
+            _ => self.index_of_refraction,
+        }
+    }
+}
+
+fn refract(uv: &Point3D, n: &Point3D, etai_over_etat: f64) -> Point3D {
+    let cos_theta = ((-*uv).dot(n)).min(1.0);
+    let r_out_perp = (*uv + *n * cos_theta) * etai_over_etat;
+    let r_out_parallel = *n * (-1.0 * (1.0 - r_out_perp.length_squared()).abs().sqrt());
+    r_out_perp + r_out_parallel
+}
+
+fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
+    let mut r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
+    r0 = r0 * r0;
+    r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+}
+
+#[test]
+fn test_refract() {
+    let uv = Point3D::new(1.0, 1.0, 0.0);
+    let n = Point3D::new(-1.0, 0.0, 0.0);
+    let etai_over_etat = 1.0;
+    let expected = Point3D::new(0.0, 1.0, 0.0);
+    let actual = refract(&uv, &n, etai_over_etat);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn test_reflectance() {
+    let cosine = 0.0;
+    let ref_idx = 1.5;
+    let expected = 1.0;
+    let actual = reflectance(cosine, ref_idx);
+    assert_eq!(actual, expected);
+}
+
+// Glass is only sampled, also when rough: how much light it absorbs inside
+// depends on the whole path and not just on the directions.
+// Rough glass evaluates the reflection off its outside; light refracted
+// through it, and anything inside it, is only sampled.
+impl Scatterable for Glass {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        match self.rough_reflection(hit_record, wi, wo) {
+            Some((f, _)) => Srgb::new(f as f32, f as f32, f as f32),
+            None => color::black(),
+        }
+    }
+
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        // rays leaving the glass were absorbed along the way inside
+        let attenuation = match (self.absorption, hit_record.front_face) {
+            (Some(color), false) => {
+                let distance = (hit_record.t * ray.direction.length()) as f32;
+                Srgb::new(
+                    color.red.powf(distance),
+                    color.green.powf(distance),
+                    color.blue.powf(distance),
+                )
+            }
+            _ => white(),
+        };
+        let index_of_refraction = self.index_of_refraction_for(ray);
+        let refraction_ratio = if hit_record.front_face {
+            1.0 / index_of_refraction
+        } else {
+            index_of_refraction
+        };
+        let unit_direction = ray.direction.unit_vector();
+        if self.roughness > 0.0 {
+            return self.scatter_rough(&unit_direction, hit_record, refraction_ratio, attenuation);
+        }
+        let cos_theta = (-unit_direction).dot(&hit_record.normal).min(1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let cannot_refract = refraction_ratio * sin_theta > 1.0;
+        if cannot_refract || reflectance(cos_theta, refraction_ratio) > random_f64() {
+            let reflected = reflect(&unit_direction, &hit_record.normal);
+            Some(BsdfSample::delta(reflected, attenuation))
+        } else {
+            let direction = refract(&unit_direction, &hit_record.normal, refraction_ratio);
+            Some(BsdfSample::delta(direction, attenuation))
+        }
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> f64 {
+        self.rough_reflection(hit_record, wi, wo)
+            .map_or(0.0, |(_, pdf)| pdf)
+    }
+
+    fn is_delta(&self) -> bool {
+        self.roughness <= 0.0
+    }
+}
+
+impl Glass {
+    // Same as the smooth case, about a microfacet normal sampled from the
+    // visible GGX distribution instead of the surface normal. Rays which
+    // end up on the wrong side of the surface are absorbed.
+    fn scatter_rough(
+        &self,
+        unit_direction: &Point3D,
+        hit_record: &HitRecord,
+        refraction_ratio: f64,
+        attenuation: Srgb,
+    ) -> Option<BsdfSample> {
+        let normal = hit_record.normal;
+        let frame = tangent_frame(&normal);
+        let wo = to_local(&-*unit_direction, &frame, &normal);
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let ggx = Ggx::new(self.roughness, 0.0);
+        let h = ggx.sample_visible_normal(&wo, random_f64(), random_f64());
+        let facet = to_world(&h, &frame, &normal);
+
+        let cos_theta = wo.dot(&h).min(1.0);
+        let fresnel = glass_fresnel(cos_theta, refraction_ratio);
+        let reflected = fresnel > random_f64();
+        let direction = match reflected {
+            true => reflect(unit_direction, &facet),
+            false => refract(unit_direction, &facet, refraction_ratio),
+        };
+        let wi = to_local(&direction, &frame, &normal);
+        if (wi.z() > 0.0) != reflected {
+            return None;
+        }
+        let weight = color::scale(attenuation, (ggx.g(&wo, &wi) / ggx.g1(&wo)) as f32);
+        match reflected && hit_record.front_face {
+            true => {
+                let pdf = fresnel * ggx.visible_normal_pdf(&wo, &h) / (4.0 * cos_theta);
+                Some(BsdfSample::new(direction, weight, pdf))
+            }
+            false => Some(BsdfSample::delta(direction, weight)),
+        }
+    }
+
+    // GGX reflection off the outside of rough glass, without the cosine,
+    // and its density.
+    fn rough_reflection(
+        &self,
+        hit_record: &HitRecord,
+        wi: &Point3D,
+        wo: &Point3D,
+    ) -> Option<(f64, f64)> {
+        if self.is_delta() || !hit_record.front_face {
+            return None;
+        }
+        let normal = hit_record.normal;
+        let frame = tangent_frame(&normal);
+        let (wi, wo) = (to_local(wi, &frame, &normal), to_local(wo, &frame, &normal));
+        if wi.z() <= 0.0 || wo.z() <= 0.0 {
+            return None;
+        }
+        let ggx = Ggx::new(self.roughness, 0.0);
+        let h = (wo + wi).unit_vector();
+        let cos_theta = wo.dot(&h).min(1.0);
+        let index_of_refraction = self.index_of_refraction_at(hit_record.wavelength);
+        let fresnel = glass_fresnel(cos_theta, 1.0 / index_of_refraction);
+        let f = fresnel * ggx.d(&h) * ggx.g(&wo, &wi) / (4.0 * wo.z() * wi.z());
+        let pdf = fresnel * ggx.visible_normal_pdf(&wo, &h) / (4.0 * cos_theta);
+        Some((f, pdf))
+    }
+}
+
+// Fraction of the light reflected by glass, all of it if it can't refract.
+fn glass_fresnel(cos_theta: f64, refraction_ratio: f64) -> f64 {
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    match refraction_ratio * sin_theta > 1.0 {
+        true => 1.0,
+        false => reflectance(cos_theta, refraction_ratio),
+    }
+}
+
+fn schlick(f0: f64, cos_theta: f64) -> f64 {
+    f0 + (1.0 - f0) * (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5)
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+// Disney-style uber material (Burley, 2012 and 2015) mixing a diffuse base
+// with sheen, a GGX specular lobe tinted by the base color as the surface
+// becomes metallic, a clear coat and rough transmission. One lobe is
+// picked per scattering event, by its estimated share of the reflected
+// light, and the sampled direction is weighted with the whole material and
+// the density of the mixture, so the lobes are sampled consistently.
+//
+// `Lambertian`, `Metal` and `Glass` are special cases, see `diffuse`,
+// `metal` and `glass`.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Principled {
+    #[serde_as(as = "SrgbAsArray")]
+    pub base_color: Srgb,
+    pub metallic: f64,
+    pub roughness: f64,
+    // Reflectance of dielectrics at normal incidence, 0.5 being 4%.
+    pub specular: f64,
+    // Grazing retro-reflection of cloth.
+    pub sheen: f64,
+    // Strength and roughness of a second, white specular lobe on top.
+    pub clearcoat: f64,
+    pub clearcoat_roughness: f64,
+    // Fraction of the dielectric base which transmits light instead of
+    // diffusing it, refracted with `ior`.
+    pub transmission: f64,
+    pub ior: f64,
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        Principled {
+            base_color: Srgb::new(0.8, 0.8, 0.8),
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            sheen: 0.0,
+            clearcoat: 0.0,
+            clearcoat_roughness: 0.1,
+            transmission: 0.0,
+            ior: 1.5,
+        }
+    }
+}
+
+// Lobes of a principled material, in the order of their probabilities.
+const DIFFUSE: usize = 0;
+const SPECULAR: usize = 1;
+const CLEARCOAT: usize = 2;
+const TRANSMISSION: usize = 3;
+
+impl Principled {
+    pub fn new(base_color: Srgb) -> Principled {
+        Principled {
+            base_color,
+            ..Principled::default()
+        }
+    }
+
+    // Same as `Lambertian`.
+    pub fn diffuse(albedo: Srgb) -> Principled {
+        Principled {
+            specular: 0.0,
+            ..Principled::new(albedo)
+        }
+    }
+
+    pub fn metal(albedo: Srgb, roughness: f64) -> Principled {
+        Principled {
+            metallic: 1.0,
+            roughness,
+            ..Principled::new(albedo)
+        }
+    }
+
+    // Smooth or rough glass, with the specular reflectance of its index of
+    // refraction.
+    pub fn glass(ior: f64, roughness: f64) -> Principled {
+        let r0 = (ior - 1.0) / (ior + 1.0);
+        Principled {
+            roughness,
+            specular: r0 * r0 / 0.08,
+            transmission: 1.0,
+            ior,
+            ..Principled::new(Srgb::new(1.0, 1.0, 1.0))
+        }
+    }
+
+    // Fresnel reflectance of the dielectric base; without any specular
+    // reflection, not even at grazing angles.
+    fn dielectric_fresnel(&self, cos_theta: f64) -> f64 {
+        match self.specular > 0.0 {
+            true => schlick(0.08 * self.specular, cos_theta),
+            false => 0.0,
+        }
+    }
+
+    // Reflectance of the specular lobe, tinted by the base color as the
+    // surface becomes metallic.
+    fn specular_fresnel(&self, cos_theta: f64) -> [f64; 3] {
+        let dielectric = self.dielectric_fresnel(cos_theta);
+        let c = self.base_color;
+        [c.red, c.green, c.blue]
+            .map(|c| lerp(dielectric, schlick(c as f64, cos_theta), self.metallic))
+    }
+
+    // Probabilities of sampling each lobe for light leaving towards `wo`.
+    fn lobe_probabilities(&self, wo: &Point3D) -> [f64; 4] {
+        let dielectric = (1.0 - self.metallic) * (1.0 - self.dielectric_fresnel(wo.z()));
+        let base = color::luminance(self.base_color) as f64;
+        let f = self.specular_fresnel(wo.z());
+        let specular = 0.2126 * f[0] + 0.7152 * f[1] + 0.0722 * f[2];
+        let mut weights = [
+            dielectric * (1.0 - self.transmission) * (base + self.sheen).max(0.05),
+            specular,
+            0.25 * self.clearcoat * schlick(0.04, wo.z()),
+            dielectric * self.transmission,
+        ];
+        let total: f64 = weights.iter().sum();
+        if total > 0.0 {
+            weights.iter_mut().for_each(|w| *w /= total);
+        }
+        weights
+    }
+
+    // Reflected radiance per unit of incident irradiance, times the cosine
+    // of `wi`, and the density of sampling `wi` from the mixture of lobes,
+    // in the local frame of the surface.
+    fn eval_local(&self, wi: &Point3D, wo: &Point3D) -> ([f64; 3], f64) {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return ([0.0; 3], 0.0);
+        }
+        let probabilities = self.lobe_probabilities(wo);
+        let h = (*wo + *wi).unit_vector();
+        let cos_d = wi.dot(&h);
+        // the coat reflects some of the light before it reaches the base
+        let coat = 1.0 - 0.25 * self.clearcoat * schlick(0.04, wo.z());
+
+        let dielectric = (1.0 - self.metallic) * (1.0 - self.dielectric_fresnel(wo.z()));
+        let diffuse = dielectric * (1.0 - self.transmission) / PI;
+        let sheen = dielectric * (1.0 - self.transmission) * self.sheen * (1.0 - cos_d).powi(5);
+        let ggx = Ggx::new(self.roughness, 0.0);
+        let specular = ggx.d(&h) * ggx.g(wo, wi) / (4.0 * wo.z() * wi.z());
+        let c = self.base_color;
+        let mut f = [c.red as f64, c.green as f64, c.blue as f64];
+        for (f, fresnel) in f.iter_mut().zip(self.specular_fresnel(cos_d)) {
+            *f = coat * (*f * diffuse + sheen + fresnel * specular) * wi.z();
+        }
+
+        let coat_ggx = Ggx::new(self.clearcoat_roughness, 0.0);
+        let clearcoat =
+            0.25 * self.clearcoat * schlick(0.04, cos_d) * coat_ggx.d(&h) * coat_ggx.g(wo, wi)
+                / (4.0 * wo.z());
+        f.iter_mut().for_each(|f| *f += clearcoat);
+
+        // densities of the reflected direction from the half vector
+        let pdf = probabilities[DIFFUSE] * wi.z() / PI
+            + probabilities[SPECULAR] * ggx.visible_normal_pdf(wo, &h) / (4.0 * cos_d)
+            + probabilities[CLEARCOAT] * coat_ggx.visible_normal_pdf(wo, &h) / (4.0 * cos_d);
+        (f, pdf)
+    }
+}
+
+// Transmitted rays are only sampled, like `Glass`; `eval` and `pdf` cover
+// the reflection.
+impl Scatterable for Principled {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        let normal = hit_record.normal;
+        let frame = tangent_frame(&normal);
+        let (wi, wo) = (to_local(wi, &frame, &normal), to_local(wo, &frame, &normal));
+        if !hit_record.front_face || wi.z() <= 0.0 {
+            return color::black();
+        }
+        let (f, _) = self.eval_local(&wi, &wo);
+        let f = f.map(|f| (f / wi.z()) as f32);
+        Srgb::new(f[0], f[1], f[2])
+    }
+
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        // rays inside a transmissive object only see the interface
+        if !hit_record.front_face {
+            return Glass::new(self.ior)
+                .with_roughness(self.roughness)
+                .sample(ray, hit_record);
+        }
+        let normal = hit_record.normal;
+        let frame = tangent_frame(&normal);
+        let unit_direction = ray.direction.unit_vector();
+        let wo = to_local(&-unit_direction, &frame, &normal);
+        if wo.z() <= 0.0 {
+            return None;
+        }
+
+        let probabilities = self.lobe_probabilities(&wo);
+        let u = random_f64();
+        let mut lobe = 0;
+        let mut cumulative = probabilities[0];
+        while lobe < TRANSMISSION && u >= cumulative {
+            lobe += 1;
+            cumulative += probabilities[lobe];
+        }
+        let wi = match lobe {
+            DIFFUSE => {
+                let direction = Point3D::new(0.0, 0.0, 1.0) + Point3D::random_unit_vector();
+                match direction.near_zero() {
+                    true => Point3D::new(0.0, 0.0, 1.0),
+                    false => direction.unit_vector(),
+                }
+            }
+            SPECULAR | CLEARCOAT => {
+                let roughness = match lobe {
+                    SPECULAR => self.roughness,
+                    _ => self.clearcoat_roughness,
+                };
+                let ggx = Ggx::new(roughness, 0.0);
+                let h = ggx.sample_visible_normal(&wo, random_f64(), random_f64());
+                h * (2.0 * wo.dot(&h)) - wo
+            }
+            _ => return self.transmit(&wo, &unit_direction, hit_record, probabilities),
+        };
+
+        let (f, pdf) = self.eval_local(&wi, &wo);
+        if pdf <= 0.0 {
+            return None;
+        }
+        let weight = f.map(|f| (f / pdf) as f32);
+        Some(BsdfSample::new(
+            to_world(&wi, &frame, &normal),
+            Srgb::new(weight[0], weight[1], weight[2]),
+            pdf,
+        ))
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> f64 {
+        if !hit_record.front_face {
+            return 0.0;
+        }
+        let normal = hit_record.normal;
+        let frame = tangent_frame(&normal);
+        let (wi, wo) = (to_local(wi, &frame, &normal), to_local(wo, &frame, &normal));
+        self.eval_local(&wi, &wo).1
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+}
+
+impl Principled {
+    // Refracts into the surface about a sampled microfacet normal. Only
+    // this lobe scatters below the surface, so the weight doesn't depend on
+    // the other lobes.
+    fn transmit(
+        &self,
+        wo: &Point3D,
+        unit_direction: &Point3D,
+        hit_record: &HitRecord,
+        probabilities: [f64; 4],
+    ) -> Option<BsdfSample> {
+        let normal = hit_record.normal;
+        let frame = tangent_frame(&normal);
+        let ggx = Ggx::new(self.roughness, 0.0);
+        let h = ggx.sample_visible_normal(wo, random_f64(), random_f64());
+        let cos_theta = wo.dot(&h).min(1.0);
+        let facet = to_world(&h, &frame, &normal);
+        let direction = refract(unit_direction, &facet, 1.0 / self.ior);
+        let wi = to_local(&direction, &frame, &normal);
+        if wi.z() >= 0.0 {
+            return None;
+        }
+        let coat = 1.0 - 0.25 * self.clearcoat * schlick(0.04, wo.z());
+        let weight = coat
+            * (1.0 - self.metallic)
+            * self.transmission
+            * (1.0 - self.dielectric_fresnel(cos_theta))
+            * ggx.g(wo, &wi)
+            / ggx.g1(wo)
+            / probabilities[TRANSMISSION];
+        Some(BsdfSample::delta(
+            direction,
+            color::scale(self.base_color, weight as f32),
+        ))
+    }
+}
+
+// Dielectric coat over another material, such as varnish over wood or the
+// clear coat of car paint. The coat reflects light about GGX microfacet
+// normals with the Fresnel reflectance of its index of refraction; the rest
+// crosses it, tinted on the way in and out, to the base. The base is
+// evaluated with the same directions, ignoring their refraction and the
+// light bouncing between the layers, which darkens rough coats a little.
+//
+// Directions are sampled from the coat or the base, by their estimated
+// share of the reflected light, and weighted like `Principled`. Rays hitting
+// the back of the surface only see the base.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Coated {
+    pub base: Box<Material>,
+    pub ior: f64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub roughness: f64,
+    // Color of white light after crossing the coat at normal incidence,
+    // down to the base and back; light crossing it at an angle is absorbed
+    // more.
+    #[serde_as(as = "SrgbAsArray")]
+    #[serde(default = "white", skip_serializing_if = "is_white")]
+    pub tint: Srgb,
+}
+
+impl Coated {
+    pub fn new(base: Material, ior: f64) -> Coated {
+        Coated {
+            base: Box::new(base),
+            ior,
+            roughness: 0.0,
+            tint: white(),
+        }
+    }
+
+    pub fn with_roughness(self, roughness: f64) -> Coated {
+        Coated { roughness, ..self }
+    }
+
+    pub fn with_tint(self, tint: Srgb) -> Coated {
+        Coated { tint, ..self }
+    }
+
+    fn ggx(&self) -> Ggx {
+        Ggx::new(self.roughness, 0.0)
+    }
+
+    fn fresnel(&self, cos_theta: f64) -> f64 {
+        reflectance(cos_theta.clamp(0.0, 1.0), self.ior)
+    }
+
+    // Fraction of the light arriving at the base from `wi`, or leaving it
+    // towards `wo`, which crosses the coat, given their cosines to the
+    // normal.
+    fn transmittance(&self, cos_i: f64, cos_o: f64) -> Srgb {
+        // the refracted rays cross the coat at a steeper angle
+        let refracted = |cos: f64| {
+            (1.0 - (1.0 - cos * cos) / (self.ior * self.ior))
+                .max(0.0)
+                .sqrt()
+        };
+        let distance = (0.5 / refracted(cos_i) + 0.5 / refracted(cos_o)) as f32;
+        let fresnel = ((1.0 - self.fresnel(cos_i)) * (1.0 - self.fresnel(cos_o))) as f32;
+        let t = self.tint;
+        Srgb::new(
+            fresnel * t.red.powf(distance),
+            fresnel * t.green.powf(distance),
+            fresnel * t.blue.powf(distance),
+        )
+    }
+
+    // Probability of sampling the coat rather than the base, for light
+    // leaving at the given cosine to the normal.
+    fn coat_probability(&self, hit_record: &HitRecord, cos_o: f64) -> f64 {
+        let coat = self.fresnel(cos_o);
+        let base = (1.0 - coat) * color::luminance(self.base.albedo(hit_record)) as f64;
+        match coat + base > 0.0 {
+            true => coat / (coat + base),
+            false => 1.0,
+        }
+    }
+
+    // Reflection of the rough coat and its density, in the local frame of
+    // the surface.
+    fn eval_coat(&self, wi: &Point3D, wo: &Point3D) -> (f64, f64) {
+        let ggx = self.ggx();
+        if ggx.is_smooth() || wi.z() <= 0.0 || wo.z() <= 0.0 {
+            return (0.0, 0.0);
+        }
+        let h = (*wo + *wi).unit_vector();
+        let f = self.fresnel(wo.dot(&h)) * ggx.d(&h) * ggx.g(wo, wi) / (4.0 * wo.z() * wi.z());
+        (f, ggx.visible_normal_pdf(wo, &h) / (4.0 * wo.dot(&h)))
+    }
+}
+
+impl Scatterable for Coated {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        let base = self.base.eval(hit_record, wi, wo);
+        if !hit_record.front_face {
+            return base;
+        }
+        let normal = hit_record.normal;
+        let frame = tangent_frame(&normal);
+        let (wi, wo) = (to_local(wi, &frame, &normal), to_local(wo, &frame, &normal));
+        if wo.z() <= 0.0 {
+            return color::black();
+        }
+        let base = color::mul(base, self.transmittance(wi.z().abs(), wo.z()));
+        let (coat, _) = self.eval_coat(&wi, &wo);
+        color::add(base, color::scale(white(), coat as f32))
+    }
+
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        if !hit_record.front_face {
+            return self.base.scatterable().sample(ray, hit_record);
+        }
+        let normal = hit_record.normal;
+        let unit_direction = ray.direction.unit_vector();
+        let cos_o = -unit_direction.dot(&normal);
+        if cos_o <= 0.0 {
+            return None;
+        }
+        let probability = self.coat_probability(hit_record, cos_o);
+        let ggx = self.ggx();
+        let wi = if random_f64() < probability {
+            if ggx.is_smooth() {
+                let wi = reflect(&unit_direction, &normal);
+                let weight = self.fresnel(cos_o) / probability;
+                return Some(BsdfSample::delta(wi, color::scale(white(), weight as f32)));
+            }
+            let frame = tangent_frame(&normal);
+            let wo = to_local(&-unit_direction, &frame, &normal);
+            let h = ggx.sample_visible_normal(&wo, random_f64(), random_f64());
+            let wi = h * (2.0 * wo.dot(&h)) - wo;
+            if wi.z() <= 0.0 {
+                return None;
+            }
+            to_world(&wi, &frame, &normal)
+        } else {
+            let sample = self.base.scatterable().sample(ray, hit_record)?;
+            if sample.delta {
+                let transmittance = self.transmittance(sample.wi.dot(&normal).abs(), cos_o);
+                let weight = color::mul(sample.weight, transmittance);
+                return Some(BsdfSample::delta(
+                    sample.wi,
+                    color::scale(weight, (1.0 / (1.0 - probability)) as f32),
+                ));
+            }
+            sample.wi
+        };
+
+        let wo = -unit_direction;
+        let pdf = self.pdf(hit_record, &wi, &wo);
+        if pdf <= 0.0 {
+            return None;
+        }
+        let f = self.eval(hit_record, &wi, &wo);
+        let cosine = wi.dot(&normal).abs();
+        Some(BsdfSample::new(
+            wi,
+            color::scale(f, (cosine / pdf) as f32),
+            pdf,
+        ))
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> f64 {
+        let base = self.base.pdf(hit_record, wi, wo);
+        if !hit_record.front_face {
+            return base;
+        }
+        let normal = hit_record.normal;
+        let frame = tangent_frame(&normal);
+        let (wi, wo) = (to_local(wi, &frame, &normal), to_local(wo, &frame, &normal));
+        if wo.z() <= 0.0 {
+            return 0.0;
+        }
+        let probability = self.coat_probability(hit_record, wo.z());
+        let (_, coat) = self.eval_coat(&wi, &wo);
+        probability * coat + (1.0 - probability) * base
+    }
+
+    fn is_delta(&self) -> bool {
+        self.ggx().is_smooth() && self.base.is_delta()
+    }
+}
+
+// Translucent solid such as wax, marble or skin: light refracts through a
+// smooth surface and scatters many times inside before it leaves again,
+// possibly far from where it entered. The path tracer follows it with a
+// random walk through the interior (see `PathTracer::walk_subsurface`), as
+// do the integrators built on it; the others refuse scenes with it.
+//
+// `albedo` is the fraction of the light which scatters at each
+// interaction, rather than being absorbed, and `mean_free_path` the average
+// distance between interactions, both for red, green and blue. Light
+// travels further in channels with a longer path, like red in skin.
+#[serde_with::serde_as]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Subsurface {
+    #[serde_as(as = "SrgbAsArray")]
+    pub albedo: Srgb,
+    #[serde_as(as = "SrgbAsArray")]
+    pub mean_free_path: Srgb,
+    pub ior: f64,
+    // Henyey-Greenstein asymmetry of the scattering inside.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub anisotropy: f64,
+}
+
+impl Subsurface {
+    pub fn new(albedo: Srgb, mean_free_path: Srgb, ior: f64) -> Subsurface {
+        Subsurface {
+            albedo,
+            mean_free_path,
+            ior,
+            anisotropy: 0.0,
+        }
+    }
+
+    pub fn with_anisotropy(self, anisotropy: f64) -> Subsurface {
+        Subsurface { anisotropy, ..self }
+    }
+
+    pub fn phase_function(&self) -> HenyeyGreenstein {
+        HenyeyGreenstein::new(self.anisotropy)
+    }
+
+    // Samples how far light travels inside along a ray before it scatters,
+    // if that happens before the ray leaves at `t_exit`, and returns the ray
+    // parameter of the interaction with the weight of the light. The
+    // distance is sampled in a channel picked by its share of the
+    // `throughput` of the path so far, and weighted by the density averaged
+    // over the channels with the same probabilities, so every channel gets
+    // its own mean free path without the weights of long walks blowing up.
+    pub fn sample_distance(&self, ray: &Ray, t_exit: f64, throughput: Srgb) -> (Option<f64>, Srgb) {
+        let channels = |color: Srgb| {
+            let c = at_wavelength(color, ray.wavelength);
+            [c.red as f64, c.green as f64, c.blue as f64]
+        };
+        let extinction = channels(self.mean_free_path).map(|d| 1.0 / d.max(1e-6));
+        let albedo = channels(self.albedo).map(|a| a.clamp(0.0, 1.0));
+
+        let mut probabilities =
+            [throughput.red, throughput.green, throughput.blue].map(|t| t.max(0.0) as f64);
+        let total: f64 = probabilities.iter().sum();
+        probabilities = match total > 0.0 {
+            true => probabilities.map(|p| p / total),
+            false => [1.0 / 3.0; 3],
+        };
+        let u = random_f64();
+        let channel = if u < probabilities[0] {
+            0
+        } else if u < probabilities[0] + probabilities[1] {
+            1
+        } else {
+            2
+        };
+
+        let length = ray.direction.length();
+        let distance = -(1.0 - random_f64()).ln() / extinction[channel];
+        let scattered = distance < t_exit * length;
+        let distance = distance.min(t_exit * length);
+
+        // density of scattering at the distance, or probability of getting
+        // past it, in each channel
+        let mut density = extinction.map(|sigma| (-sigma * distance).exp());
+        if scattered {
+            density
+                .iter_mut()
+                .zip(extinction)
+                .for_each(|(d, sigma)| *d *= sigma);
+        }
+        let pdf: f64 = density.iter().zip(probabilities).map(|(d, p)| d * p).sum();
+        let weight = match scattered {
+            true => [0, 1, 2].map(|i| (density[i] * albedo[i] / pdf) as f32),
+            false => density.map(|d| (d / pdf) as f32),
+        };
+        (
+            scattered.then_some(distance / length),
+            Srgb::new(weight[0], weight[1], weight[2]),
+        )
+    }
+}
+
+// The surface is a smooth dielectric interface.
+impl Scatterable for Subsurface {
+    fn eval(&self, _hit_record: &HitRecord, _wi: &Point3D, _wo: &Point3D) -> Srgb {
+        color::black()
+    }
+
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        Glass::new(self.ior).sample(ray, hit_record)
+    }
+
+    fn pdf(&self, _hit_record: &HitRecord, _wi: &Point3D, _wo: &Point3D) -> f64 {
+        0.0
+    }
+
+    fn is_delta(&self) -> bool {
+        true
+    }
+}
+
+// Complex numbers, for the Fresnel amplitudes of absorbing media.
+#[derive(Debug, Clone, Copy)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    fn new(re: f64, im: f64) -> Complex {
+        Complex { re, im }
+    }
+
+    fn norm_squared(&self) -> f64 {
+        self.re * self.re + self.im * self.im
+    }
+
+    // Principal square root.
+    fn sqrt(&self) -> Complex {
+        let r = self.norm_squared().sqrt();
+        Complex::new(
+            ((r + self.re) / 2.0).max(0.0).sqrt(),
+            ((r - self.re) / 2.0).max(0.0).sqrt().copysign(self.im),
+        )
+    }
+}
+
+impl From<f64> for Complex {
+    fn from(re: f64) -> Complex {
+        Complex::new(re, 0.0)
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+
+    fn div(self, other: Complex) -> Complex {
+        let d = other.norm_squared();
+        Complex::new(
+            (self.re * other.re + self.im * other.im) / d,
+            (self.im * other.re - self.re * other.im) / d,
+        )
+    }
+}
+
+// Thin dielectric film over another material, like a soap bubble, oil on
+// water or the colors of heated steel. Light reflected at the top of the
+// film interferes with light reflected below it, so the reflectance
+// depends on the wavelength, the angle and the thickness of the film, in
+// nanometers; colors show up below about a micrometer.
+//
+// In RGB mode the reflectance spectrum is averaged into a color, and in
+// spectral mode each ray sees its own wavelength.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ThinFilm {
+    pub base: Box<Material>,
+    pub thickness: f64,
+    pub ior: f64,
+}
+
+impl ThinFilm {
+    pub fn new(base: Material, thickness: f64, ior: f64) -> ThinFilm {
+        ThinFilm {
+            base: Box::new(base),
+            thickness,
+            ior,
+        }
+    }
+
+    // Index of refraction below the film at a wavelength in nanometers,
+    // complex for conductors.
+    fn substrate(&self, lambda: f64) -> Complex {
+        match self.base.as_ref() {
+            Material::Glass(g) => Complex::from(match &g.dispersion {
+                Some(dispersion) => dispersion.index_of_refraction(lambda),
+                None => g.index_of_refraction,
+            }),
+            Material::Conductor(c) => Complex::new(
+                rgb_to_spectrum(c.eta, lambda) as f64,
+                rgb_to_spectrum(c.k, lambda) as f64,
+            ),
+            // a dielectric reflecting as much at normal incidence
+            Material::Metal(m) => {
+                let r = (rgb_to_spectrum(m.albedo, lambda) as f64)
+                    .clamp(0.0, 0.99)
+                    .sqrt();
+                Complex::from((1.0 + r) / (1.0 - r))
+            }
+            _ => Complex::from(1.5),
+        }
+    }
+
+    // Reflectance of the film and the surface below it, for light arriving
+    // at `cos_theta` to the normal with a wavelength in nanometers: the
+    // light reflected at the top adds up with the light coming back after
+    // each round trip inside the film, shifted by its optical path (Airy
+    // summation), averaged over both polarizations.
+    fn reflectance_at(&self, cos_theta: f64, lambda: f64) -> f64 {
+        let cos1 = cos_theta.clamp(0.0, 1.0);
+        let sin1_squared = 1.0 - cos1 * cos1;
+        let n2 = self.ior;
+        let cos2 = (1.0 - sin1_squared / (n2 * n2)).max(0.0).sqrt();
+        let n3 = self.substrate(lambda);
+        // n3 cos(theta3) from Snell's law, complex in conductors
+        let n3_cos3 = (n3 * n3 - Complex::from(sin1_squared)).sqrt();
+
+        let phase = 4.0 * PI * n2 * self.thickness * cos2 / lambda;
+        let shift = Complex::new(phase.cos(), phase.sin());
+        let airy = |r12: f64, r23: Complex| {
+            let r12 = Complex::from(r12);
+            ((r12 + r23 * shift) / (Complex::from(1.0) + r12 * r23 * shift)).norm_squared()
+        };
+
+        let n2_cos2 = Complex::from(n2 * cos2);
+        let s = airy(
+            (cos1 - n2 * cos2) / (cos1 + n2 * cos2),
+            (n2_cos2 - n3_cos3) / (n2_cos2 + n3_cos3),
+        );
+        let n3_n3_cos2 = n3 * n3 * Complex::from(cos2);
+        let n2_n3_cos3 = Complex::from(n2) * n3_cos3;
+        let p = airy(
+            (n2 * cos1 - cos2) / (n2 * cos1 + cos2),
+            (n3_n3_cos2 - n2_n3_cos3) / (n3_n3_cos2 + n2_n3_cos3),
+        );
+        ((s + p) / 2.0).clamp(0.0, 1.0)
+    }
+
+    // Reflectance seen by a ray of the given wavelength, or as a color
+    // outside spectral mode.
+    fn reflectance_for(&self, cos_theta: f64, wavelength: Option<f64>) -> Srgb {
+        match wavelength {
+            Some(lambda) => {
+                let r = self.reflectance_at(cos_theta, lambda) as f32;
+                Srgb::new(r, r, r)
+            }
+            None => {
+                let c = reflectance_to_rgb(|lambda| self.reflectance_at(cos_theta, lambda), 32);
+                Srgb::new(
+                    c.red.clamp(0.0, 1.0),
+                    c.green.clamp(0.0, 1.0),
+                    c.blue.clamp(0.0, 1.0),
+                )
+            }
+        }
+    }
+
+    // Fraction of the light crossing the film from `wo` into the base, and
+    // back out towards `wi` if that is above the surface, given their
+    // cosines to the normal.
+    fn transmittance(&self, cos_i: f64, cos_o: f64, wavelength: Option<f64>) -> Srgb {
+        let transmitted = |cos_theta: f64| {
+            let r = self.reflectance_for(cos_theta, wavelength);
+            Srgb::new(1.0 - r.red, 1.0 - r.green, 1.0 - r.blue)
+        };
+        match cos_i > 0.0 {
+            true => color::mul(transmitted(cos_i), transmitted(cos_o)),
+            false => transmitted(cos_o),
+        }
+    }
+
+    // Probability of sampling the reflection off the film rather than the
+    // base, for light leaving at the given cosine to the normal.
+    fn reflection_probability(&self, hit_record: &HitRecord, cos_o: f64) -> f64 {
+        let film = color::luminance(self.reflectance_for(cos_o, hit_record.wavelength)) as f64;
+        let base = (1.0 - film) * color::luminance(self.base.albedo(hit_record)) as f64;
+        match film + base > 0.0 {
+            true => film / (film + base),
+            false => 1.0,
+        }
+    }
+}
+
+// Over metals the film takes the place of their Fresnel reflectance, in
+// their own lobe. Over anything else it is a smooth layer on top, like the
+// coat of `Coated`; light it doesn't reflect crosses it to the base, or is
+// refracted straight into `Glass`, whose surface the film's reflectance
+// includes. Rays inside the base only see the base.
+impl Scatterable for ThinFilm {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        let base = self.base.eval(hit_record, wi, wo);
+        match self.base.as_ref() {
+            Material::Conductor(c) => c.eval_with(hit_record, wi, wo, |cos_theta| {
+                self.reflectance_for(cos_theta, hit_record.wavelength)
+            }),
+            Material::Metal(m) => m.eval_with(hit_record, wi, wo, |cos_theta| {
+                self.reflectance_for(cos_theta, hit_record.wavelength)
+            }),
+            _ if !hit_record.front_face => base,
+            _ => {
+                let normal = hit_record.normal;
+                let transmittance =
+                    self.transmittance(normal.dot(wi), normal.dot(wo), hit_record.wavelength);
+                color::mul(base, transmittance)
+            }
+        }
+    }
+
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        let base = self.base.scatterable();
+        let unit_direction = ray.direction.unit_vector();
+        let wo = -unit_direction;
+        match self.base.as_ref() {
+            Material::Conductor(c) => {
+                return c.sample_with(ray, hit_record, |cos_theta| {
+                    self.reflectance_for(cos_theta, ray.wavelength)
+                })
+            }
+            Material::Metal(_) => {
+                let sample = base.sample(ray, hit_record)?;
+                let h = (wo + sample.wi).unit_vector();
+                let reflectance = self.reflectance_for(wo.dot(&h), ray.wavelength);
+                return Some(BsdfSample {
+                    weight: reflectance,
+                    ..sample
+                });
+            }
+            _ if !hit_record.front_face => return base.sample(ray, hit_record),
+            _ => {}
+        }
+
+        let normal = hit_record.normal;
+        let cos_o = wo.dot(&normal);
+        if cos_o <= 0.0 {
+            return None;
+        }
+        let probability = self.reflection_probability(hit_record, cos_o);
+        if random_f64() < probability {
+            let reflectance = self.reflectance_for(cos_o, ray.wavelength);
+            return Some(BsdfSample::delta(
+                reflect(&unit_direction, &normal),
+                color::scale(reflectance, (1.0 / probability) as f32),
+            ));
+        }
+        let sample = match self.base.as_ref() {
+            Material::Glass(g) => {
+                let refraction_ratio = 1.0 / g.index_of_refraction_for(ray);
+                let direction = refract(&unit_direction, &normal, refraction_ratio);
+                BsdfSample::delta(direction, white())
+            }
+            _ => base.sample(ray, hit_record)?,
+        };
+        if sample.delta {
+            let cos_i = sample.wi.dot(&normal);
+            let transmittance = self.transmittance(cos_i, cos_o, ray.wavelength);
+            let weight = color::mul(sample.weight, transmittance);
+            return Some(BsdfSample::delta(
+                sample.wi,
+                color::scale(weight, (1.0 / (1.0 - probability)) as f32),
+            ));
+        }
+
+        let pdf = self.pdf(hit_record, &sample.wi, &wo);
+        if pdf <= 0.0 {
+            return None;
+        }
+        let f = self.eval(hit_record, &sample.wi, &wo);
+        let cosine = sample.wi.dot(&normal).abs();
+        Some(BsdfSample::new(
+            sample.wi,
+            color::scale(f, (cosine / pdf) as f32),
+            pdf,
+        ))
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> f64 {
+        let base = self.base.pdf(hit_record, wi, wo);
+        let cos_o = hit_record.normal.dot(wo);
+        match self.base.as_ref() {
+            Material::Conductor(_) | Material::Metal(_) => base,
+            _ if !hit_record.front_face => base,
+            _ if cos_o <= 0.0 => 0.0,
+            _ => (1.0 - self.reflection_probability(hit_record, cos_o)) * base,
+        }
+    }
+
+    fn is_delta(&self) -> bool {
+        self.base.is_delta()
     }
 }
 
-impl Scatterable for Light {
-    fn scatter(&self, _ray: &Ray, _hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
-        Some((None, Srgb::new(1.0, 1.0, 1.0)))
-    }
+// Blend of two materials, for surfaces which are partly one and partly the
+// other, like rust on metal. Each scattering event picks one of them, the
+// `top` with the probability given by the weight at the hit point; samples
+// from delta lobes keep their weight, and the others are weighted with the
+// blend of both materials and of their densities.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Mix {
+    pub base: Box<Material>,
+    pub top: Box<Material>,
+    pub weight: MixWeight,
 }
 
-#[serde_with::serde_as]
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
-pub struct Lambertian {
-    #[serde_as(as = "SrgbAsArray")]
-    pub albedo: Srgb,
+// Fraction of a `Mix` taken by its top material: a number, or the path of a
+// grayscale JPEG image wrapped around the sphere like a `Texture`, from
+// black for the base to white for the top.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum MixWeight {
+    Constant(f64),
+    Mask(Mask),
 }
 
-impl Lambertian {
-    pub fn new(albedo: Srgb) -> Lambertian {
-        Lambertian { albedo }
-    }
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Mask {
+    values: Vec<f32>,
+    width: usize,
+    height: usize,
+    path: String,
 }
 
-impl Scatterable for Lambertian {
-    fn scatter(&self, _ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
-        let mut scatter_direction = hit_record.normal + Point3D::random_in_unit_sphere();
-        if scatter_direction.near_zero() {
-            scatter_direction = hit_record.normal;
-        }
-        let target = hit_record.point + scatter_direction;
-        let scattered = Ray::new(hit_record.point, target - hit_record.point);
-        let attenuation = self.albedo;
-        Some((Some(scattered), attenuation))
+// Masks are data rather than colors, so their values are used as stored,
+// averaged over the channels of color images.
+impl TryFrom<String> for Mask {
+    type Error = io::Error;
+
+    fn try_from(path: String) -> Result<Mask, io::Error> {
+        let file = File::open(&path)?;
+        let mut decoder = Decoder::new(BufReader::new(file));
+        let pixels = decoder
+            .decode()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let metadata = decoder.info().unwrap();
+        let (width, height) = (metadata.width as usize, metadata.height as usize);
+        let channels = (pixels.len() / (width * height).max(1)).max(1);
+        let values = pixels
+            .chunks(channels)
+            .map(|p| p.iter().map(|v| *v as f32).sum::<f32>() / (255.0 * channels as f32))
+            .collect();
+        Ok(Mask {
+            values,
+            width,
+            height,
+            path,
+        })
     }
 }
 
-#[serde_with::serde_as]
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
-pub struct Metal {
-    #[serde_as(as = "SrgbAsArray")]
-    pub albedo: Srgb,
-    pub fuzz: f64,
+impl From<Mask> for String {
+    fn from(mask: Mask) -> String {
+        mask.path
+    }
 }
 
-impl Metal {
-    pub fn new(albedo: Srgb, fuzz: f64) -> Metal {
-        Metal { albedo, fuzz }
+impl Mask {
+    pub fn value(&self, u: f64, v: f64) -> f64 {
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = (((1.0 - v) * self.height as f64) as usize).min(self.height - 1);
+        self.values[y * self.width + x] as f64
     }
 }
 
-fn reflect(v: &Point3D, n: &Point3D) -> Point3D {
-    *v - *n * (2.0 * v.dot(n))
-}
+impl Mix {
+    pub fn new(base: Material, top: Material, weight: MixWeight) -> Mix {
+        Mix {
+            base: Box::new(base),
+            top: Box::new(top),
+            weight,
+        }
+    }
 
-impl Scatterable for Metal {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
-        let reflected = reflect(&ray.direction, &hit_record.normal);
-        let scattered = Ray::new(
-            hit_record.point,
-            reflected + Point3D::random_in_unit_sphere() * self.fuzz,
-        );
-        let attenuation = self.albedo;
-        if scattered.direction.dot(&hit_record.normal) > 0.0 {
-            Some((Some(scattered), attenuation))
-        } else {
-            None
+    // Fraction of the top material at the hit point.
+    fn weight_at(&self, hit_record: &HitRecord) -> f64 {
+        match &self.weight {
+            MixWeight::Constant(weight) => weight.clamp(0.0, 1.0),
+            MixWeight::Mask(mask) => mask.value(hit_record.u, hit_record.v),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
-pub struct Glass {
-    pub index_of_refraction: f64,
-}
+impl Scatterable for Mix {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        let weight = self.weight_at(hit_record) as f32;
+        color::add(
+            color::scale(self.base.eval(hit_record, wi, wo), 1.0 - weight),
+            color::scale(self.top.eval(hit_record, wi, wo), weight),
+        )
+    }
 
-impl Glass {
-    pub fn new(index_of_refraction: f64) -> Glass {
-        Glass {
-            index_of_refraction,
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        let picked = match random_f64() < self.weight_at(hit_record) {
+            true => &self.top,
+            false => &self.base,
+        };
+        let sample = picked.scatterable().sample(ray, hit_record)?;
+        if sample.delta {
+            return Some(sample);
         }
+        let wo = -ray.direction.unit_vector();
+        let pdf = self.pdf(hit_record, &sample.wi, &wo);
+        if pdf <= 0.0 {
+            return None;
+        }
+        let f = self.eval(hit_record, &sample.wi, &wo);
+        let cosine = sample.wi.dot(&hit_record.normal).abs();
+        Some(BsdfSample::new(
+            sample.wi,
+            color::scale(f, (cosine / pdf) as f32),
+            pdf,
+        ))
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> f64 {
+        let weight = self.weight_at(hit_record);
+        (1.0 - weight) * self.base.pdf(hit_record, wi, wo)
+            + weight * self.top.pdf(hit_record, wi, wo)
+    }
+
+    fn is_delta(&self) -> bool {
+        self.base.is_delta() && self.top.is_delta()
     }
 }
 
-fn refract(uv: &Point3D, n: &Point3D, etai_over_etat: f64) -> Point3D {
-    let cos_theta = ((-*uv).dot(n)).min(1.0);
-    let r_out_perp = (*uv + *n * cos_theta) * etai_over_etat;
-    let r_out_parallel = *n * (-1.0 * (1.0 - r_out_perp.length_squared()).abs().sqrt());
-    r_out_perp + r_out_parallel
+// A material with inputs fed by a shader graph, as in
+// {"Shaded": {"material": {"Principled": ...}, "graph": {"nodes": ...,
+// "inputs": {"base_color": "ramp", "roughness": "noise"}}}}. The graph is
+// compiled when the scene is loaded, and run at each hit to set those
+// inputs on a copy of the parameters of the material.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "ShadedParams", into = "ShadedParams")]
+pub struct Shaded {
+    pub material: Box<Material>,
+    graph: ShaderGraph,
+    program: Program,
+    // Parameters of the material, if it has any inputs.
+    inputs: Option<Inputs>,
+    // Radiance of shaded lights averaged over the surface, for sampling.
+    emitted: Srgb,
 }
 
-fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
-    let mut r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
-    r0 = r0 * r0;
-    r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+#[derive(Deserialize, Serialize)]
+struct ShadedParams {
+    material: Box<Material>,
+    graph: ShaderGraph,
 }
 
-#[test]
-fn test_refract() {
-    let uv = Point3D::new(1.0, 1.0, 0.0);
-    let n = Point3D::new(-1.0, 0.0, 0.0);
-    let etai_over_etat = 1.0;
-    let expected = Point3D::new(0.0, 1.0, 0.0);
-    let actual = refract(&uv, &n, etai_over_etat);
-    assert_eq!(actual, expected);
+impl TryFrom<ShadedParams> for Shaded {
+    type Error = ShaderError;
+
+    fn try_from(params: ShadedParams) -> Result<Shaded, ShaderError> {
+        Shaded::new(*params.material, params.graph)
+    }
 }
 
-#[test]
-fn test_reflectance() {
-    let cosine = 0.0;
-    let ref_idx = 1.5;
-    let expected = 1.0;
-    let actual = reflectance(cosine, ref_idx);
-    assert_eq!(actual, expected);
+impl From<Shaded> for ShadedParams {
+    fn from(shaded: Shaded) -> ShadedParams {
+        ShadedParams {
+            material: shaded.material,
+            graph: shaded.graph,
+        }
+    }
 }
 
-impl Scatterable for Glass {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
-        let mut rng = rand::thread_rng();
-        let attenuation = Srgb::new(1.0 as f32, 1.0 as f32, 1.0 as f32);
-        let refraction_ratio = if hit_record.front_face {
-            1.0 / self.index_of_refraction
-        } else {
-            self.index_of_refraction
+impl Shaded {
+    pub fn new(material: Material, graph: ShaderGraph) -> Result<Shaded, ShaderError> {
+        let inputs = Inputs::of(&material);
+        let mut probe = inputs;
+        let program = graph.compile(material.name(), |name| {
+            let input = probe.as_mut()?.shader_input(name)?;
+            Some(input.kind())
+        })?;
+        let mut shaded = Shaded {
+            material: Box::new(material),
+            graph,
+            program,
+            inputs,
+            emitted: color::black(),
         };
-        let unit_direction = ray.direction.unit_vector();
-        let cos_theta = (-unit_direction).dot(&hit_record.normal).min(1.0);
-        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
-        let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        if cannot_refract || reflectance(cos_theta, refraction_ratio) > rng.gen::<f64>() {
-            let reflected = reflect(&unit_direction, &hit_record.normal);
-            let scattered = Ray::new(hit_record.point, reflected);
-            Some((Some(scattered), attenuation))
-        } else {
-            let direction = refract(&unit_direction, &hit_record.normal, refraction_ratio);
-            let scattered = Ray::new(hit_record.point, direction);
-            Some((Some(scattered), attenuation))
+        if shaded.material.is_light() {
+            // texture coordinates are uniform over the area of a sphere
+            let (columns, rows) = (32, 16);
+            let scale = 1.0 / (columns * rows) as f32;
+            for i in 0..columns * rows {
+                let u = ((i % columns) as f64 + 0.5) / columns as f64;
+                let v = ((i / columns) as f64 + 0.5) / rows as f64;
+                let emitted = shaded.inputs_at(u, v).and_then(|i| i.emitted());
+                let emitted = emitted.unwrap_or_else(color::black);
+                shaded.emitted = color::add(shaded.emitted, color::scale(emitted, scale));
+            }
+        }
+        Ok(shaded)
+    }
+
+    // The parameters of the material with the inputs found by the graph at
+    // texture coordinates `u` and `v`, or None if it has none.
+    fn inputs_at(&self, u: f64, v: f64) -> Option<Inputs> {
+        let mut inputs = self.inputs?;
+        self.program.evaluate(u, v, |name, value| {
+            if let Some(input) = inputs.shader_input(name) {
+                input.set(value);
+            }
+        });
+        Some(inputs)
+    }
+
+    // The material as it scatters at the hit point.
+    fn with_inputs<R>(&self, hit_record: &HitRecord, f: impl FnOnce(&dyn Scatterable) -> R) -> R {
+        match self.inputs_at(hit_record.u, hit_record.v) {
+            Some(inputs) => f(inputs.scatterable()),
+            None => f(self.material.scatterable()),
         }
     }
 }
 
+impl Scatterable for Shaded {
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        self.with_inputs(hit_record, |m| m.eval(hit_record, wi, wo))
+    }
+
+    fn sample(&self, ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        self.with_inputs(hit_record, |m| m.sample(ray, hit_record))
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> f64 {
+        self.with_inputs(hit_record, |m| m.pdf(hit_record, wi, wo))
+    }
+
+    // A graph can make a smooth material rough at some points.
+    fn is_delta(&self) -> bool {
+        self.material.is_delta() && !self.program.feeds("roughness") && !self.program.feeds("fuzz")
+    }
+}
+
 #[serde_with::serde_as]
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Texture {
@@ -253,16 +2542,26 @@ impl Texture {
     }
 }
 
+// Lambertian with the albedo read from the texture.
 impl Scatterable for Texture {
-    fn scatter(&self, _ray: &Ray, hit_record: &HitRecord) -> Option<(Option<Ray>, Srgb)> {
-        let mut scatter_direction = hit_record.normal + Point3D::random_in_unit_sphere();
-        if scatter_direction.near_zero() {
-            scatter_direction = hit_record.normal;
-        }
-        let target = hit_record.point + scatter_direction;
-        let scattered = Ray::new(hit_record.point, target - hit_record.point);
-        let attenuation = self.get_albedo(hit_record.u, hit_record.v);
-        Some((Some(scattered), attenuation))
+    fn eval(&self, hit_record: &HitRecord, wi: &Point3D, wo: &Point3D) -> Srgb {
+        let albedo = self.get_albedo(hit_record.u, hit_record.v);
+        diffuse(albedo, &hit_record.normal, wi, wo)
+    }
+
+    fn sample(&self, _ray: &Ray, hit_record: &HitRecord) -> Option<BsdfSample> {
+        let wi = cosine_direction(&hit_record.normal);
+        let pdf = cosine_pdf(&hit_record.normal, &wi);
+        let albedo = self.get_albedo(hit_record.u, hit_record.v);
+        Some(BsdfSample::new(wi, albedo, pdf))
+    }
+
+    fn pdf(&self, hit_record: &HitRecord, wi: &Point3D, _wo: &Point3D) -> f64 {
+        cosine_pdf(&hit_record.normal, wi)
+    }
+
+    fn is_delta(&self) -> bool {
+        false
     }
 }
 
@@ -281,3 +2580,897 @@ fn test_to_json() {
     let serialized = serde_json::to_string(&m).unwrap();
     assert_eq!(r#"{"albedo":[0.8,0.8,0.8],"fuzz":2.0}"#, serialized,);
 }
+
+#[test]
+fn test_dispersion() {
+    // blue light bends more than red light
+    for dispersion in [
+        Dispersion::bk7(),
+        Dispersion::sf11(),
+        Dispersion::diamond(),
+        Dispersion::Cauchy { a: 1.5, b: 0.004 },
+    ] {
+        assert!(dispersion.index_of_refraction(450.0) > dispersion.index_of_refraction(650.0));
+    }
+    assert!((Dispersion::bk7().index_of_refraction(587.6) - 1.5168).abs() < 1e-3);
+    assert!((Dispersion::diamond().index_of_refraction(587.6) - 2.417).abs() < 5e-3);
+
+    let glass = Glass::new(1.5).with_dispersion(Dispersion::bk7());
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 0.0), Point3D::new(0.0, 0.0, -1.0));
+    assert_eq!(glass.index_of_refraction_for(&ray), 1.5);
+    let ray = ray.with_wavelength(Some(450.0));
+    assert_eq!(
+        glass.index_of_refraction_for(&ray),
+        Dispersion::bk7().index_of_refraction(450.0)
+    );
+    let serialized = serde_json::to_string(
+        &Glass::new(1.5).with_dispersion(Dispersion::Cauchy { a: 1.5, b: 0.004 }),
+    )
+    .unwrap();
+    assert_eq!(
+        serialized,
+        r#"{"index_of_refraction":1.5,"dispersion":{"Cauchy":{"a":1.5,"b":0.004}}}"#
+    );
+}
+
+#[test]
+fn test_light() {
+    assert_eq!(Light::new().emitted(), Srgb::new(1.0, 1.0, 1.0));
+    let light = Light::new()
+        .with_color(Srgb::new(1.0, 0.5, 0.25))
+        .with_intensity(2.0);
+    assert_eq!(light.emitted(), Srgb::new(2.0, 1.0, 0.5));
+    // a temperature takes precedence over the color
+    let tungsten = light.with_temperature(2700.0);
+    assert_eq!(
+        tungsten.emitted(),
+        color::scale(blackbody_color(2700.0), 2.0)
+    );
+    assert!(tungsten.emitted().red > tungsten.emitted().blue);
+    assert_eq!(
+        Material::Lambertian(Lambertian::new(white())).emitted(),
+        color::black()
+    );
+
+    assert_eq!(serde_json::to_string(&Light::new()).unwrap(), "{}");
+    assert_eq!(
+        serde_json::to_string(&Light::new().with_temperature(3200.0)).unwrap(),
+        r#"{"temperature":3200.0}"#
+    );
+    let light: Light = serde_json::from_str(r#"{"color":[1.0,0.5,0.0],"intensity":4.0}"#).unwrap();
+    assert_eq!(light.emitted(), Srgb::new(4.0, 2.0, 0.0));
+}
+
+#[cfg(test)]
+fn hit_record_at_top(material: &Material) -> HitRecord<'_> {
+    HitRecord {
+        t: 1.0,
+        point: Point3D::new(0.0, 0.0, 1.0),
+        normal: Point3D::new(0.0, 0.0, 1.0),
+        front_face: true,
+        material,
+        u: 0.5,
+        v: 0.5,
+        wavelength: None,
+    }
+}
+
+#[test]
+fn test_ggx() {
+    // the visible normals are distributed as their density says
+    let ggx = Ggx::new(0.5, 0.6);
+    let wo = Point3D::new(0.6, 0.0, 0.8);
+    let samples = 200000;
+    let mut inside = 0;
+    for _ in 0..samples {
+        let h = ggx.sample_visible_normal(&wo, random_f64(), random_f64());
+        assert!((h.length() - 1.0).abs() < 1e-9);
+        if h.z() > 0.95 {
+            inside += 1;
+        }
+    }
+    // integrate the density over the same cap
+    let steps = 400;
+    let mut expected = 0.0;
+    let cos_max = 0.95_f64;
+    for i in 0..steps {
+        for j in 0..steps {
+            let cos_theta = 1.0 - (1.0 - cos_max) * (i as f64 + 0.5) / steps as f64;
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let phi = 2.0 * PI * (j as f64 + 0.5) / steps as f64;
+            let h = Point3D::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+            let solid_angle = (1.0 - cos_max) * 2.0 * PI / (steps * steps) as f64;
+            expected += ggx.visible_normal_pdf(&wo, &h) * solid_angle;
+        }
+    }
+    let frequency = inside as f64 / samples as f64;
+    assert!(
+        (frequency - expected).abs() < 0.01,
+        "{} != {}",
+        frequency,
+        expected
+    );
+}
+
+#[test]
+fn test_conductor() {
+    // gold reflects more red than blue, and everything at grazing angles
+    let gold = Conductor::gold(0.0);
+    let normal_incidence = gold.reflectance(1.0);
+    assert!(normal_incidence.red > 0.9 && normal_incidence.blue < 0.5);
+    let grazing = gold.reflectance(0.0);
+    assert!((grazing.blue - 1.0).abs() < 1e-6);
+    assert!(Conductor::aluminum(0.0).reflectance(1.0).blue > 0.9);
+    let copper = Conductor::copper(0.0).reflectance(1.0);
+    assert!(copper.red > copper.green && copper.green > copper.blue);
+
+    // a smooth conductor is a mirror
+    let material = Material::Conductor(Conductor::aluminum(0.0));
+    let hit_record = hit_record_at_top(&material);
+    let ray = Ray::new(Point3D::new(-1.0, 0.0, 2.0), Point3D::new(1.0, 0.0, -1.0));
+    let (scattered, attenuation) = material.scatter(&ray, &hit_record).unwrap();
+    let direction = scattered.unwrap().direction.unit_vector();
+    assert!((direction - Point3D::new(1.0, 0.0, 1.0).unit_vector()).length() < 1e-3);
+    let expected = Conductor::aluminum(0.0).reflectance(0.5_f64.sqrt());
+    assert!((attenuation.red - expected.red).abs() < 1e-3);
+
+    // rough ones scatter above the surface, losing little energy, and
+    // anisotropic ones spread highlights along the tangent (the x axis)
+    let material = Material::Conductor(
+        Conductor::new(Srgb::new(1.0, 1.0, 1.0), Srgb::new(1e3, 1e3, 1e3), 0.3)
+            .with_anisotropy(0.8),
+    );
+    let hit_record = hit_record_at_top(&material);
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 2.0), Point3D::new(0.0, 0.0, -1.0));
+    let samples = 20000;
+    let mut energy = 0.0;
+    let (mut spread_x, mut spread_y) = (0.0, 0.0);
+    for _ in 0..samples {
+        if let Some((Some(scattered), attenuation)) = material.scatter(&ray, &hit_record) {
+            let direction = scattered.direction.unit_vector();
+            assert!(direction.z() > 0.0);
+            assert!(attenuation.red <= 1.0 + 1e-6);
+            energy += attenuation.red as f64 / samples as f64;
+            spread_x += direction.x().abs();
+            spread_y += direction.y().abs();
+        }
+    }
+    assert!(energy > 0.9 && energy <= 1.0, "{}", energy);
+    assert!(spread_x > 1.5 * spread_y);
+
+    let serialized = serde_json::to_string(&Conductor::gold(0.3).with_anisotropy(0.5)).unwrap();
+    assert_eq!(
+        serialized,
+        r#"{"eta":[0.143,0.374,1.442],"k":[3.983,2.385,1.603],"roughness":0.3,"anisotropy":0.5}"#
+    );
+}
+
+#[test]
+fn test_rough_glass() {
+    // frosted glass spreads the refracted rays, and reflects a few percent
+    let material = Material::Glass(Glass::new(1.5).with_roughness(0.3));
+    let hit_record = hit_record_at_top(&material);
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 2.0), Point3D::new(0.0, 0.0, -1.0));
+    let samples = 20000;
+    let (mut reflected, mut transmitted, mut spread) = (0.0, 0.0, 0.0);
+    for _ in 0..samples {
+        if let Some((Some(scattered), attenuation)) = material.scatter(&ray, &hit_record) {
+            assert!(attenuation.red <= 1.0 + 1e-6);
+            let direction = scattered.direction.unit_vector();
+            match direction.z() > 0.0 {
+                true => reflected += attenuation.red as f64 / samples as f64,
+                false => transmitted += attenuation.red as f64 / samples as f64,
+            }
+            spread += direction.x().hypot(direction.y()) / samples as f64;
+        }
+    }
+    assert!(reflected > 0.02 && reflected < 0.1, "{}", reflected);
+    assert!(transmitted > 0.85, "{}", transmitted);
+    assert!(spread > 0.02, "{}", spread);
+}
+
+#[test]
+fn test_glass_absorption() {
+    let material = Material::Glass(Glass::new(1.0).with_absorption(Srgb::new(0.5, 1.0, 1.0)));
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 3.0), Point3D::new(0.0, 0.0, -1.0));
+    // entering the glass doesn't absorb anything
+    let hit_record = hit_record_at_top(&material);
+    let (_, attenuation) = material.scatter(&ray, &hit_record).unwrap();
+    assert_eq!(attenuation, Srgb::new(1.0, 1.0, 1.0));
+    // leaving it after two units of distance leaves a quarter of the red
+    let hit_record = HitRecord {
+        t: 2.0,
+        front_face: false,
+        ..hit_record_at_top(&material)
+    };
+    let (_, attenuation) = material.scatter(&ray, &hit_record).unwrap();
+    assert_eq!(attenuation, Srgb::new(0.25, 1.0, 1.0));
+
+    let serialized = serde_json::to_string(
+        &Glass::new(1.5)
+            .with_roughness(0.2)
+            .with_absorption(Srgb::new(0.5, 1.0, 1.0)),
+    )
+    .unwrap();
+    assert_eq!(
+        serialized,
+        r#"{"index_of_refraction":1.5,"roughness":0.2,"absorption":[0.5,1.0,1.0]}"#
+    );
+    let glass: Glass = serde_json::from_str(r#"{"index_of_refraction":1.5}"#).unwrap();
+    assert_eq!(glass.roughness, 0.0);
+    assert!(glass.absorption.is_none());
+}
+
+#[test]
+fn test_principled_special_cases() {
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 2.0), Point3D::new(0.3, 0.0, -1.0));
+
+    // without specular reflection, the diffuse lobe alone is a Lambertian
+    let material = Material::Principled(Principled::diffuse(Srgb::new(0.8, 0.4, 0.2)));
+    let hit_record = hit_record_at_top(&material);
+    for _ in 0..100 {
+        let (scattered, attenuation) = material.scatter(&ray, &hit_record).unwrap();
+        assert!(scattered.unwrap().direction.z() > 0.0);
+        assert!((attenuation.red - 0.8).abs() < 1e-5);
+        assert!((attenuation.blue - 0.2).abs() < 1e-5);
+    }
+
+    // a smooth metal is a mirror tinted by its base color
+    let material = Material::Principled(Principled::metal(Srgb::new(0.9, 0.6, 0.3), 0.0));
+    let hit_record = hit_record_at_top(&material);
+    let (scattered, attenuation) = material.scatter(&ray, &hit_record).unwrap();
+    let direction = scattered.unwrap().direction.unit_vector();
+    assert!((direction - Point3D::new(0.3, 0.0, 1.0).unit_vector()).length() < 1e-3);
+    assert!(attenuation.red > attenuation.green && attenuation.green > attenuation.blue);
+
+    // glass transmits most light, and reflects a few percent
+    let material = Material::Principled(Principled::glass(1.5, 0.0));
+    let hit_record = hit_record_at_top(&material);
+    let samples = 20000;
+    let (mut reflected, mut transmitted) = (0.0, 0.0);
+    for _ in 0..samples {
+        if let Some((Some(scattered), attenuation)) = material.scatter(&ray, &hit_record) {
+            match scattered.direction.z() > 0.0 {
+                true => reflected += attenuation.green as f64 / samples as f64,
+                false => transmitted += attenuation.green as f64 / samples as f64,
+            }
+        }
+    }
+    assert!((reflected - 0.04).abs() < 0.01, "{}", reflected);
+    assert!((transmitted - 0.96).abs() < 0.02, "{}", transmitted);
+}
+
+#[test]
+fn test_principled_sampling() {
+    // the average weight of sampled directions is the integral of the
+    // material over the hemisphere, whichever lobe they come from
+    let material = Principled {
+        base_color: Srgb::new(0.5, 0.3, 0.2),
+        metallic: 0.3,
+        roughness: 0.4,
+        specular: 0.5,
+        sheen: 0.5,
+        clearcoat: 1.0,
+        clearcoat_roughness: 0.2,
+        transmission: 0.0,
+        ior: 1.5,
+    };
+    let wo = Point3D::new(0.6, 0.0, 0.8);
+    let steps = 600;
+    let mut expected = 0.0;
+    for i in 0..steps {
+        for j in 0..steps {
+            let cos_theta = (i as f64 + 0.5) / steps as f64;
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let phi = 2.0 * PI * (j as f64 + 0.5) / steps as f64;
+            let wi = Point3D::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+            let (f, _) = material.eval_local(&wi, &wo);
+            expected += f[0] * 2.0 * PI / (steps * steps) as f64;
+        }
+    }
+
+    let material = Material::Principled(material);
+    let hit_record = hit_record_at_top(&material);
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 0.0), -wo);
+    let samples = 200000;
+    let mut sampled = 0.0;
+    for _ in 0..samples {
+        if let Some((Some(_), attenuation)) = material.scatter(&ray, &hit_record) {
+            sampled += attenuation.red as f64 / samples as f64;
+        }
+    }
+    assert!(
+        (sampled - expected).abs() < 0.01 * expected,
+        "{} != {}",
+        sampled,
+        expected
+    );
+    assert!(expected < 1.0);
+}
+
+#[test]
+fn test_principled_to_json() {
+    let serialized = serde_json::to_string(&Material::Principled(Principled::default())).unwrap();
+    assert_eq!(
+        serialized,
+        r#"{"Principled":{"base_color":[0.8,0.8,0.8],"metallic":0.0,"roughness":0.5,"specular":0.5,"sheen":0.0,"clearcoat":0.0,"clearcoat_roughness":0.1,"transmission":0.0,"ior":1.5}}"#
+    );
+    // missing parameters take their default values
+    let material: Material =
+        serde_json::from_str(r#"{"Principled":{"base_color":[1.0,0.0,0.0],"metallic":1.0}}"#)
+            .unwrap();
+    match material {
+        Material::Principled(p) => {
+            assert_eq!(p.base_color, Srgb::new(1.0, 0.0, 0.0));
+            assert_eq!(p.metallic, 1.0);
+            assert_eq!(p.roughness, 0.5);
+        }
+        _ => panic!("not a principled material"),
+    }
+}
+
+#[cfg(test)]
+fn hemisphere_integral(wo: &Point3D, eval: impl Fn(&Point3D, &Point3D) -> Srgb) -> f64 {
+    let wo = wo.unit_vector();
+    let steps = 600;
+    let mut integral = 0.0;
+    for i in 0..steps {
+        for j in 0..steps {
+            let cos_theta = (i as f64 + 0.5) / steps as f64;
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let phi = 2.0 * PI * (j as f64 + 0.5) / steps as f64;
+            let wi = Point3D::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+            let f = eval(&wo, &wi).red as f64;
+            integral += f * cos_theta * 2.0 * PI / (steps * steps) as f64;
+        }
+    }
+    integral
+}
+
+#[test]
+fn test_oren_nayar() {
+    let wo = Point3D::new(0.6, 0.0, 0.8);
+    let wi = Point3D::new(-0.3, 0.5, 0.5_f64.sqrt()).unit_vector();
+
+    // without roughness it is a Lambertian
+    let smooth = Material::OrenNayar(OrenNayar::new(Srgb::new(0.5, 0.5, 0.5), 0.0));
+    let hit_record = hit_record_at_top(&smooth);
+    assert!((smooth.eval(&hit_record, &wi, &wo).red as f64 - 0.5 / PI).abs() < 1e-6);
+
+    // reciprocal, and the sampled weights average to the reflected energy,
+    // which is below the albedo
+    let rough = Material::OrenNayar(OrenNayar::new(Srgb::new(0.8, 0.8, 0.8), 30.0));
+    let hit_record = hit_record_at_top(&rough);
+    let f = rough.eval(&hit_record, &wi, &wo);
+    assert!((f.red - rough.eval(&hit_record, &wo, &wi).red).abs() < 1e-6);
+    let expected = hemisphere_integral(&wo, |wo, wi| rough.eval(&hit_record, wi, wo));
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 0.0), -wo);
+    let samples = 100000;
+    let mut sampled = 0.0;
+    for _ in 0..samples {
+        let sample = rough.sample(&ray, &hit_record).unwrap();
+        assert!(sample.wi.z() >= 0.0);
+        assert!((sample.pdf - rough.pdf(&hit_record, &sample.wi, &wo)).abs() < 1e-9);
+        sampled += sample.weight.red as f64 / samples as f64;
+    }
+    assert!(
+        (sampled - expected).abs() < 0.01,
+        "{} != {}",
+        sampled,
+        expected
+    );
+    assert!(expected < 0.8);
+    // rough surfaces reflect more light back towards it
+    let back = rough.eval(&hit_record, &wo, &wo).red;
+    let forward = rough
+        .eval(&hit_record, &Point3D::new(-0.6, 0.0, 0.8), &wo)
+        .red;
+    assert!(back > forward);
+}
+
+#[test]
+fn test_sheen() {
+    let wo = Point3D::new(0.8, 0.0, 0.6);
+    let velvet = Material::Sheen(Sheen::new(
+        Srgb::new(0.1, 0.1, 0.1),
+        Srgb::new(1.0, 1.0, 1.0),
+        0.8,
+    ));
+    let hit_record = hit_record_at_top(&velvet);
+    let wi = Point3D::new(-0.3, 0.5, 0.5_f64.sqrt()).unit_vector();
+    let f = velvet.eval(&hit_record, &wi, &wo);
+    assert!((f.red - velvet.eval(&hit_record, &wo, &wi).red).abs() < 1e-6);
+
+    // the sheen brightens grazing angles, and stays below one
+    let grazing = hemisphere_integral(&Point3D::new(0.98, 0.0, 0.2), |wo, wi| {
+        velvet.eval(&hit_record, wi, wo)
+    });
+    let normal_incidence = hemisphere_integral(&hit_record.normal, |wo, wi| {
+        velvet.eval(&hit_record, wi, wo)
+    });
+    assert!(grazing > normal_incidence);
+    assert!(grazing < 1.0);
+
+    let expected = hemisphere_integral(&wo, |wo, wi| velvet.eval(&hit_record, wi, wo));
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 0.0), -wo);
+    let samples = 100000;
+    let mut sampled = 0.0;
+    for _ in 0..samples {
+        let (_, attenuation) = velvet.scatter(&ray, &hit_record).unwrap();
+        sampled += attenuation.red as f64 / samples as f64;
+    }
+    assert!(
+        (sampled - expected).abs() < 0.01,
+        "{} != {}",
+        sampled,
+        expected
+    );
+
+    let serialized = serde_json::to_string(&Material::OrenNayar(OrenNayar::new(
+        Srgb::new(0.5, 0.5, 0.5),
+        20.0,
+    )))
+    .unwrap();
+    assert_eq!(
+        serialized,
+        r#"{"OrenNayar":{"albedo":[0.5,0.5,0.5],"sigma":20.0}}"#
+    );
+}
+
+#[test]
+fn test_sample_matches_eval() {
+    // sampled weights are the BSDF times the cosine over the density, for
+    // every material which can evaluate its BSDF
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 0.0), Point3D::new(-0.6, 0.2, -0.8));
+    let wo = -ray.direction.unit_vector();
+    let color = Srgb::new(0.8, 0.5, 0.2);
+    let materials = [
+        Material::Lambertian(Lambertian::new(color)),
+        Material::OrenNayar(OrenNayar::new(color, 20.0)),
+        Material::Sheen(Sheen::new(color, white(), 0.5)),
+        Material::Conductor(Conductor::copper(0.4).with_anisotropy(0.5)),
+        Material::Principled(Principled {
+            clearcoat: 1.0,
+            transmission: 0.5,
+            ..Principled::new(color)
+        }),
+        Material::Coated(
+            Coated::new(Material::Lambertian(Lambertian::new(color)), 1.5).with_roughness(0.3),
+        ),
+        Material::Coated(
+            Coated::new(Material::OrenNayar(OrenNayar::new(color, 20.0)), 1.5)
+                .with_tint(Srgb::new(0.9, 0.6, 0.3)),
+        ),
+        Material::ThinFilm(ThinFilm::new(
+            Material::Lambertian(Lambertian::new(color)),
+            400.0,
+            1.4,
+        )),
+        Material::ThinFilm(ThinFilm::new(
+            Material::Conductor(Conductor::copper(0.4)),
+            300.0,
+            1.5,
+        )),
+        Material::Mix(Mix::new(
+            Material::Lambertian(Lambertian::new(color)),
+            Material::Conductor(Conductor::copper(0.4)),
+            MixWeight::Constant(0.3),
+        )),
+        Material::Mix(Mix::new(
+            Material::Metal(Metal::new(color, 0.1)),
+            Material::Sheen(Sheen::new(color, white(), 0.5)),
+            MixWeight::Constant(0.6),
+        )),
+        Material::Shaded(
+            Shaded::new(
+                Material::Principled(Principled::new(color)),
+                ShaderGraph::new()
+                    .with_node("rough", Node::Float(0.3))
+                    .with_input("roughness", "rough"),
+            )
+            .unwrap(),
+        ),
+        Material::Metal(Metal::new(color, 0.3)),
+        Material::Metal(Metal::new(color, 1.5)),
+        Material::Glass(Glass::new(1.5).with_roughness(0.2)),
+        Material::Glass(
+            Glass::new(1.5)
+                .with_roughness(0.2)
+                .with_dispersion(Dispersion::sf11()),
+        ),
+        Material::ThinFilm(ThinFilm::new(
+            Material::Metal(Metal::new(color, 0.1)),
+            400.0,
+            1.3,
+        )),
+    ];
+    // in spectral mode, at the wavelength of the ray
+    for (material, wavelength) in materials.iter().flat_map(|m| [(m, None), (m, Some(520.0))]) {
+        let ray = ray.with_wavelength(wavelength);
+        let hit_record = HitRecord {
+            wavelength,
+            ..hit_record_at_top(material)
+        };
+        assert!(!material.is_delta());
+        let mut evaluated = 0;
+        for _ in 0..10000 {
+            let sample = match material.sample(&ray, &hit_record) {
+                Some(sample) if !sample.delta => sample,
+                _ => continue,
+            };
+            let pdf = material.pdf(&hit_record, &sample.wi, &wo);
+            assert!((sample.pdf - pdf).abs() <= 1e-6 * pdf, "{:?}", material);
+            let cosine = sample.wi.dot(&hit_record.normal).abs();
+            let f = at_wavelength(material.eval(&hit_record, &sample.wi, &wo), wavelength);
+            let expected = f.red as f64 * cosine / pdf;
+            assert!(
+                (sample.weight.red as f64 - expected).abs() <= 1e-3 * expected.max(1.0),
+                "{:?} {:?}: {} != {}",
+                material,
+                wavelength,
+                sample.weight.red,
+                expected
+            );
+            evaluated += 1;
+        }
+        assert!(evaluated > 200, "{:?}", material);
+    }
+
+    // delta lobes are only sampled
+    for material in [
+        Material::Metal(Metal::new(color, 0.0)),
+        Material::Glass(Glass::new(1.5)),
+        Material::Conductor(Conductor::gold(0.0)),
+        Material::Coated(Coated::new(Material::Metal(Metal::new(color, 0.0)), 1.5)),
+        Material::ThinFilm(ThinFilm::new(Material::Glass(Glass::new(1.5)), 400.0, 1.3)),
+        Material::ThinFilm(ThinFilm::new(
+            Material::Metal(Metal::new(color, 0.0)),
+            400.0,
+            1.3,
+        )),
+    ] {
+        let hit_record = hit_record_at_top(&material);
+        assert!(material.is_delta());
+        let sample = material.sample(&ray, &hit_record).unwrap();
+        assert!(sample.delta);
+        assert!(color::is_black(material.eval(&hit_record, &sample.wi, &wo)));
+        assert_eq!(material.pdf(&hit_record, &sample.wi, &wo), 0.0);
+    }
+}
+
+#[test]
+fn test_coated() {
+    let white_base = Material::Lambertian(Lambertian::new(white()));
+    let varnish =
+        Material::Coated(Coated::new(white_base, 1.5).with_tint(Srgb::new(0.5, 0.5, 0.5)));
+    let hit_record = hit_record_at_top(&varnish);
+    let normal = hit_record.normal;
+
+    // at normal incidence the base is tinted once, and loses what the coat
+    // reflects on the way in and out
+    let f = varnish.eval(&hit_record, &normal, &normal);
+    assert!((f.red as f64 - 0.5 * 0.96 * 0.96 / PI).abs() < 1e-6);
+
+    // the coat reflects more light at grazing angles, and the layers
+    // together never reflect more than comes in
+    let clear = Material::Coated(Coated::new(
+        Material::Lambertian(Lambertian::new(white())),
+        1.5,
+    ));
+    let hit_record = hit_record_at_top(&clear);
+    let mut mirrored = Vec::new();
+    for wo in [
+        Point3D::new(0.0, 0.0, 1.0),
+        Point3D::new(0.98, 0.0, 0.2).unit_vector(),
+    ] {
+        let ray = Ray::new(Point3D::new(0.0, 0.0, 0.0), -wo);
+        let samples = 100000;
+        let (mut reflected, mut coat) = (0.0, 0.0);
+        for _ in 0..samples {
+            if let Some(sample) = clear.sample(&ray, &hit_record) {
+                reflected += sample.weight.red as f64 / samples as f64;
+                if sample.delta {
+                    coat += sample.weight.red as f64 / samples as f64;
+                }
+            }
+        }
+        assert!(reflected < 1.0, "{}", reflected);
+        assert!((coat - reflectance(wo.z(), 1.5)).abs() < 0.02, "{}", coat);
+        mirrored.push(coat);
+    }
+    assert!(mirrored[1] > 5.0 * mirrored[0]);
+
+    let serialized = serde_json::to_string(&clear).unwrap();
+    assert_eq!(
+        serialized,
+        r#"{"Coated":{"base":{"Lambertian":{"albedo":[1.0,1.0,1.0]}},"ior":1.5}}"#
+    );
+    let material: Material = serde_json::from_str(
+        r#"{"Coated":{"base":{"Metal":{"albedo":[0.8,0.1,0.1],"fuzz":0.0}},"ior":1.5,"roughness":0.1,"tint":[0.9,0.9,0.5]}}"#,
+    )
+    .unwrap();
+    match material {
+        Material::Coated(c) => {
+            assert!(matches!(*c.base, Material::Metal(_)));
+            assert_eq!(c.roughness, 0.1);
+            assert_eq!(c.tint, Srgb::new(0.9, 0.9, 0.5));
+        }
+        _ => panic!("not a coated material"),
+    }
+}
+
+#[test]
+fn test_subsurface() {
+    // light gets past a distance in each channel as often as its own mean
+    // free path says, and loses the absorbed part at each interaction
+    let marble = Subsurface::new(Srgb::new(0.9, 0.9, 0.5), Srgb::new(1.0, 0.5, 0.25), 1.5);
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 0.0), Point3D::new(0.0, 0.0, 2.0));
+    let t_exit = 0.25;
+    let samples = 200000;
+    let (mut transmitted, mut scattered) = ([0.0; 3], [0.0; 3]);
+    for _ in 0..samples {
+        let (t, weight) = marble.sample_distance(&ray, t_exit, white());
+        let weight = [weight.red, weight.green, weight.blue].map(|w| w as f64 / samples as f64);
+        let sums = match t {
+            Some(t) => {
+                assert!(t < t_exit);
+                &mut scattered
+            }
+            None => &mut transmitted,
+        };
+        sums.iter_mut().zip(weight).for_each(|(s, w)| *s += w);
+    }
+    for (i, mean_free_path) in [1.0_f64, 0.5, 0.25].iter().enumerate() {
+        let expected = (-0.5 / mean_free_path).exp();
+        assert!(
+            (transmitted[i] - expected).abs() < 0.01,
+            "{:?}",
+            transmitted
+        );
+        let albedo = [0.9, 0.9, 0.5][i];
+        assert!(
+            (scattered[i] - albedo * (1.0 - expected)).abs() < 0.01,
+            "{:?}",
+            scattered
+        );
+    }
+
+    let serialized = serde_json::to_string(&Material::Subsurface(marble)).unwrap();
+    assert_eq!(
+        serialized,
+        r#"{"Subsurface":{"albedo":[0.9,0.9,0.5],"mean_free_path":[1.0,0.5,0.25],"ior":1.5}}"#
+    );
+}
+
+#[test]
+fn test_thin_film() {
+    // without a film, the glass and the gold below reflect as usual
+    let glass = ThinFilm::new(Material::Glass(Glass::new(1.5)), 0.0, 1.33);
+    for cos_theta in [1.0_f64, 0.5, 0.1] {
+        let sin_squared = 1.0 - cos_theta * cos_theta;
+        let cos_t = (1.0 - sin_squared / (1.5 * 1.5)).sqrt();
+        let rs = (cos_theta - 1.5 * cos_t) / (cos_theta + 1.5 * cos_t);
+        let rp = (1.5 * cos_theta - cos_t) / (1.5 * cos_theta + cos_t);
+        let fresnel = (rs * rs + rp * rp) / 2.0;
+        assert!((glass.reflectance_at(cos_theta, 550.0) - fresnel).abs() < 1e-9);
+    }
+    let gold = Conductor::gold(0.0);
+    let film = ThinFilm::new(Material::Conductor(gold), 0.0, 1.33);
+    let color = film.reflectance_for(1.0, Some(650.0));
+    let (eta, k) = (
+        rgb_to_spectrum(gold.eta, 650.0) as f64,
+        rgb_to_spectrum(gold.k, 650.0) as f64,
+    );
+    let expected = ((eta - 1.0).powi(2) + k * k) / ((eta + 1.0).powi(2) + k * k);
+    assert!((color.red as f64 - expected).abs() < 1e-6);
+
+    // a soap bubble reflects some wavelengths much more than others, and
+    // never more than two reflections in phase
+    let bubble = ThinFilm::new(Material::Glass(Glass::new(1.0)), 500.0, 1.33);
+    let reflectances: Vec<f64> = (400..700)
+        .step_by(10)
+        .map(|lambda| bubble.reflectance_at(0.9, lambda as f64))
+        .collect();
+    let max = reflectances.iter().cloned().fold(0.0, f64::max);
+    let min = reflectances.iter().cloned().fold(1.0, f64::min);
+    assert!(max > 10.0 * min, "{} {}", min, max);
+    let r = (1.33 - 1.0) / (1.33 + 1.0);
+    assert!(
+        max < 4.2 * r * r / ((1.0 + r * r) * (1.0 + r * r)),
+        "{}",
+        max
+    );
+    let color = bubble.reflectance_for(1.0, None);
+    let channels = [color.red, color.green, color.blue];
+    let spread =
+        channels.iter().cloned().fold(0.0, f32::max) - channels.iter().cloned().fold(1.0, f32::min);
+    assert!(spread > 0.03, "{:?}", color);
+
+    // light not reflected by the film enters the glass
+    let material = Material::ThinFilm(bubble.clone());
+    let hit_record = hit_record_at_top(&material);
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 2.0), Point3D::new(0.3, 0.0, -1.0));
+    let samples = 10000;
+    let mut reflected = 0.0;
+    for _ in 0..samples {
+        let sample = material.sample(&ray, &hit_record).unwrap();
+        if sample.wi.z() > 0.0 {
+            reflected += sample.weight.green as f64 / samples as f64;
+        } else {
+            assert!((sample.wi - ray.direction.unit_vector()).length() < 1e-9);
+        }
+    }
+    let cos_theta = -ray.direction.unit_vector().z();
+    let expected = bubble.reflectance_for(cos_theta, None).green as f64;
+    assert!(
+        (reflected - expected).abs() < 0.01,
+        "{} != {}",
+        reflected,
+        expected
+    );
+
+    let serialized = serde_json::to_string(&material).unwrap();
+    assert_eq!(
+        serialized,
+        r#"{"ThinFilm":{"base":{"Glass":{"index_of_refraction":1.0}},"thickness":500.0,"ior":1.33}}"#
+    );
+}
+
+#[test]
+fn test_mix() {
+    // rust on the right half of the sphere, metal on the left
+    let mask = Mask {
+        values: vec![0.0, 1.0],
+        width: 2,
+        height: 1,
+        path: String::new(),
+    };
+    let rust = Srgb::new(0.4, 0.15, 0.05);
+    let rusty = Material::Mix(Mix::new(
+        Material::Metal(Metal::new(Srgb::new(0.9, 0.9, 0.9), 0.0)),
+        Material::Lambertian(Lambertian::new(rust)),
+        MixWeight::Mask(mask),
+    ));
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 2.0), Point3D::new(0.3, 0.0, -1.0));
+    let wo = -ray.direction.unit_vector();
+    let metal_hit = HitRecord {
+        u: 0.25,
+        ..hit_record_at_top(&rusty)
+    };
+    let rust_hit = HitRecord {
+        u: 0.75,
+        ..hit_record_at_top(&rusty)
+    };
+    assert_eq!(rusty.albedo(&rust_hit), rust);
+    for _ in 0..100 {
+        assert!(rusty.sample(&ray, &metal_hit).unwrap().delta);
+        let sample = rusty.sample(&ray, &rust_hit).unwrap();
+        assert!(!sample.delta);
+        let f = rusty.eval(&rust_hit, &sample.wi, &wo);
+        assert!((f.red as f64 - 0.4 / PI).abs() < 1e-6);
+        assert!(color::is_black(rusty.eval(&metal_hit, &sample.wi, &wo)));
+    }
+
+    // the weight is a number, or the path of a mask image
+    let half = Material::Mix(Mix::new(
+        Material::Lambertian(Lambertian::new(white())),
+        Material::Metal(Metal::new(white(), 0.0)),
+        MixWeight::Constant(0.5),
+    ));
+    assert_eq!(
+        serde_json::to_string(&half).unwrap(),
+        r#"{"Mix":{"base":{"Lambertian":{"albedo":[1.0,1.0,1.0]}},"top":{"Metal":{"albedo":[1.0,1.0,1.0],"fuzz":0.0}},"weight":0.5}}"#
+    );
+    let json = r#"{"Mix":{"base":{"Lambertian":{"albedo":[1.0,1.0,1.0]}},"top":{"Metal":{"albedo":[1.0,1.0,1.0],"fuzz":0.0}},"weight":"data/moon.jpg"}}"#;
+    let masked: Material = serde_json::from_str(json).unwrap();
+    match &masked {
+        Material::Mix(Mix {
+            weight: MixWeight::Mask(mask),
+            ..
+        }) => {
+            assert!(mask.width > 0 && mask.height > 0);
+            assert!(mask.values.iter().all(|v| (0.0..=1.0).contains(v)));
+        }
+        _ => panic!("not a masked mix"),
+    }
+    assert_eq!(serde_json::to_string(&masked).unwrap(), json);
+}
+
+#[test]
+fn test_shaded() {
+    // a ramp from red to blue over noise
+    let graph = ShaderGraph::new()
+        .with_node(
+            "noise",
+            Node::Noise {
+                uv: None,
+                scale: 4.0,
+                octaves: 1,
+            },
+        )
+        .with_node(
+            "ramp",
+            Node::Ramp {
+                input: "noise".to_string(),
+                stops: vec![(0.0, [1.0, 0.0, 0.0]), (1.0, [0.0, 0.0, 1.0])],
+            },
+        );
+    let shaded = Material::Shaded(
+        Shaded::new(
+            Material::Lambertian(Lambertian::new(white())),
+            graph.clone().with_input("albedo", "ramp"),
+        )
+        .unwrap(),
+    );
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 2.0), Point3D::new(0.3, 0.0, -1.0));
+    let wo = -ray.direction.unit_vector();
+    let mut reds = 0;
+    for i in 0..20 {
+        let hit_record = HitRecord {
+            u: i as f64 / 20.0,
+            ..hit_record_at_top(&shaded)
+        };
+        let albedo = shaded.albedo(&hit_record);
+        assert!((albedo.red + albedo.blue - 1.0).abs() < 1e-6);
+        assert_eq!(albedo.green, 0.0);
+        if albedo.red > albedo.blue {
+            reds += 1;
+        }
+        // the material behaves as a Lambertian with that albedo
+        let sample = shaded.sample(&ray, &hit_record).unwrap();
+        assert!((sample.weight.red - albedo.red).abs() < 1e-6);
+        let f = shaded.eval(&hit_record, &sample.wi, &wo);
+        assert!((f.blue as f64 - albedo.blue as f64 / PI).abs() < 1e-6);
+    }
+    assert!(reds > 0 && reds < 20);
+
+    // inputs must exist on the material, with the right kind
+    let fuzz = graph.clone().with_input("fuzz", "noise");
+    let lambertian = Material::Lambertian(Lambertian::new(white()));
+    assert!(Shaded::new(lambertian, fuzz.clone()).is_err());
+    assert!(Shaded::new(Material::Metal(Metal::new(white(), 0.0)), fuzz).is_ok());
+    let roughness = graph.clone().with_input("roughness", "ramp");
+    assert!(Shaded::new(Material::Principled(Principled::new(white())), roughness).is_err());
+
+    // materials made of other materials have no inputs, and are shaded
+    // through the materials inside them
+    let lambertian = Material::Lambertian(Lambertian::new(white()));
+    let albedo = graph.clone().with_input("albedo", "ramp");
+    let error = Shaded::new(Material::Coated(Coated::new(lambertian, 1.5)), albedo).err();
+    assert_eq!(
+        error.map(|e| e.to_string()),
+        Some("`Coated` has no input `albedo`".to_string())
+    );
+    let coated = Material::Coated(Coated::new(shaded.clone(), 1.5));
+    for i in 0..20 {
+        let hit_record = HitRecord {
+            u: i as f64 / 20.0,
+            ..hit_record_at_top(&coated)
+        };
+        assert_eq!(coated.albedo(&hit_record), shaded.albedo(&hit_record));
+    }
+
+    // lights glow with their graph, and are sampled by their average
+    let light = Material::Shaded(
+        Shaded::new(
+            Material::Light(Light::new().with_intensity(2.0)),
+            graph.clone().with_input("color", "ramp"),
+        )
+        .unwrap(),
+    );
+    assert!(light.is_light() && !shaded.is_light());
+    let hit_record = hit_record_at_top(&light);
+    let emitted = light.emission(&hit_record).unwrap();
+    assert!((emitted.red + emitted.blue - 2.0).abs() < 1e-6);
+    let average = light.emitted();
+    assert!(average.red > 0.2 && average.blue > 0.2);
+    assert!((average.red + average.blue - 2.0).abs() < 1e-4);
+    assert!(shaded.emission(&hit_record).is_none());
+
+    // the graph is compiled when read from JSON, and written back as given
+    let json = r#"{"Shaded":{"material":{"Metal":{"albedo":[1.0,1.0,1.0],"fuzz":0.0}},"graph":{"nodes":{"n":{"Noise":{"scale":2.0,"octaves":3}}},"inputs":{"fuzz":"n"}}}}"#;
+    let metal: Material = serde_json::from_str(json).unwrap();
+    assert_eq!(serde_json::to_string(&metal).unwrap(), json);
+    let json = json.replace(r#""fuzz":"n""#, r#""fuzz":"m""#);
+    assert!(serde_json::from_str::<Material>(&json).is_err());
+}
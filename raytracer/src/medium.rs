@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+use crate::point3d::Point3D;
+use crate::ray::Ray;
+
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+// Scene-wide participating medium filling the space between objects.
+// Coefficients are per unit of distance at height zero; with a height
+// falloff the density decays as exp(-height_falloff * y) above that and
+// grows below it.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Fog {
+    pub absorption: f64,
+    pub scattering: f64,
+    #[serde(default)]
+    pub height_falloff: f64,
+    // Henyey-Greenstein asymmetry: 0 scatters uniformly, positive values
+    // mostly forward
+    #[serde(default)]
+    pub anisotropy: f64,
+}
+
+impl Fog {
+    pub fn new(absorption: f64, scattering: f64, height_falloff: f64, anisotropy: f64) -> Fog {
+        Fog {
+            absorption,
+            scattering,
+            height_falloff,
+            anisotropy,
+        }
+    }
+
+    fn extinction(&self) -> f64 {
+        self.absorption + self.scattering
+    }
+
+    // Fraction of the extinction that is scattered rather than absorbed.
+    pub fn albedo(&self) -> f64 {
+        match self.extinction() {
+            x if x > 0.0 => self.scattering / x,
+            _ => 0.0,
+        }
+    }
+
+    // Extinction integrated along the ray up to the parameter `t`, which
+    // may be infinite.
+    fn optical_depth(&self, ray: &Ray, t: f64) -> f64 {
+        let speed = ray.direction.length();
+        let distance = t * speed;
+        let sigma = self.extinction() * (-self.height_falloff * ray.origin.y()).exp();
+        let k = self.height_falloff * ray.direction.y() / speed;
+        if sigma == 0.0 {
+            return 0.0;
+        }
+        if k.abs() < 1e-9 {
+            return sigma * distance;
+        }
+        if distance.is_infinite() {
+            return match k > 0.0 {
+                true => sigma / k,
+                false => f64::INFINITY,
+            };
+        }
+        sigma * (1.0 - (-k * distance).exp()) / k
+    }
+
+    // Fraction of light passing along the ray up to the parameter `t`.
+    pub fn transmittance(&self, ray: &Ray, t: f64) -> f64 {
+        (-self.optical_depth(ray, t)).exp()
+    }
+
+    // Samples the ray parameter at which light traveling along the ray
+    // interacts with the medium, proportionally to extinction times
+    // transmittance. Returns None if the interaction would happen beyond
+    // `t_max`, which has a probability equal to the transmittance up to it.
+    pub fn sample_distance(&self, ray: &Ray, t_max: f64, u: f64) -> Option<f64> {
+        let speed = ray.direction.length();
+        let sigma = self.extinction() * (-self.height_falloff * ray.origin.y()).exp();
+        if sigma == 0.0 {
+            return None;
+        }
+        let k = self.height_falloff * ray.direction.y() / speed;
+        let depth = -(1.0 - u).ln();
+        let distance = if k.abs() < 1e-9 {
+            depth / sigma
+        } else {
+            let a = depth * k / sigma;
+            if a >= 1.0 {
+                return None;
+            }
+            -(1.0 - a).ln() / k
+        };
+        let t = distance / speed;
+        match t < t_max {
+            true => Some(t),
+            false => None,
+        }
+    }
+
+    pub fn phase_function(&self) -> HenyeyGreenstein {
+        HenyeyGreenstein::new(self.anisotropy)
+    }
+
+    pub fn phase(&self, cos_theta: f64) -> f64 {
+        self.phase_function().phase(cos_theta)
+    }
+
+    pub fn sample_phase(&self, direction: &Point3D, u1: f64, u2: f64) -> Point3D {
+        self.phase_function().sample(direction, u1, u2)
+    }
+}
+
+// Henyey-Greenstein phase function, with its asymmetry: 0 scatters
+// uniformly, positive values mostly forward.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HenyeyGreenstein {
+    pub g: f64,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f64) -> HenyeyGreenstein {
+        HenyeyGreenstein { g }
+    }
+
+    // Density of the angle between the direction of travel and the
+    // scattered direction.
+    pub fn phase(&self, cos_theta: f64) -> f64 {
+        let g = self.g;
+        let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+    }
+
+    // Samples a scattered direction for light traveling along `direction`,
+    // proportionally to the phase function.
+    pub fn sample(&self, direction: &Point3D, u1: f64, u2: f64) -> Point3D {
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u1
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u1);
+            (1.0 + g * g - s * s) / (2.0 * g)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        let w = direction.unit_vector();
+        let (u, v) = w.perpendicular_basis();
+        u * (sin_theta * phi.cos()) + v * (sin_theta * phi.sin()) + w * cos_theta
+    }
+}
+
+#[test]
+fn test_fog_transmittance() {
+    let fog = Fog::new(0.1, 0.1, 0.0, 0.0);
+    // the direction's length does not change the distance traveled
+    let r = Ray::new(Point3D::new(0.0, 0.0, 0.0), Point3D::new(2.0, 0.0, 0.0));
+    assert_approx_eq!(fog.transmittance(&r, 5.0), (-2.0f64).exp());
+    assert_eq!(fog.transmittance(&r, f64::INFINITY), 0.0);
+    assert_eq!(fog.albedo(), 0.5);
+
+    // with a height falloff, the density integrates to a finite amount
+    // above any height
+    let fog = Fog::new(0.5, 0.0, 2.0, 0.0);
+    let up = Ray::new(Point3D::new(0.0, 1.0, 0.0), Point3D::new(0.0, 1.0, 0.0));
+    assert_approx_eq!(
+        fog.transmittance(&up, f64::INFINITY),
+        (-0.25 * (-2.0f64).exp()).exp()
+    );
+    let slanted = Ray::new(Point3D::new(0.0, 1.0, 0.0), Point3D::new(3.0, -1.0, 0.0));
+    let steps = 10000;
+    let mut depth = 0.0;
+    for i in 0..steps {
+        let p = slanted.at((i as f64 + 0.5) / steps as f64);
+        depth += 0.5 * (-2.0 * p.y()).exp() * slanted.direction.length() / steps as f64;
+    }
+    assert_approx_eq!(fog.transmittance(&slanted, 1.0), (-depth).exp(), 1e-6);
+}
+
+#[test]
+fn test_fog_sample_distance() {
+    let fog = Fog::new(0.2, 0.3, 0.7, 0.0);
+    let r = Ray::new(Point3D::new(0.0, 0.5, 0.0), Point3D::new(1.0, -0.5, 0.0));
+    for u in [0.1, 0.5, 0.9] {
+        let t = fog.sample_distance(&r, f64::INFINITY, u).unwrap();
+        // the sampled distance has the optical depth -ln(1 - u)
+        assert_approx_eq!(fog.transmittance(&r, t), 1.0 - u);
+    }
+    assert_eq!(fog.sample_distance(&r, 0.01, 0.9), None);
+
+    // the probability of passing t_max is the transmittance up to it
+    let samples = 20000;
+    let passed = (0..samples)
+        .filter(|_| fog.sample_distance(&r, 2.0, rand::random()).is_none())
+        .count();
+    assert_approx_eq!(
+        passed as f64 / samples as f64,
+        fog.transmittance(&r, 2.0),
+        0.02
+    );
+}
+
+#[test]
+fn test_fog_phase() {
+    for g in [0.0, 0.5, -0.3] {
+        let fog = Fog::new(0.0, 1.0, 0.0, g);
+        // integrates to one over the sphere
+        let steps = 1000;
+        let integral: f64 = (0..steps)
+            .map(|i| {
+                let cos_theta = -1.0 + 2.0 * (i as f64 + 0.5) / steps as f64;
+                fog.phase(cos_theta) * 2.0 * PI * 2.0 / steps as f64
+            })
+            .sum();
+        assert_approx_eq!(integral, 1.0, 1e-3);
+
+        // the mean cosine of sampled directions is the asymmetry
+        let direction = Point3D::new(0.0, 0.0, 1.0);
+        let samples = 20000;
+        let mean: f64 = (0..samples)
+            .map(|_| {
+                fog.sample_phase(&direction, rand::random(), rand::random())
+                    .z()
+            })
+            .sum::<f64>()
+            / samples as f64;
+        assert_approx_eq!(mean, g, 0.02);
+    }
+}
@@ -0,0 +1,277 @@
+use palette::Srgb;
+use rand::Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::color;
+use crate::config::Config;
+use crate::integrator::Integrator;
+use crate::integrator::PathTracer;
+use crate::ray::Ray;
+use crate::sampler::random_f64;
+use crate::sampler::with_primary_samples;
+use crate::sampler::PrimarySamples;
+use crate::sphere::Sphere;
+
+#[cfg(test)]
+use crate::camera::Camera;
+#[cfg(test)]
+use crate::config::Sky;
+#[cfg(test)]
+use crate::integrator::IntegratorKind;
+#[cfg(test)]
+use crate::materials::Lambertian;
+#[cfg(test)]
+use crate::materials::Light;
+#[cfg(test)]
+use crate::materials::Material;
+#[cfg(test)]
+use crate::point3d::Point3D;
+
+// Primary sample space Metropolis light transport (Kelemen et al. 2002).
+//
+// Each path is the path tracer's estimate for a point in primary sample
+// space: the first two numbers pick the image position, the remaining ones
+// are consumed by the materials while scattering. Markov chains mutate
+// these numbers with either a fresh uniform sample (large step) or a small
+// gaussian perturbation, and are distributed according to the luminance of
+// the paths. The overall brightness is recovered from `bootstrap_samples`
+// independent paths. In total `samples_per_pixel` mutations per pixel are
+// spread over `chains` chains.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Metropolis {
+    pub bootstrap_samples: usize,
+    pub chains: usize,
+    pub large_step_probability: f64,
+    pub sigma: f64,
+}
+
+impl Metropolis {
+    pub fn new(
+        bootstrap_samples: usize,
+        chains: usize,
+        large_step_probability: f64,
+        sigma: f64,
+    ) -> Metropolis {
+        Metropolis {
+            bootstrap_samples,
+            chains,
+            large_step_probability,
+            sigma,
+        }
+    }
+}
+
+struct PathSample {
+    pixel: usize,
+    color: Srgb,
+}
+
+fn evaluate(
+    scene: &Config,
+    lights: &[Sphere],
+    samples: PrimarySamples,
+) -> (PathSample, PrimarySamples) {
+    with_primary_samples(samples, || {
+        let x = random_f64() * scene.width as f64;
+        let y = random_f64() * scene.height as f64;
+        let u = x / (scene.width as f64 - 1.0);
+        let v = (scene.height as f64 - y) / (scene.height as f64 - 1.0);
+        let ray = scene.camera.get_ray(u, v);
+        let pixel_x = (x as usize).min(scene.width - 1);
+        let pixel_y = (y as usize).min(scene.height - 1);
+        PathSample {
+            pixel: pixel_y * scene.width + pixel_x,
+            color: PathTracer::without_light_rays().ray_color(&ray, scene, lights),
+        }
+    })
+}
+
+fn splat(film: &mut [Srgb], sample: &PathSample, weight: f32) {
+    film[sample.pixel] = color::add(film[sample.pixel], color::scale(sample.color, weight));
+}
+
+impl Metropolis {
+    fn run_chain(
+        &self,
+        scene: &Config,
+        lights: &[Sphere],
+        start: &[f64],
+        mutations: usize,
+    ) -> Vec<Srgb> {
+        let mut rng = rand::thread_rng();
+        let mut film = vec![color::black(); scene.width * scene.height];
+        let (mut current, mut samples) = evaluate(
+            scene,
+            lights,
+            PrimarySamples::from_values(start, self.sigma),
+        );
+        for _ in 0..mutations {
+            let large_step = rng.gen::<f64>() < self.large_step_probability;
+            samples.start_iteration(large_step);
+            let (proposed, mutated) = evaluate(scene, lights, samples);
+            samples = mutated;
+
+            let current_luminance = color::luminance(current.color) as f64;
+            let proposed_luminance = color::luminance(proposed.color) as f64;
+            let accept = if current_luminance > 0.0 {
+                (proposed_luminance / current_luminance).min(1.0)
+            } else {
+                1.0
+            };
+            // expected values: both states contribute by their probability
+            if accept > 0.0 {
+                splat(&mut film, &proposed, (accept / proposed_luminance) as f32);
+            }
+            if current_luminance > 0.0 {
+                splat(
+                    &mut film,
+                    &current,
+                    ((1.0 - accept) / current_luminance) as f32,
+                );
+            }
+
+            if rng.gen::<f64>() < accept {
+                current = proposed;
+                samples.accept();
+            } else {
+                samples.reject();
+            }
+        }
+        film
+    }
+}
+
+impl Integrator for Metropolis {
+    // Single rays fall back to the path tracer used to evaluate paths.
+    fn ray_color(&self, ray: &Ray, scene: &Config, lights: &[Sphere]) -> Srgb {
+        PathTracer::without_light_rays().ray_color(ray, scene, lights)
+    }
+
+    fn render_frame(&self, scene: &Config, lights: &[Sphere]) -> Option<Vec<Srgb>> {
+        let pixel_count = scene.width * scene.height;
+
+        // bootstrap: normalization constant and starting points of the chains
+        let bootstrap: Vec<(f64, Vec<f64>)> = (0..self.bootstrap_samples)
+            .into_par_iter()
+            .map(|_| {
+                let (sample, samples) = evaluate(scene, lights, PrimarySamples::new(self.sigma));
+                (color::luminance(sample.color) as f64, samples.values())
+            })
+            .collect();
+        let mut cdf = Vec::with_capacity(bootstrap.len());
+        let mut total = 0.0;
+        for (luminance, _) in &bootstrap {
+            total += luminance;
+            cdf.push(total);
+        }
+        if total <= 0.0 {
+            return Some(vec![color::black(); pixel_count]);
+        }
+        let b = total / bootstrap.len() as f64;
+
+        let chains = self.chains.max(1);
+        let mutations = scene.samples_per_pixel as usize * pixel_count / chains;
+        let film = (0..chains)
+            .into_par_iter()
+            .map(|_| {
+                let target = rand::thread_rng().gen::<f64>() * total;
+                let index = cdf.partition_point(|c| *c <= target).min(cdf.len() - 1);
+                self.run_chain(scene, lights, &bootstrap[index].1, mutations)
+            })
+            .reduce(
+                || vec![color::black(); pixel_count],
+                |a, b| {
+                    a.iter()
+                        .zip(b.iter())
+                        .map(|(x, y)| color::add(*x, *y))
+                        .collect()
+                },
+            );
+
+        let scale = (b * pixel_count as f64 / (mutations * chains).max(1) as f64) as f32;
+        Some(film.iter().map(|c| color::scale(*c, scale)).collect())
+    }
+}
+
+#[cfg(test)]
+fn test_scene() -> Config {
+    Config {
+        width: 8,
+        height: 6,
+        samples_per_pixel: 256,
+        max_depth: 4,
+        spectral: false,
+        sky: Some(Sky::new_default_sky()),
+        fog: None,
+        volumes: Vec::new(),
+        camera: Camera::new(
+            Point3D::new(0.0, 1.0, 3.0),
+            Point3D::new(0.0, 0.0, 0.0),
+            Point3D::new(0.0, 1.0, 0.0),
+            60.0,
+            8.0 / 6.0,
+        ),
+        integrator: IntegratorKind::Metropolis(Metropolis::new(10000, 8, 0.3, 0.01)),
+        objects: vec![
+            Sphere::new(
+                Point3D::new(0.0, -1000.0, 0.0),
+                1000.0,
+                Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+            ),
+            Sphere::new(
+                Point3D::new(0.0, 0.5, 0.0),
+                0.5,
+                Material::Light(Light::new()),
+            ),
+        ],
+    }
+}
+
+#[test]
+fn test_metropolis_matches_path_tracer_brightness() {
+    let scene = test_scene();
+    let lights: Vec<Sphere> = vec![scene.objects[1].clone()];
+    let params = Metropolis::new(20000, 8, 0.3, 0.01);
+    let frame = params.render_frame(&scene, &lights).unwrap();
+    assert_eq!(frame.len(), 48);
+    let mlt_mean: f32 = frame.iter().map(|c| color::luminance(*c)).sum::<f32>() / 48.0;
+
+    let mut rng = rand::thread_rng();
+    let samples = 50000;
+    let mut path_mean = 0.0;
+    for _ in 0..samples {
+        // same image plane mapping as the render loop
+        let u = rng.gen::<f64>() * 8.0 / 7.0;
+        let v = rng.gen::<f64>() * 6.0 / 5.0;
+        let r = scene.camera.get_ray(u, v);
+        path_mean += color::luminance(params.ray_color(&r, &scene, &lights));
+    }
+    path_mean /= samples as f32;
+    assert!(
+        (mlt_mean - path_mean).abs() < 0.05 * path_mean,
+        "{} != {}",
+        mlt_mean,
+        path_mean
+    );
+}
+
+#[test]
+fn test_metropolis_black_scene() {
+    let mut scene = test_scene();
+    scene.sky = None;
+    scene.objects.pop();
+    let params = Metropolis::new(100, 2, 0.3, 0.01);
+    let frame = params.render_frame(&scene, &[]).unwrap();
+    assert!(frame.iter().all(|c| color::is_black(*c)));
+}
+
+#[test]
+fn test_metropolis_to_json() {
+    let m = IntegratorKind::Metropolis(Metropolis::new(1000, 4, 0.3, 0.01));
+    let serialized = serde_json::to_string(&m).unwrap();
+    assert_eq!(
+        serialized,
+        "{\"Metropolis\":{\"bootstrap_samples\":1000,\"chains\":4,\"large_step_probability\":0.3,\"sigma\":0.01}}"
+    );
+}
@@ -0,0 +1,456 @@
+use palette::Srgb;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+
+use crate::color;
+use crate::config::Config;
+use crate::integrator::sky_color;
+use crate::integrator::Integrator;
+use crate::materials::Scatterable;
+use crate::point3d::Point3D;
+use crate::ray::HitRecord;
+use crate::ray::Ray;
+use crate::raytracer::hit_world;
+use crate::sphere::Sphere;
+
+#[cfg(test)]
+use crate::camera::Camera;
+#[cfg(test)]
+use crate::integrator::region_colors;
+#[cfg(test)]
+use crate::integrator::IntegratorKind;
+#[cfg(test)]
+use crate::integrator::PathTracer;
+#[cfg(test)]
+use crate::materials::Glass;
+#[cfg(test)]
+use crate::materials::Lambertian;
+#[cfg(test)]
+use crate::materials::Light;
+#[cfg(test)]
+use crate::materials::Material;
+#[cfg(test)]
+use crate::materials::{Conductor, OrenNayar};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Photon {
+    pub point: Point3D,
+    // unit vector pointing back towards where the photon came from
+    pub incoming: Point3D,
+    pub power: Srgb,
+}
+
+// Photons stored in a balanced kd-tree: the median of each range is the
+// node, and the halves before and after it are its subtrees.
+#[derive(Debug, Clone)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    // split axis of the node stored at the same index
+    axes: Vec<usize>,
+}
+
+fn axis_value(p: &Point3D, axis: usize) -> f64 {
+    match axis {
+        0 => p.x(),
+        1 => p.y(),
+        _ => p.z(),
+    }
+}
+
+impl PhotonMap {
+    pub fn new(photons: Vec<Photon>) -> PhotonMap {
+        let mut photons = photons;
+        let mut axes = vec![0; photons.len()];
+        PhotonMap::build(&mut photons, &mut axes);
+        PhotonMap { photons, axes }
+    }
+
+    fn build(photons: &mut [Photon], axes: &mut [usize]) {
+        if photons.len() <= 1 {
+            return;
+        }
+        // split along the axis with the largest extent
+        let mut min = [f64::MAX; 3];
+        let mut max = [f64::MIN; 3];
+        for photon in photons.iter() {
+            for axis in 0..3 {
+                let v = axis_value(&photon.point, axis);
+                min[axis] = min[axis].min(v);
+                max[axis] = max[axis].max(v);
+            }
+        }
+        let axis = (0..3)
+            .max_by(|a, b| {
+                (max[*a] - min[*a])
+                    .partial_cmp(&(max[*b] - min[*b]))
+                    .unwrap_or(Ordering::Equal)
+            })
+            .unwrap_or(0);
+
+        let median = photons.len() / 2;
+        photons.select_nth_unstable_by(median, |a, b| {
+            axis_value(&a.point, axis)
+                .partial_cmp(&axis_value(&b.point, axis))
+                .unwrap_or(Ordering::Equal)
+        });
+        axes[median] = axis;
+        let (left, right) = photons.split_at_mut(median);
+        let (left_axes, right_axes) = axes.split_at_mut(median);
+        PhotonMap::build(left, left_axes);
+        PhotonMap::build(&mut right[1..], &mut right_axes[1..]);
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    // Calls `f` for every photon within `radius` of `point`.
+    pub fn for_each_in_radius<F: FnMut(&Photon)>(&self, point: &Point3D, radius: f64, f: &mut F) {
+        self.visit(0, self.photons.len(), point, radius * radius, f);
+    }
+
+    fn visit<F: FnMut(&Photon)>(
+        &self,
+        start: usize,
+        end: usize,
+        point: &Point3D,
+        radius_squared: f64,
+        f: &mut F,
+    ) {
+        if start >= end {
+            return;
+        }
+        let median = start + (end - start) / 2;
+        let photon = &self.photons[median];
+        if (photon.point - *point).length_squared() <= radius_squared {
+            f(photon);
+        }
+        if end - start == 1 {
+            return;
+        }
+        let axis = self.axes[median];
+        let delta = axis_value(point, axis) - axis_value(&photon.point, axis);
+        if delta <= 0.0 || delta * delta <= radius_squared {
+            self.visit(start, median, point, radius_squared, f);
+        }
+        if delta >= 0.0 || delta * delta <= radius_squared {
+            self.visit(median + 1, end, point, radius_squared, f);
+        }
+    }
+}
+
+// Parameters of the photon mapping integrator.
+//
+// `photons` are emitted from the lights before each frame and only those
+// which reach a surface through delta bounces (off smooth metal, through
+// smooth glass) are kept, i.e. the caustic photon map. They are stored on
+// every surface whose BSDF isn't made of delta lobes only, where the
+// caustic radiance is estimated from the photons within `radius` of a hit
+// point. Everything else is path traced, including light from the sky.
+//
+// Fog, volumes, spectral rendering and subsurface scattering aren't
+// supported, so `IntegratorKind::build` refuses scenes with any of them.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct PhotonMapping {
+    pub photons: usize,
+    pub radius: f64,
+}
+
+impl PhotonMapping {
+    pub fn new(photons: usize, radius: f64) -> PhotonMapping {
+        PhotonMapping { photons, radius }
+    }
+}
+
+pub fn trace_caustic_photons(scene: &Config, lights: &[Sphere], count: usize) -> Vec<Photon> {
+    let mut rng = rand::thread_rng();
+    let mut photons = Vec::new();
+    if lights.is_empty() || count == 0 {
+        return photons;
+    }
+    for _ in 0..count {
+        // uniform point on a uniformly chosen light, cosine weighted
+        // direction
+        let light = &lights[rng.gen_range(0..lights.len())];
+        let normal = Point3D::random_unit_vector();
+        let mut direction = normal + Point3D::random_unit_vector();
+        if direction.near_zero() {
+            direction = normal;
+        }
+        let flux = PI * 4.0 * PI * light.radius * light.radius * lights.len() as f64;
+        let emitted = light
+            .material
+            .emission(&light.surface_hit(normal))
+            .unwrap_or_else(color::black);
+        let mut power = color::scale(emitted, (flux / count as f64) as f32);
+        let mut ray = Ray::new(light.center + normal * light.radius, direction);
+
+        // the camera ray towards the photon is one more ray of the path
+        let mut specular = false;
+        for _ in 1..scene.max_depth {
+            let hit_record = match hit_world(&scene.objects, &ray, 0.001, f64::MAX) {
+                Some(hit_record) => hit_record,
+                None => break,
+            };
+            if hit_record.material.is_light() {
+                break;
+            }
+            if specular && !hit_record.material.is_delta() {
+                photons.push(Photon {
+                    point: hit_record.point,
+                    incoming: (-ray.direction).unit_vector(),
+                    power,
+                });
+            }
+            // photons scattered by any other lobe light the scene
+            // indirectly, which is path traced
+            match hit_record.material.sample(&ray, &hit_record) {
+                Some(sample) if sample.delta => {
+                    power = color::mul(power, sample.weight);
+                    ray = Ray::new(hit_record.point, sample.wi);
+                    specular = true;
+                }
+                _ => break,
+            }
+        }
+    }
+    photons
+}
+
+// Path tracer which takes caustics (light reaching a surface through
+// delta bounces only) from a photon map instead of tracing them.
+pub struct PhotonMapIntegrator {
+    map: PhotonMap,
+    radius: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PathState {
+    // only delta bounces so far
+    Camera,
+    // the last bounce was sampled from the lobes `eval` covers
+    Surface,
+    // only delta bounces since the last other one: a light reached now
+    // belongs to a caustic
+    Caustic,
+}
+
+impl PhotonMapIntegrator {
+    pub fn new(params: &PhotonMapping, scene: &Config, lights: &[Sphere]) -> PhotonMapIntegrator {
+        let photons = trace_caustic_photons(scene, lights, params.photons);
+        PhotonMapIntegrator {
+            map: PhotonMap::new(photons),
+            radius: params.radius,
+        }
+    }
+
+    // Radiance from the caustic photons around a hit, reflected or
+    // transmitted towards `wo`.
+    fn caustic(&self, hit_record: &HitRecord, wo: &Point3D) -> Srgb {
+        let mut radiance = color::black();
+        self.map
+            .for_each_in_radius(&hit_record.point, self.radius, &mut |photon: &Photon| {
+                let f = hit_record.material.eval(hit_record, &photon.incoming, wo);
+                radiance = color::add(radiance, color::mul(f, photon.power));
+            });
+        let area = PI * self.radius * self.radius;
+        color::scale(radiance, (1.0 / area) as f32)
+    }
+
+    fn trace(&self, ray: &Ray, scene: &Config, state: PathState, depth: usize) -> Srgb {
+        if depth == 0 {
+            return color::black();
+        }
+        let hit_record = match hit_world(&scene.objects, ray, 0.001, f64::MAX) {
+            Some(hit_record) => hit_record,
+            None => return sky_color(ray, scene),
+        };
+        if let Some(emitted) = hit_record.material.emission(&hit_record) {
+            return match state {
+                PathState::Caustic => color::black(),
+                _ => emitted,
+            };
+        }
+        let caustic = match hit_record.material.is_delta() {
+            true => color::black(),
+            false => self.caustic(&hit_record, &(-ray.direction).unit_vector()),
+        };
+        let sample = match hit_record.material.sample(ray, &hit_record) {
+            Some(sample) => sample,
+            None => return caustic,
+        };
+        let next = match (sample.delta, state) {
+            (false, _) => PathState::Surface,
+            (true, PathState::Camera) => PathState::Camera,
+            (true, _) => PathState::Caustic,
+        };
+        let scattered = Ray::new(hit_record.point, sample.wi);
+        let incoming = self.trace(&scattered, scene, next, depth - 1);
+        color::add(caustic, color::mul(sample.weight, incoming))
+    }
+}
+
+impl Integrator for PhotonMapIntegrator {
+    fn ray_color(&self, ray: &Ray, scene: &Config, _lights: &[Sphere]) -> Srgb {
+        self.trace(ray, scene, PathState::Camera, scene.max_depth)
+    }
+}
+
+#[test]
+fn test_photon_map_radius_query() {
+    let mut rng = rand::thread_rng();
+    let photons: Vec<Photon> = (0..1000)
+        .map(|_| Photon {
+            point: Point3D::random(-1.0, 1.0),
+            incoming: Point3D::new(0.0, 1.0, 0.0),
+            power: Srgb::new(1.0, 1.0, 1.0),
+        })
+        .collect();
+    let map = PhotonMap::new(photons.clone());
+    assert_eq!(map.len(), 1000);
+    for _ in 0..20 {
+        let point = Point3D::random(-1.0, 1.0);
+        let radius = rng.gen_range(0.0..0.5);
+        let mut found = 0;
+        map.for_each_in_radius(&point, radius, &mut |_: &Photon| found += 1);
+        let expected = photons
+            .iter()
+            .filter(|p| (p.point - point).length() <= radius)
+            .count();
+        assert_eq!(found, expected);
+    }
+}
+
+#[cfg(test)]
+fn caustic_scene() -> (Config, Vec<Sphere>) {
+    let light = Sphere::new(
+        Point3D::new(0.0, 4.0, 0.0),
+        0.5,
+        Material::Light(Light::new()),
+    );
+    let scene = Config {
+        width: 10,
+        height: 10,
+        samples_per_pixel: 1,
+        max_depth: 8,
+        spectral: false,
+        sky: None,
+        fog: None,
+        volumes: Vec::new(),
+        camera: Camera::new(
+            Point3D::new(0.0, 2.0, 3.0),
+            Point3D::new(0.0, 0.0, 0.0),
+            Point3D::new(0.0, 1.0, 0.0),
+            60.0,
+            1.0,
+        ),
+        integrator: IntegratorKind::PhotonMapping(PhotonMapping::new(1000, 0.1)),
+        objects: vec![
+            Sphere::new(
+                Point3D::new(0.0, -1000.0, 0.0),
+                1000.0,
+                Material::Lambertian(Lambertian::new(Srgb::new(0.5, 0.5, 0.5))),
+            ),
+            Sphere::new(
+                Point3D::new(0.0, 1.0, 0.0),
+                0.5,
+                Material::Glass(Glass::new(1.5)),
+            ),
+            light.clone(),
+        ],
+    };
+    (scene, vec![light])
+}
+
+#[test]
+fn test_trace_caustic_photons() {
+    let (scene, lights) = caustic_scene();
+    let photons = trace_caustic_photons(&scene, &lights, 20000);
+    assert!(!photons.is_empty());
+    // every stored photon went through or off the glass sphere onto the
+    // floor, and the refracted ones are focused below it
+    for photon in &photons {
+        assert!(photon.point.y() < 1e-3);
+        assert!(photon.incoming.y() > 0.0);
+    }
+    let focused = photons
+        .iter()
+        .filter(|p| p.point.x().abs() < 1.0 && p.point.z().abs() < 1.0)
+        .count();
+    assert!(focused > photons.len() / 2);
+
+    // without a specular object there are no caustics
+    let mut scene = scene;
+    scene.objects.remove(1);
+    assert!(trace_caustic_photons(&scene, &lights, 20000).is_empty());
+}
+
+#[test]
+fn test_photon_map_caustic_brighter_than_surroundings() {
+    let (scene, lights) = caustic_scene();
+    let integrator = PhotonMapIntegrator::new(&PhotonMapping::new(100000, 0.05), &scene, &lights);
+    // the glass sphere focuses light below its center
+    let caustic = |x: f64| {
+        let r = Ray::new(Point3D::new(x, 0.2, 0.0), Point3D::new(0.0, -1.0, 0.0));
+        let hit_record = hit_world(&scene.objects, &r, 0.001, f64::MAX).unwrap();
+        integrator.caustic(&hit_record, &Point3D::new(0.0, 1.0, 0.0))
+    };
+    let focus = caustic(0.0);
+    let outside = caustic(2.0);
+    // a stray photon reflected off the glass may still land outside
+    assert!(focus.red > 0.0);
+    assert!(focus.red > 10.0 * outside.red);
+}
+
+#[test]
+fn test_photon_map_matches_path_tracer() {
+    // the glass sphere over a rough diffuse floor, between a glossy sphere
+    // and a diffuse one floating above the floor: caustics land on all of
+    // them, and the bottom of the floating sphere is lit by the floor only
+    let (mut scene, _) = caustic_scene();
+    scene.objects[0].material = Material::OrenNayar(OrenNayar::new(Srgb::new(0.8, 0.8, 0.8), 20.0));
+    // a larger light keeps the path tracer's noise down
+    scene.objects[2] = Sphere::new(
+        Point3D::new(0.0, 5.0, 0.0),
+        1.5,
+        Material::Light(Light::new()),
+    );
+    let lights = vec![scene.objects[2].clone()];
+    scene.objects.push(Sphere::new(
+        Point3D::new(1.2, 0.5, 0.3),
+        0.5,
+        Material::Conductor(Conductor::gold(0.3)),
+    ));
+    scene.objects.push(Sphere::new(
+        Point3D::new(-1.2, 1.2, 0.3),
+        0.5,
+        Material::Lambertian(Lambertian::new(Srgb::new(0.8, 0.8, 0.8))),
+    ));
+    let integrator = PhotonMapIntegrator::new(&PhotonMapping::new(400000, 0.02), &scene, &lights);
+    let similar = |a: Srgb, b: Srgb| (a.red - b.red).abs() < 0.1 * a.red;
+
+    let expected = region_colors(&scene, &PathTracer::new(), 3, 3, 200000);
+    let actual = region_colors(&scene, &integrator, 3, 3, 200000);
+    for (e, a) in expected.iter().zip(&actual) {
+        assert!(similar(*e, *a), "{:?} != {:?}", e, a);
+    }
+    // the bottom of the floating sphere
+    let r = Ray::new(Point3D::new(-1.2, 0.2, 0.3), Point3D::new(0.0, 1.0, 0.0));
+    let samples = 100000;
+    let mut expected = color::black();
+    let mut actual = color::black();
+    for _ in 0..samples {
+        let scale = 1.0 / samples as f32;
+        let color = PathTracer::new().ray_color(&r, &scene, &lights);
+        expected = color::add(expected, color::scale(color, scale));
+        let color = integrator.ray_color(&r, &scene, &lights);
+        actual = color::add(actual, color::scale(color, scale));
+    }
+    assert!(similar(expected, actual), "{:?} != {:?}", expected, actual);
+}
@@ -1,9 +1,10 @@
-use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
 use std::f64;
 use std::ops::{Add, Div, Mul, Neg, Sub};
 
+use crate::sampler::random_f64;
+
 #[cfg(test)]
 use assert_approx_eq::assert_approx_eq;
 
@@ -20,11 +21,10 @@ impl Point3D {
     }
 
     pub fn random(min: f64, max: f64) -> Point3D {
-        let mut rng = rand::thread_rng();
         Point3D::new(
-            rng.gen_range(min..max),
-            rng.gen_range(min..max),
-            rng.gen_range(min..max),
+            min + (max - min) * random_f64(),
+            min + (max - min) * random_f64(),
+            min + (max - min) * random_f64(),
         )
     }
 
@@ -37,6 +37,10 @@ impl Point3D {
         }
     }
 
+    pub fn random_unit_vector() -> Point3D {
+        Point3D::random_in_unit_sphere().unit_vector()
+    }
+
     pub fn x(&self) -> f64 {
         self.x
     }
@@ -81,6 +85,16 @@ impl Point3D {
         )
     }
 
+    // Two unit vectors perpendicular to this unit vector and to each other.
+    pub fn perpendicular_basis(&self) -> (Point3D, Point3D) {
+        let a = match self.x.abs() > 0.9 {
+            true => Point3D::new(0.0, 1.0, 0.0),
+            false => Point3D::new(1.0, 0.0, 0.0),
+        };
+        let v = self.cross(&a).unit_vector();
+        (self.cross(&v), v)
+    }
+
     pub fn near_zero(&self) -> bool {
         self.x.abs() < f64::EPSILON && self.y.abs() < f64::EPSILON && self.z.abs() < f64::EPSILON
     }
@@ -282,3 +296,20 @@ fn test_near_zero() {
     let p = Point3D::new(0.0, 0.0, 0.0);
     assert!(p.near_zero());
 }
+
+#[test]
+fn test_perpendicular_basis() {
+    let w = Point3D::new(1.0, 2.0, 3.0).unit_vector();
+    let (u, v) = w.perpendicular_basis();
+    assert_approx_eq!(u.length(), 1.0);
+    assert_approx_eq!(v.length(), 1.0);
+    assert_approx_eq!(u.dot(&v), 0.0);
+    assert_approx_eq!(u.dot(&w), 0.0);
+    assert_approx_eq!(v.dot(&w), 0.0);
+}
+
+#[test]
+fn test_random_unit_vector() {
+    let p = Point3D::random_unit_vector();
+    assert_approx_eq!(p.length(), 1.0);
+}
@@ -8,11 +8,21 @@ use assert_approx_eq::assert_approx_eq;
 pub struct Ray {
     pub origin: Point3D,
     pub direction: Point3D,
+    // Wavelength in nanometers carried by the ray in spectral mode.
+    pub wavelength: Option<f64>,
 }
 
 impl Ray {
     pub fn new(origin: Point3D, direction: Point3D) -> Ray {
-        Ray { origin, direction }
+        Ray {
+            origin,
+            direction,
+            wavelength: None,
+        }
+    }
+
+    pub fn with_wavelength(self, wavelength: Option<f64>) -> Ray {
+        Ray { wavelength, ..self }
     }
 
     pub fn at(&self, t: f64) -> Point3D {
@@ -20,6 +30,7 @@ impl Ray {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct HitRecord<'material> {
     pub t: f64,
     pub point: Point3D,
@@ -28,6 +39,8 @@ pub struct HitRecord<'material> {
     pub material: &'material Material,
     pub u: f64,
     pub v: f64,
+    // Wavelength of the ray which hit, in spectral mode.
+    pub wavelength: Option<f64>,
 }
 
 pub trait Hittable {
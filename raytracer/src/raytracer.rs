@@ -10,11 +10,11 @@ use std::time::Instant;
 
 use crate::config::Config;
 use crate::imgui_image::ImguiImage;
-use crate::materials::Material;
-use crate::materials::Scatterable;
+use crate::integrator::Integrator;
 use crate::ray::HitRecord;
 use crate::ray::Hittable;
 use crate::ray::Ray;
+use crate::spectrum::sample_ray_color;
 use crate::sphere::Sphere;
 
 #[cfg(test)]
@@ -23,13 +23,18 @@ use std::fs;
 #[cfg(test)]
 use crate::point3d::Point3D;
 
+#[cfg(test)]
+use crate::aov::Aov;
 #[cfg(test)]
 use crate::camera::Camera;
 #[cfg(test)]
-use crate::config::Sky;
+use crate::integrator::IntegratorKind;
+
 #[cfg(test)]
 use crate::materials::Lambertian;
 #[cfg(test)]
+use crate::materials::Material;
+#[cfg(test)]
 use crate::materials::Light;
 
 pub struct ImguiRender {
@@ -96,21 +101,34 @@ impl ImguiRender {
         renderer: &mut imgui_wgpu::Renderer,
         scene: &Config,
     ) {
-        // NOTE: multi threading
         let mut image_data = Vec::new();
         image_data.resize(4 * self.image_width * self.image_height, 0);
-        let bands: Vec<(usize, &mut [u8])> = image_data
-            .chunks_mut(self.image_width * 4)
-            .enumerate()
-            .collect();
 
         let lights = find_lights(&scene.objects);
 
         let start = Instant::now();
-        // NOTE: draw vertical band
-        bands.into_par_iter().for_each(|(i, band_pixels)| {
-            render_line_rgba(band_pixels, &scene, &lights, i);
-        });
+        // keep showing the last frame rather than a wrong one
+        let integrator = match scene.integrator.build(scene, &lights) {
+            Ok(integrator) => integrator,
+            Err(e) => {
+                println!("Can't render the scene: {}", e);
+                return;
+            }
+        };
+        match integrator.render_frame(scene, &lights) {
+            Some(frame) => write_frame(&mut image_data, &frame, scene, 4),
+            None => {
+                // NOTE: multi threading
+                let bands: Vec<(usize, &mut [u8])> = image_data
+                    .chunks_mut(self.image_width * 4)
+                    .enumerate()
+                    .collect();
+                // NOTE: draw vertical band
+                bands.into_par_iter().for_each(|(i, band_pixels)| {
+                    render_line_rgba(band_pixels, &scene, integrator.as_ref(), &lights, i);
+                });
+            }
+        }
 
         println!("Frame time: {}ms", start.elapsed().as_millis());
 
@@ -132,157 +150,38 @@ fn write_image(
     Ok(())
 }
 
-fn hit_world<'material>(
+pub fn hit_world<'material>(
     world: &'material Vec<Sphere>,
     r: &Ray,
     t_min: f64,
     t_max: f64,
 ) -> Option<HitRecord<'material>> {
+    hit_world_object(world, r, t_min, t_max).map(|(_, hit)| hit)
+}
+
+// Same as `hit_world`, but also returns the index of the object that was hit.
+pub fn hit_world_object<'material>(
+    world: &'material [Sphere],
+    r: &Ray,
+    t_min: f64,
+    t_max: f64,
+) -> Option<(usize, HitRecord<'material>)> {
     let mut closest_so_far = t_max;
     let mut hit_record = None;
-    for sphere in world {
+    for (index, sphere) in world.iter().enumerate() {
         if let Some(hit) = sphere.hit(r, t_min, closest_so_far) {
             closest_so_far = hit.t;
-            hit_record = Some(hit);
+            hit_record = Some((index, hit));
         }
     }
     hit_record
 }
 
-fn clamp(value: f32) -> f32 {
-    if value < 0.0 {
-        0.0
-    } else if value > 1.0 {
-        1.0
-    } else {
-        value
-    }
-}
-
-fn ray_color(
-    ray: &Ray,
-    scene: &Config,
-    lights: &Vec<Sphere>,
-    max_depth: usize,
-    depth: usize,
-) -> Srgb {
-    let mut rng = rand::thread_rng();
-
-    if depth <= 0 {
-        return Srgb::new(0.0, 0.0, 0.0);
-    }
-    let hit = hit_world(&scene.objects, ray, 0.001, std::f64::MAX);
-    match hit {
-        Some(hit_record) => {
-            let scattered = hit_record.material.scatter(ray, &hit_record);
-            match scattered {
-                Some((scattered_ray, albedo)) => {
-                    let mut light_red = 0.0;
-                    let mut light_green = 0.0;
-                    let mut light_blue = 0.0;
-                    let mut prob = 0.1;
-                    match hit_record.material {
-                        Material::Glass(_) => {
-                            prob = 0.05;
-                        }
-                        _ => {}
-                    }
-                    if lights.len() > 0
-                        && rng.gen::<f64>() > (1.0 - lights.len() as f64 * prob)
-                        && depth > (max_depth - 2)
-                    {
-                        for light in lights {
-                            let light_ray =
-                                Ray::new(hit_record.point, light.center - hit_record.point);
-                            let target_color = ray_color(&light_ray, scene, lights, 2, 1);
-                            light_red += albedo.red * target_color.red;
-                            light_green += albedo.green * target_color.green;
-                            light_blue += albedo.blue * target_color.blue;
-                        }
-                        light_red /= lights.len() as f32;
-                        light_green /= lights.len() as f32;
-                        light_blue /= lights.len() as f32;
-                    }
-                    match scattered_ray {
-                        Some(sr) => {
-                            let target_color = ray_color(&sr, scene, lights, max_depth, depth - 1);
-                            return Srgb::new(
-                                clamp(light_red + albedo.red * target_color.red),
-                                clamp(light_green + albedo.green * target_color.green),
-                                clamp(light_blue + albedo.blue * target_color.blue),
-                            );
-                        }
-                        None => albedo,
-                    }
-                }
-                None => {
-                    // don't bother bouncing absorbed rays towards lights
-                    // (they would be absorbed in the opposite direction).
-                    return Srgb::new(0.0, 0.0, 0.0);
-                }
-            }
-        }
-        None => {
-            let t: f32 = clamp(0.5 * (ray.direction.unit_vector().y() as f32 + 1.0));
-            let u: f32 = clamp(0.5 * (ray.direction.unit_vector().x() as f32 + 1.0));
-            match &scene.sky {
-                None => {
-                    return Srgb::new(0.0, 0.0, 0.0);
-                }
-                Some(sky) => match &sky.texture {
-                    None => {
-                        return Srgb::new(
-                            (1.0 - t) * 1.0 + t * 0.5,
-                            (1.0 - t) * 1.0 + t * 0.7,
-                            (1.0 - t) * 1.0 + t * 1.0,
-                        );
-                    }
-                    Some((pixels, width, height, _)) => {
-                        let x = (u * (*width - 1) as f32) as usize;
-                        let y = ((1.0 - t) * (*height - 1) as f32) as usize;
-                        let pixel_red = &pixels[(y * *width + x) * 3];
-                        let pixel_green = &pixels[(y * *width + x) * 3 + 1];
-                        let pixel_blue = &pixels[(y * *width + x) * 3 + 2];
-                        return Srgb::new(
-                            0.7 * *pixel_red as f32 / 255.0,
-                            0.7 * *pixel_green as f32 / 255.0,
-                            0.7 * *pixel_blue as f32 / 255.0,
-                        );
-                    }
-                },
-            }
-        }
-    }
-}
-
-#[test]
-fn test_ray_color() {
-    let p = Point3D::new(0.0, 0.0, 0.0);
-    let q = Point3D::new(1.0, 0.0, 0.0);
-    let r = Ray::new(p, q);
-    let scene = Config {
-        width: 80,
-        height: 60,
-        samples_per_pixel: 1,
-        max_depth: 2,
-        sky: Some(Sky::new_default_sky()),
-        camera: Camera::new(
-            Point3D::new(0.0, 0.0, -3.0),
-            Point3D::new(0.0, 0.0, 0.0),
-            Point3D::new(0.0, 1.0, 0.0),
-            20.0,
-            1.333,
-        ),
-        objects: Vec::new(),
-    };
-    let l = Vec::new();
-    assert_eq!(ray_color(&r, &scene, &l, 2, 2), Srgb::new(0.75, 0.85, 1.0));
-}
-
 fn render_line_rgba(
     pixels: &mut [u8],
     scene: &Config,
-    lights: &Vec<Sphere>,
+    integrator: &dyn Integrator,
+    lights: &[Sphere],
     y: usize,
 ) {
     let mut rng = rand::thread_rng();
@@ -296,18 +195,22 @@ fn render_line_rgba(
             let u = (x as f64 + rng.gen::<f64>()) / (bounds.0 as f64 - 1.0);
             let v = (bounds.1 as f64 - (y as f64 + rng.gen::<f64>())) / (bounds.1 as f64 - 1.0);
             let r = scene.camera.get_ray(u, v);
-            let c = ray_color(&r, scene, lights, scene.max_depth, scene.max_depth);
+            let c = sample_ray_color(integrator, &r, scene, lights);
             pixel_colors[0] += c.red;
             pixel_colors[1] += c.green;
             pixel_colors[2] += c.blue;
         }
-        // gamma correction
         let scale = 1.0 / scene.samples_per_pixel as f32;
-        let color = Srgb::new(
-            (scale * pixel_colors[0]).sqrt(),
-            (scale * pixel_colors[1]).sqrt(),
-            (scale * pixel_colors[2]).sqrt(),
+        let color = white_balance(
+            scene,
+            Srgb::new(
+                scale * pixel_colors[0],
+                scale * pixel_colors[1],
+                scale * pixel_colors[2],
+            ),
         );
+        // gamma correction
+        let color = Srgb::new(color.red.sqrt(), color.green.sqrt(), color.blue.sqrt());
         let pixel: [u8; 3] = color.into_format().into_raw();
         // note: write to band pixel stride
         pixels[x * 4] = pixel[0];
@@ -320,7 +223,8 @@ fn render_line_rgba(
 fn render_line_rgb(
     pixels: &mut [u8],
     scene: &Config,
-    lights: &Vec<Sphere>,
+    integrator: &dyn Integrator,
+    lights: &[Sphere],
     y: usize,
 ) {
     let mut rng = rand::thread_rng();
@@ -334,18 +238,22 @@ fn render_line_rgb(
             let u = (x as f64 + rng.gen::<f64>()) / (bounds.0 as f64 - 1.0);
             let v = (bounds.1 as f64 - (y as f64 + rng.gen::<f64>())) / (bounds.1 as f64 - 1.0);
             let r = scene.camera.get_ray(u, v);
-            let c = ray_color(&r, scene, lights, scene.max_depth, scene.max_depth);
+            let c = sample_ray_color(integrator, &r, scene, lights);
             pixel_colors[0] += c.red;
             pixel_colors[1] += c.green;
             pixel_colors[2] += c.blue;
         }
-        // gamma correction
         let scale = 1.0 / scene.samples_per_pixel as f32;
-        let color = Srgb::new(
-            (scale * pixel_colors[0]).sqrt(),
-            (scale * pixel_colors[1]).sqrt(),
-            (scale * pixel_colors[2]).sqrt(),
+        let color = white_balance(
+            scene,
+            Srgb::new(
+                scale * pixel_colors[0],
+                scale * pixel_colors[1],
+                scale * pixel_colors[2],
+            ),
         );
+        // gamma correction
+        let color = Srgb::new(color.red.sqrt(), color.green.sqrt(), color.blue.sqrt());
         let pixel: [u8; 3] = color.into_format().into_raw();
         // note: write to band pixel stride
         pixels[x * 3] = pixel[0];
@@ -354,13 +262,43 @@ fn render_line_rgb(
     }
 }
 
+// The camera's white balance applies to images of light only.
+fn white_balance(
+    scene: &Config,
+    color: Srgb,
+) -> Srgb {
+    match scene.integrator.renders_light() {
+        true => scene.camera.white_balance(color),
+        false => color,
+    }
+}
+
+// Writes the linear colors of a whole frame into 8 bit pixels with either
+// 3 (RGB) or 4 (RGBA) channels.
+fn write_frame(
+    pixels: &mut [u8],
+    frame: &[Srgb],
+    scene: &Config,
+    channels: usize,
+) {
+    for (i, c) in frame.iter().enumerate() {
+        let c = white_balance(scene, *c);
+        // gamma correction
+        let color = Srgb::new(c.red.sqrt(), c.green.sqrt(), c.blue.sqrt());
+        let pixel: [u8; 3] = color.into_format().into_raw();
+        pixels[i * channels] = pixel[0];
+        pixels[i * channels + 1] = pixel[1];
+        pixels[i * channels + 2] = pixel[2];
+        if channels == 4 {
+            pixels[i * channels + 3] = 255; // alpha
+        }
+    }
+}
+
 fn find_lights(world: &Vec<Sphere>) -> Vec<Sphere> {
     world
         .iter()
-        .filter(|s| match s.material {
-            Material::Light(_) => true,
-            _ => false,
-        })
+        .filter(|s| s.material.is_light())
         .cloned()
         .collect()
 }
@@ -384,6 +322,46 @@ fn test_find_lights() {
     assert_eq!(find_lights(&world).len(), 1);
 }
 
+#[test]
+fn test_render_aov_values() {
+    // debug views come out the same with a white balance and in spectral
+    // mode: a camera inside a sphere sees its albedo everywhere
+    let mut scene = Config {
+        width: 4,
+        height: 2,
+        samples_per_pixel: 4,
+        max_depth: 2,
+        spectral: false,
+        sky: None,
+        fog: None,
+        volumes: Vec::new(),
+        camera: Camera::new(
+            Point3D::new(0.0, 0.0, 0.0),
+            Point3D::new(0.0, 0.0, -1.0),
+            Point3D::new(0.0, 1.0, 0.0),
+            90.0,
+            2.0,
+        ),
+        integrator: IntegratorKind::Aov(Aov::Albedo),
+        objects: vec![Sphere::new(
+            Point3D::new(0.0, 0.0, 0.0),
+            100.0,
+            Material::Lambertian(Lambertian::new(Srgb::new(0.8, 0.4, 0.2))),
+        )],
+    };
+    let mut expected = vec![0; 4 * 3];
+    render_line_rgb(&mut expected, &scene, &Aov::Albedo, &[], 0);
+    assert_eq!(&expected[..3], &[204, 102, 51]);
+
+    scene.spectral = true;
+    scene.camera = scene.camera.with_white_balance(3200.0);
+    let mut pixels = vec![0; 4 * 3];
+    render_line_rgb(&mut pixels, &scene, &Aov::Albedo, &[], 0);
+    assert_eq!(pixels, expected);
+    write_frame(&mut pixels, &[Srgb::new(0.64, 0.16, 0.04)], &scene, 3);
+    assert_eq!(pixels, expected);
+}
+
 pub fn render(
     filename: &str,
     scene: Config,
@@ -392,16 +370,26 @@ pub fn render(
     let image_height = scene.height;
 
     let mut pixels = vec![0; image_width * image_height * 3];
-    // NOTE: multi threading
-    let bands: Vec<(usize, &mut [u8])> = pixels.chunks_mut(image_width * 3).enumerate().collect();
 
     let lights = find_lights(&scene.objects);
 
     let start = Instant::now();
-    // NOTE: draw vertical band
-    bands.into_par_iter().for_each(|(i, band)| {
-        render_line_rgb(band, &scene, &lights, i);
-    });
+    let integrator = scene
+        .integrator
+        .build(&scene, &lights)
+        .expect("error building the integrator");
+    match integrator.render_frame(&scene, &lights) {
+        Some(frame) => write_frame(&mut pixels, &frame, &scene, 3),
+        None => {
+            // NOTE: multi threading
+            let bands: Vec<(usize, &mut [u8])> =
+                pixels.chunks_mut(image_width * 3).enumerate().collect();
+            // NOTE: draw vertical band
+            bands.into_par_iter().for_each(|(i, band)| {
+                render_line_rgb(band, &scene, integrator.as_ref(), &lights, i);
+            });
+        }
+    }
 
     println!("Frame time: {}ms", start.elapsed().as_millis());
     write_image(filename, &pixels, (image_width, image_height)).expect("error writing image");
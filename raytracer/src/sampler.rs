@@ -0,0 +1,187 @@
+use rand::Rng;
+use std::cell::RefCell;
+
+// Source of the uniform random numbers used while tracing a path. By
+// default these come straight from the thread's RNG; Metropolis light
+// transport installs a `PrimarySamples` vector instead, so that the same
+// path can be replayed and mutated in primary sample space.
+thread_local! {
+    static PRIMARY_SAMPLES: RefCell<Option<PrimarySamples>> = const { RefCell::new(None) };
+}
+
+// Uniform random number in [0, 1).
+pub fn random_f64() -> f64 {
+    PRIMARY_SAMPLES.with(|cell| match cell.borrow_mut().as_mut() {
+        Some(samples) => samples.next_sample(),
+        None => rand::thread_rng().gen::<f64>(),
+    })
+}
+
+// Runs `f` with every call to `random_f64` on this thread drawing from
+// `samples`, and hands `samples` back afterwards.
+pub fn with_primary_samples<T, F: FnOnce() -> T>(
+    samples: PrimarySamples,
+    f: F,
+) -> (T, PrimarySamples) {
+    PRIMARY_SAMPLES.with(|cell| *cell.borrow_mut() = Some(samples));
+    let result = f();
+    let samples = PRIMARY_SAMPLES.with(|cell| cell.borrow_mut().take());
+    (
+        result,
+        samples.expect("primary samples were removed while in use"),
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PrimarySample {
+    value: f64,
+    // iteration at which `value` was last mutated
+    last_modified: u64,
+    backup_value: f64,
+    backup_modified: u64,
+}
+
+// Lazily mutated vector of primary samples (Kelemen et al. 2002). Samples
+// are only mutated when they are used, catching up on the small steps they
+// missed since their last use.
+#[derive(Debug, Clone)]
+pub struct PrimarySamples {
+    samples: Vec<PrimarySample>,
+    iteration: u64,
+    last_large_step: u64,
+    large_step: bool,
+    index: usize,
+    sigma: f64,
+}
+
+fn standard_normal() -> f64 {
+    // Box-Muller transform
+    let mut rng = rand::thread_rng();
+    let u1: f64 = 1.0 - rng.gen::<f64>();
+    let u2: f64 = rng.gen::<f64>();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+impl PrimarySamples {
+    pub fn new(sigma: f64) -> PrimarySamples {
+        PrimarySamples {
+            samples: Vec::new(),
+            iteration: 0,
+            last_large_step: 0,
+            large_step: true,
+            index: 0,
+            sigma,
+        }
+    }
+
+    // Starts a chain at previously recorded sample values, which are
+    // replayed unchanged until the first call to `start_iteration`.
+    pub fn from_values(values: &[f64], sigma: f64) -> PrimarySamples {
+        let mut samples = PrimarySamples::new(sigma);
+        samples.large_step = false;
+        samples.samples = values
+            .iter()
+            .map(|value| PrimarySample {
+                value: *value,
+                last_modified: 0,
+                backup_value: *value,
+                backup_modified: 0,
+            })
+            .collect();
+        samples
+    }
+
+    pub fn values(&self) -> Vec<f64> {
+        self.samples.iter().map(|s| s.value).collect()
+    }
+
+    pub fn start_iteration(&mut self, large_step: bool) {
+        self.iteration += 1;
+        self.large_step = large_step;
+        self.index = 0;
+    }
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    pub fn reject(&mut self) {
+        for sample in self.samples.iter_mut() {
+            if sample.last_modified == self.iteration {
+                sample.value = sample.backup_value;
+                sample.last_modified = sample.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    pub fn next_sample(&mut self) -> f64 {
+        let mut rng = rand::thread_rng();
+        if self.index >= self.samples.len() {
+            self.samples.push(PrimarySample {
+                value: rng.gen::<f64>(),
+                last_modified: 0,
+                backup_value: 0.0,
+                backup_modified: 0,
+            });
+        }
+        let sample = &mut self.samples[self.index];
+        self.index += 1;
+
+        // a large step happened since this sample was last used
+        if sample.last_modified < self.last_large_step {
+            sample.value = rng.gen::<f64>();
+            sample.last_modified = self.last_large_step;
+        }
+
+        sample.backup_value = sample.value;
+        sample.backup_modified = sample.last_modified;
+        if self.large_step {
+            sample.value = rng.gen::<f64>();
+        } else {
+            let small_steps = (self.iteration - sample.last_modified) as f64;
+            sample.value += standard_normal() * self.sigma * small_steps.sqrt();
+            sample.value -= sample.value.floor();
+        }
+        sample.last_modified = self.iteration;
+        sample.value
+    }
+}
+
+#[test]
+fn test_random_f64_without_primary_samples() {
+    for _ in 0..100 {
+        let x = random_f64();
+        assert!((0.0..1.0).contains(&x));
+    }
+}
+
+#[test]
+fn test_primary_samples_reject_restores_values() {
+    let mut samples = PrimarySamples::new(0.01);
+    samples.start_iteration(true);
+    let first: Vec<f64> = (0..4).map(|_| samples.next_sample()).collect();
+    samples.accept();
+
+    samples.start_iteration(false);
+    let mutated: Vec<f64> = (0..4).map(|_| samples.next_sample()).collect();
+    for (a, b) in first.iter().zip(mutated.iter()) {
+        // small steps wrap around [0, 1)
+        let distance = (a - b).abs().min(1.0 - (a - b).abs());
+        assert!(distance < 0.1);
+    }
+    samples.reject();
+    assert_eq!(samples.values(), first);
+}
+
+#[test]
+fn test_with_primary_samples_replays_values() {
+    let samples = PrimarySamples::from_values(&[0.25, 0.5], 0.01);
+    let (drawn, samples) =
+        with_primary_samples(samples, || (random_f64(), random_f64(), random_f64()));
+    assert_eq!((drawn.0, drawn.1), (0.25, 0.5));
+    assert!((0.0..1.0).contains(&drawn.2));
+    assert_eq!(samples.values().len(), 3);
+}
@@ -0,0 +1,727 @@
+use jpeg_decoder::Decoder;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use thiserror::Error;
+
+// Shader graphs: named nodes computing values over the surface of an object
+// from its texture coordinates, feeding the inputs of a material. A graph is
+// checked and compiled once, when the scene is loaded, into a list of
+// operations in dependency order, each writing one register, which is run
+// at every hit.
+
+// Graphs are evaluated in registers on the stack, so their size is bounded.
+pub const MAX_NODES: usize = 64;
+
+// Finer octaves of noise than this are below the precision of the
+// coordinates anyway.
+pub const MAX_OCTAVES: u32 = 24;
+
+// Type of the value of a node. Numbers are stored as grays, so they can be
+// used wherever a color is expected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Float,
+    Color,
+    Uv,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Node {
+    Float(f64),
+    Color([f32; 3]),
+    // Texture coordinates scaled, rotated by `rotation` degrees, then
+    // offset. Coordinates outside the unit square repeat the images.
+    Transform {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uv: Option<String>,
+        #[serde(default = "unit_scale")]
+        scale: [f64; 2],
+        #[serde(default)]
+        offset: [f64; 2],
+        #[serde(default)]
+        rotation: f64,
+    },
+    // Color of a JPEG image.
+    Image {
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uv: Option<String>,
+    },
+    // Fractal value noise between 0 and 1, with `scale` cells across the
+    // unit square and `octaves` layers of finer detail. It repeats like the
+    // images, so it wraps around the seam of a sphere.
+    Noise {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uv: Option<String>,
+        #[serde(default = "one")]
+        scale: f64,
+        #[serde(default = "one_octave")]
+        octaves: u32,
+    },
+    // Per channel arithmetic on numbers and colors.
+    Math {
+        op: MathOp,
+        a: Operand,
+        b: Operand,
+    },
+    // Color interpolated between stops, given as positions and colors.
+    Ramp {
+        input: String,
+        stops: Vec<(f64, [f32; 3])>,
+    },
+}
+
+fn unit_scale() -> [f64; 2] {
+    [1.0, 1.0]
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+fn one_octave() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum MathOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Minimum,
+    Maximum,
+    Power,
+}
+
+// Argument of a math node: a number, or the name of a node.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Operand {
+    Constant(f64),
+    Node(String),
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ShaderGraph {
+    pub nodes: BTreeMap<String, Node>,
+    // Inputs of the material, by name, and the nodes feeding them.
+    pub inputs: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Error)]
+pub enum ShaderError {
+    #[error("unknown node `{0}`")]
+    UnknownNode(String),
+    #[error("node `{0}` depends on itself")]
+    Cycle(String),
+    #[error("`{node}` expects {expected:?} but `{input}` is {found:?}")]
+    Mismatch {
+        node: String,
+        input: String,
+        expected: Kind,
+        found: Kind,
+    },
+    #[error("`{0}` has no input `{1}`")]
+    UnknownInput(&'static str, String),
+    #[error("ramp `{0}` has no stops")]
+    EmptyRamp(String),
+    #[error("noise `{0}` has more than {MAX_OCTAVES} octaves")]
+    TooManyOctaves(String),
+    #[error("more than {MAX_NODES} nodes")]
+    TooManyNodes,
+    #[error("image `{0}`: {1}")]
+    Image(String, io::Error),
+}
+
+impl ShaderGraph {
+    pub fn new() -> ShaderGraph {
+        ShaderGraph::default()
+    }
+
+    pub fn with_node(mut self, name: &str, node: Node) -> ShaderGraph {
+        self.nodes.insert(name.to_string(), node);
+        self
+    }
+
+    pub fn with_input(mut self, input: &str, node: &str) -> ShaderGraph {
+        self.inputs.insert(input.to_string(), node.to_string());
+        self
+    }
+
+    // Checks the graph against the kinds of the inputs of `material` and
+    // compiles it. Nodes which feed no input are left out.
+    pub fn compile(
+        &self,
+        material: &'static str,
+        mut input_kind: impl FnMut(&str) -> Option<Kind>,
+    ) -> Result<Program, ShaderError> {
+        let mut compiler = Compiler {
+            graph: self,
+            compiled: HashMap::new(),
+            visiting: HashSet::new(),
+            ops: Vec::new(),
+        };
+        let mut outputs = Vec::new();
+        for (input, node) in &self.inputs {
+            let expected = input_kind(input)
+                .ok_or_else(|| ShaderError::UnknownInput(material, input.clone()))?;
+            let (register, found) = compiler.visit(node)?;
+            check(input, node, expected, found)?;
+            outputs.push((input.clone(), register));
+        }
+        Ok(Program {
+            ops: compiler.ops,
+            outputs,
+        })
+    }
+}
+
+// Whether a value of kind `found` can be used where `expected` is needed.
+fn check(node: &str, input: &str, expected: Kind, found: Kind) -> Result<(), ShaderError> {
+    match (expected, found) {
+        (Kind::Color, Kind::Float) => Ok(()),
+        _ if expected == found => Ok(()),
+        _ => Err(ShaderError::Mismatch {
+            node: node.to_string(),
+            input: input.to_string(),
+            expected,
+            found,
+        }),
+    }
+}
+
+struct Compiler<'a> {
+    graph: &'a ShaderGraph,
+    compiled: HashMap<&'a str, (usize, Kind)>,
+    visiting: HashSet<&'a str>,
+    ops: Vec<Op>,
+}
+
+impl<'a> Compiler<'a> {
+    // Compiles a node after the nodes it depends on, returning its register
+    // and the kind of its value.
+    fn visit(&mut self, name: &'a str) -> Result<(usize, Kind), ShaderError> {
+        if let Some(compiled) = self.compiled.get(name) {
+            return Ok(*compiled);
+        }
+        let node = self
+            .graph
+            .nodes
+            .get(name)
+            .ok_or_else(|| ShaderError::UnknownNode(name.to_string()))?;
+        if !self.visiting.insert(name) {
+            return Err(ShaderError::Cycle(name.to_string()));
+        }
+        let (op, kind) = match node {
+            Node::Float(value) => (Op::Constant([*value; 3]), Kind::Float),
+            Node::Color(color) => (Op::Constant(color.map(|c| c as f64)), Kind::Color),
+            Node::Transform {
+                uv,
+                scale,
+                offset,
+                rotation,
+            } => {
+                let (sin, cos) = rotation.to_radians().sin_cos();
+                let matrix = [
+                    [cos * scale[0], -sin * scale[1], offset[0]],
+                    [sin * scale[0], cos * scale[1], offset[1]],
+                ];
+                let uv = self.uv(name, uv)?;
+                (Op::Transform { uv, matrix }, Kind::Uv)
+            }
+            Node::Image { path, uv } => {
+                let image = Image::load(path).map_err(|e| ShaderError::Image(path.clone(), e))?;
+                let uv = self.uv(name, uv)?;
+                (Op::Image { uv, image }, Kind::Color)
+            }
+            Node::Noise { uv, scale, octaves } => {
+                if *octaves > MAX_OCTAVES {
+                    return Err(ShaderError::TooManyOctaves(name.to_string()));
+                }
+                let uv = self.uv(name, uv)?;
+                let op = Op::Noise {
+                    uv,
+                    scale: *scale,
+                    octaves: (*octaves).max(1),
+                };
+                (op, Kind::Float)
+            }
+            Node::Math { op, a, b } => {
+                let (a, a_kind) = self.operand(name, a)?;
+                let (b, b_kind) = self.operand(name, b)?;
+                let kind = match (a_kind, b_kind) {
+                    (Kind::Float, Kind::Float) => Kind::Float,
+                    _ => Kind::Color,
+                };
+                (Op::Math { op: *op, a, b }, kind)
+            }
+            Node::Ramp { input, stops } => {
+                if stops.is_empty() {
+                    return Err(ShaderError::EmptyRamp(name.to_string()));
+                }
+                let (register, found) = self.visit(input)?;
+                check(name, input, Kind::Float, found)?;
+                let mut stops: Vec<(f64, [f64; 3])> = stops
+                    .iter()
+                    .map(|(position, color)| (*position, color.map(|c| c as f64)))
+                    .collect();
+                stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+                (
+                    Op::Ramp {
+                        input: register,
+                        stops,
+                    },
+                    Kind::Color,
+                )
+            }
+        };
+        if self.ops.len() == MAX_NODES {
+            return Err(ShaderError::TooManyNodes);
+        }
+        self.visiting.remove(name);
+        let register = self.ops.len();
+        self.ops.push(op);
+        self.compiled.insert(name, (register, kind));
+        Ok((register, kind))
+    }
+
+    // Register of the texture coordinates feeding a node, or None for the
+    // coordinates of the hit.
+    fn uv(&mut self, node: &str, uv: &'a Option<String>) -> Result<Option<usize>, ShaderError> {
+        match uv {
+            Some(input) => {
+                let (register, found) = self.visit(input)?;
+                check(node, input, Kind::Uv, found)?;
+                Ok(Some(register))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn operand(
+        &mut self,
+        node: &str,
+        operand: &'a Operand,
+    ) -> Result<(Argument, Kind), ShaderError> {
+        match operand {
+            Operand::Constant(value) => Ok((Argument::Constant(*value), Kind::Float)),
+            Operand::Node(input) => {
+                let (register, found) = self.visit(input)?;
+                check(node, input, Kind::Color, found)?;
+                Ok((Argument::Register(register), found))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Argument {
+    Constant(f64),
+    Register(usize),
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Constant([f64; 3]),
+    Transform {
+        uv: Option<usize>,
+        matrix: [[f64; 3]; 2],
+    },
+    Image {
+        uv: Option<usize>,
+        image: Image,
+    },
+    Noise {
+        uv: Option<usize>,
+        scale: f64,
+        octaves: u32,
+    },
+    Math {
+        op: MathOp,
+        a: Argument,
+        b: Argument,
+    },
+    Ramp {
+        input: usize,
+        stops: Vec<(f64, [f64; 3])>,
+    },
+}
+
+// A compiled graph.
+#[derive(Debug, Clone)]
+pub struct Program {
+    ops: Vec<Op>,
+    // Inputs of the material and the registers holding their values.
+    outputs: Vec<(String, usize)>,
+}
+
+impl Program {
+    // Runs the graph at texture coordinates `u` and `v`, passing the value
+    // of each input of the material to `set`.
+    pub fn evaluate(&self, u: f64, v: f64, mut set: impl FnMut(&str, [f64; 3])) {
+        let mut registers = [[0.0; 3]; MAX_NODES];
+        for (i, op) in self.ops.iter().enumerate() {
+            let uv = |source: &Option<usize>, registers: &[[f64; 3]]| match source {
+                Some(register) => (registers[*register][0], registers[*register][1]),
+                None => (u, v),
+            };
+            registers[i] = match op {
+                Op::Constant(value) => *value,
+                Op::Transform { uv: source, matrix } => {
+                    let (u, v) = uv(source, &registers);
+                    [
+                        matrix[0][0] * u + matrix[0][1] * v + matrix[0][2],
+                        matrix[1][0] * u + matrix[1][1] * v + matrix[1][2],
+                        0.0,
+                    ]
+                }
+                Op::Image { uv: source, image } => {
+                    let (u, v) = uv(source, &registers);
+                    image.color(u, v)
+                }
+                Op::Noise {
+                    uv: source,
+                    scale,
+                    octaves,
+                } => {
+                    let (u, v) = uv(source, &registers);
+                    [fractal_noise(u * scale, v * scale, *scale, *octaves); 3]
+                }
+                Op::Math { op, a, b } => {
+                    let argument = |argument: &Argument| match argument {
+                        Argument::Constant(value) => [*value; 3],
+                        Argument::Register(register) => registers[*register],
+                    };
+                    let (a, b) = (argument(a), argument(b));
+                    [0, 1, 2].map(|c| math(*op, a[c], b[c]))
+                }
+                Op::Ramp { input, stops } => ramp(stops, registers[*input][0]),
+            };
+        }
+        for (input, register) in &self.outputs {
+            set(input, registers[*register]);
+        }
+    }
+
+    // Whether the graph feeds the input of the material named `input`.
+    pub fn feeds(&self, input: &str) -> bool {
+        self.outputs.iter().any(|(name, _)| name == input)
+    }
+}
+
+fn math(op: MathOp, a: f64, b: f64) -> f64 {
+    match op {
+        MathOp::Add => a + b,
+        MathOp::Subtract => a - b,
+        MathOp::Multiply => a * b,
+        MathOp::Divide if b == 0.0 => 0.0,
+        MathOp::Divide => a / b,
+        MathOp::Minimum => a.min(b),
+        MathOp::Maximum => a.max(b),
+        MathOp::Power => a.max(0.0).powf(b),
+    }
+}
+
+// Stops are sorted by position; values beyond the ends take their colors.
+fn ramp(stops: &[(f64, [f64; 3])], t: f64) -> [f64; 3] {
+    let next = stops.partition_point(|(position, _)| *position <= t);
+    if next == 0 {
+        return stops[0].1;
+    }
+    if next == stops.len() {
+        return stops[next - 1].1;
+    }
+    let (p0, c0) = stops[next - 1];
+    let (p1, c1) = stops[next];
+    let s = (t - p0) / (p1 - p0);
+    [0, 1, 2].map(|c| c0[c] + (c1[c] - c0[c]) * s)
+}
+
+// Pseudo-random value between 0 and 1 at a lattice point.
+fn lattice(x: i64, y: i64) -> f64 {
+    let mut h = (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ (y as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f);
+    h ^= h >> 29;
+    h = h.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h ^= h >> 32;
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Smoothly interpolated lattice values, repeating every `period` cells.
+fn value_noise(x: f64, y: f64, period: i64) -> f64 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let smooth = |t: f64| t * t * (3.0 - 2.0 * t);
+    let (sx, sy) = (smooth(x - x0), smooth(y - y0));
+    let (ix, iy) = (
+        (x0 as i64).rem_euclid(period),
+        (y0 as i64).rem_euclid(period),
+    );
+    let at = |dx: i64, dy: i64| lattice((ix + dx).rem_euclid(period), (iy + dy).rem_euclid(period));
+    let bottom = at(0, 0) + (at(1, 0) - at(0, 0)) * sx;
+    let top = at(0, 1) + (at(1, 1) - at(0, 1)) * sx;
+    bottom + (top - bottom) * sy
+}
+
+// Octaves of value noise, each twice as fine and half as strong as the
+// previous one, normalized back to between 0 and 1.
+fn fractal_noise(x: f64, y: f64, scale: f64, octaves: u32) -> f64 {
+    let period = (scale.round() as i64).max(1);
+    let (mut sum, mut total, mut amplitude, mut frequency) = (0.0, 0.0, 1.0, 1.0);
+    for octave in 0..octaves {
+        let period = match 1i64.checked_shl(octave).and_then(|f| period.checked_mul(f)) {
+            Some(period) => period,
+            None => break,
+        };
+        sum += amplitude * value_noise(x * frequency, y * frequency, period);
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / total
+}
+
+// Image read by the nearest pixel, repeated outside the unit square.
+#[derive(Debug, Clone)]
+struct Image {
+    pixels: Vec<[f64; 3]>,
+    width: usize,
+    height: usize,
+}
+
+impl Image {
+    fn load(path: &str) -> Result<Image, io::Error> {
+        let file = File::open(path)?;
+        let mut decoder = Decoder::new(BufReader::new(file));
+        let pixels = decoder
+            .decode()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let metadata = decoder.info().unwrap();
+        let (width, height) = (metadata.width as usize, metadata.height as usize);
+        let channels = (pixels.len() / (width * height).max(1)).max(1);
+        let pixels = pixels
+            .chunks(channels)
+            .map(|p| [0, 1, 2].map(|c| p[c.min(channels - 1)] as f64 / 255.0))
+            .collect();
+        Ok(Image {
+            pixels,
+            width,
+            height,
+        })
+    }
+
+    fn color(&self, u: f64, v: f64) -> [f64; 3] {
+        let (u, v) = (u.rem_euclid(1.0), v.rem_euclid(1.0));
+        let x = ((u * self.width as f64) as usize).min(self.width - 1);
+        let y = (((1.0 - v) * self.height as f64) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x]
+    }
+}
+
+#[cfg(test)]
+fn color_input(input: &str) -> Option<Kind> {
+    match input {
+        "albedo" => Some(Kind::Color),
+        "roughness" => Some(Kind::Float),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+fn evaluate_at(program: &Program, u: f64, v: f64) -> HashMap<String, [f64; 3]> {
+    let mut values = HashMap::new();
+    program.evaluate(u, v, |input, value| {
+        values.insert(input.to_string(), value);
+    });
+    values
+}
+
+#[test]
+fn test_shader_graph() {
+    // a ramp over stretched noise, and a number which a color input takes
+    // as a gray
+    let graph = ShaderGraph::new()
+        .with_node(
+            "uv",
+            Node::Transform {
+                uv: None,
+                scale: [2.0, 1.0],
+                offset: [0.0, 0.0],
+                rotation: 0.0,
+            },
+        )
+        .with_node(
+            "noise",
+            Node::Noise {
+                uv: Some("uv".to_string()),
+                scale: 4.0,
+                octaves: 3,
+            },
+        )
+        .with_node(
+            "ramp",
+            Node::Ramp {
+                input: "noise".to_string(),
+                stops: vec![(0.0, [0.0, 0.0, 0.0]), (1.0, [1.0, 0.5, 0.0])],
+            },
+        )
+        .with_node(
+            "half",
+            Node::Math {
+                op: MathOp::Multiply,
+                a: Operand::Node("noise".to_string()),
+                b: Operand::Constant(0.5),
+            },
+        )
+        .with_node("unused", Node::Float(1.0))
+        .with_input("albedo", "ramp")
+        .with_input("roughness", "half");
+    let program = graph.compile("Lambertian", color_input).unwrap();
+    assert_eq!(program.ops.len(), 4);
+    assert!(program.feeds("albedo") && !program.feeds("sigma"));
+    for (u, v) in [(0.1, 0.2), (0.5, 0.5), (0.93, 0.7)] {
+        let values = evaluate_at(&program, u, v);
+        let (albedo, roughness) = (values["albedo"], values["roughness"]);
+        assert!((0.0..=0.5).contains(&roughness[0]));
+        assert!((albedo[0] - 2.0 * roughness[0]).abs() < 1e-9);
+        assert!((albedo[1] - roughness[0]).abs() < 1e-9);
+        assert_eq!(albedo[2], 0.0);
+    }
+    // the noise wraps around the seam
+    let (left, right) = (
+        evaluate_at(&program, 0.0, 0.3),
+        evaluate_at(&program, 1.0, 0.3),
+    );
+    assert!((left["roughness"][0] - right["roughness"][0]).abs() < 1e-9);
+
+    // images are read through transformed coordinates and repeat
+    let graph = ShaderGraph::new()
+        .with_node(
+            "uv",
+            Node::Transform {
+                uv: None,
+                scale: [1.0, 1.0],
+                offset: [1.0, 0.0],
+                rotation: 0.0,
+            },
+        )
+        .with_node(
+            "earth",
+            Node::Image {
+                path: "data/earth.jpg".to_string(),
+                uv: Some("uv".to_string()),
+            },
+        )
+        .with_input("albedo", "earth");
+    let shifted = evaluate_at(
+        &graph.compile("Lambertian", color_input).unwrap(),
+        0.25,
+        0.5,
+    );
+    let graph = graph.with_node(
+        "earth",
+        Node::Image {
+            path: "data/earth.jpg".to_string(),
+            uv: None,
+        },
+    );
+    let plain = evaluate_at(
+        &graph.compile("Lambertian", color_input).unwrap(),
+        0.25,
+        0.5,
+    );
+    assert_eq!(shifted["albedo"], plain["albedo"]);
+
+    // the graph reads the same from JSON
+    let json = r#"{
+        "nodes": {
+            "a": {"Float": 0.25},
+            "b": {"Math": {"op": "Add", "a": "a", "b": 0.5}}
+        },
+        "inputs": {"roughness": "b"}
+    }"#;
+    let graph: ShaderGraph = serde_json::from_str(json).unwrap();
+    let values = evaluate_at(&graph.compile("Lambertian", color_input).unwrap(), 0.0, 0.0);
+    assert_eq!(values["roughness"], [0.75; 3]);
+}
+
+#[test]
+fn test_shader_graph_errors() {
+    let compile = |graph: ShaderGraph| graph.compile("Lambertian", color_input).unwrap_err();
+    let loop_graph = ShaderGraph::new()
+        .with_node(
+            "a",
+            Node::Math {
+                op: MathOp::Add,
+                a: Operand::Node("b".to_string()),
+                b: Operand::Constant(1.0),
+            },
+        )
+        .with_node(
+            "b",
+            Node::Math {
+                op: MathOp::Add,
+                a: Operand::Node("a".to_string()),
+                b: Operand::Constant(1.0),
+            },
+        )
+        .with_input("roughness", "a");
+    assert!(matches!(compile(loop_graph), ShaderError::Cycle(_)));
+    let missing = ShaderGraph::new().with_input("roughness", "nothing");
+    assert!(matches!(compile(missing), ShaderError::UnknownNode(_)));
+    let unknown = ShaderGraph::new()
+        .with_node("a", Node::Float(1.0))
+        .with_input("shininess", "a");
+    assert!(matches!(
+        compile(unknown),
+        ShaderError::UnknownInput("Lambertian", _)
+    ));
+    // a color can't feed a number, nor a number texture coordinates
+    let color = ShaderGraph::new()
+        .with_node("a", Node::Color([1.0, 0.0, 0.0]))
+        .with_input("roughness", "a");
+    assert!(matches!(compile(color), ShaderError::Mismatch { .. }));
+    let uv = ShaderGraph::new()
+        .with_node("a", Node::Float(1.0))
+        .with_node(
+            "b",
+            Node::Noise {
+                uv: Some("a".to_string()),
+                scale: 1.0,
+                octaves: 1,
+            },
+        )
+        .with_input("roughness", "b");
+    assert!(matches!(compile(uv), ShaderError::Mismatch { .. }));
+    let ramp = ShaderGraph::new()
+        .with_node("a", Node::Float(1.0))
+        .with_node(
+            "b",
+            Node::Ramp {
+                input: "a".to_string(),
+                stops: Vec::new(),
+            },
+        )
+        .with_input("albedo", "b");
+    assert!(matches!(compile(ramp), ShaderError::EmptyRamp(_)));
+    let noise = |octaves| {
+        ShaderGraph::new()
+            .with_node(
+                "a",
+                Node::Noise {
+                    uv: None,
+                    scale: 1e18,
+                    octaves,
+                },
+            )
+            .with_input("roughness", "a")
+    };
+    assert!(matches!(compile(noise(64)), ShaderError::TooManyOctaves(_)));
+    let program = noise(MAX_OCTAVES)
+        .compile("Lambertian", color_input)
+        .unwrap();
+    assert!((0.0..=1.0).contains(&evaluate_at(&program, 0.3, 0.6)["roughness"][0]));
+}
@@ -0,0 +1,252 @@
+use palette::Srgb;
+use std::sync::OnceLock;
+
+use crate::blackbody::cie_xyz;
+use crate::color;
+use crate::config::Config;
+use crate::integrator::Integrator;
+use crate::ray::Ray;
+use crate::sampler::random_f64;
+use crate::sphere::Sphere;
+
+#[cfg(test)]
+use crate::camera::Camera;
+#[cfg(test)]
+use crate::config::Sky;
+#[cfg(test)]
+use crate::integrator::IntegratorKind;
+#[cfg(test)]
+use crate::integrator::PathTracer;
+#[cfg(test)]
+use crate::materials::Lambertian;
+#[cfg(test)]
+use crate::materials::Material;
+#[cfg(test)]
+use crate::point3d::Point3D;
+
+// Spectral rendering: each camera ray carries a single wavelength, sampled
+// uniformly over the visible range. Colors of albedos and lights are
+// upsampled to spectra with a smooth red, green and blue basis that sums to
+// one, so white stays flat. The value found at the wavelength is weighted
+// back into RGB so that the expected color of any upsampled spectrum is
+// exactly the RGB color it came from.
+
+pub const MIN_WAVELENGTH: f64 = 380.0;
+pub const MAX_WAVELENGTH: f64 = 780.0;
+
+pub fn sample_wavelength(u: f64) -> f64 {
+    MIN_WAVELENGTH + u * (MAX_WAVELENGTH - MIN_WAVELENGTH)
+}
+
+// Weights of the red, green and blue basis spectra at a wavelength.
+fn basis(lambda: f64) -> [f64; 3] {
+    // smooth steps between the bands, 40nm wide
+    let step = |edge: f64| {
+        let t = ((lambda - edge) / 40.0 + 0.5).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    };
+    let red = step(590.0);
+    let blue = 1.0 - step(490.0);
+    [red, 1.0 - red - blue, blue]
+}
+
+// Value at a wavelength of the spectrum upsampled from a linear color.
+pub fn rgb_to_spectrum(color: Srgb, lambda: f64) -> f32 {
+    let [r, g, b] = basis(lambda);
+    (color.red as f64 * r + color.green as f64 * g + color.blue as f64 * b) as f32
+}
+
+// Color of a reflectance or a light as seen by a ray: in spectral mode, the
+// value of its spectrum at the wavelength of the ray, as a gray.
+pub fn at_wavelength(color: Srgb, wavelength: Option<f64>) -> Srgb {
+    match wavelength {
+        Some(lambda) => {
+            let value = rgb_to_spectrum(color, lambda);
+            Srgb::new(value, value, value)
+        }
+        None => color,
+    }
+}
+
+fn xyz_to_rgb(x: f64, y: f64, z: f64) -> [f64; 3] {
+    [
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    ]
+}
+
+fn invert(m: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let cofactor = |r: usize, c: usize| {
+        let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+        let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    let det = m[0][0] * cofactor(0, 0) + m[0][1] * cofactor(0, 1) + m[0][2] * cofactor(0, 2);
+    let mut inverse = [[0.0; 3]; 3];
+    for (r, row) in inverse.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = cofactor(c, r) / det;
+        }
+    }
+    inverse
+}
+
+// Matrix taking the color matching functions, in linear RGB, to weights
+// which reproduce the colors of the basis spectra.
+fn calibration() -> &'static [[f64; 3]; 3] {
+    static CALIBRATION: OnceLock<[[f64; 3]; 3]> = OnceLock::new();
+    CALIBRATION.get_or_init(|| {
+        let mut m = [[0.0; 3]; 3];
+        let mut lambda = MIN_WAVELENGTH + 0.5;
+        while lambda < MAX_WAVELENGTH {
+            let (x, y, z) = cie_xyz(lambda);
+            let rgb = xyz_to_rgb(x, y, z);
+            let weights = basis(lambda);
+            for (row, value) in m.iter_mut().zip(rgb) {
+                for (entry, weight) in row.iter_mut().zip(weights) {
+                    *entry += value * weight;
+                }
+            }
+            lambda += 1.0;
+        }
+        invert(m)
+    })
+}
+
+// Color contributed by a spectral value found at a uniformly sampled
+// wavelength, divided by the probability density of that wavelength.
+pub fn spectrum_to_rgb(value: f32, lambda: f64) -> Srgb {
+    let (x, y, z) = cie_xyz(lambda);
+    let rgb = xyz_to_rgb(x, y, z);
+    let m = calibration();
+    let scale = value as f64 * (MAX_WAVELENGTH - MIN_WAVELENGTH);
+    let channel =
+        |row: &[f64; 3]| ((row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2]) * scale) as f32;
+    Srgb::new(channel(&m[0]), channel(&m[1]), channel(&m[2]))
+}
+
+// Linear color of a reflectance spectrum, from its values at `steps`
+// wavelengths spread evenly over the visible range. Saturated spectra can
+// fall outside the RGB gamut, with negative channels.
+pub fn reflectance_to_rgb(reflectance: impl Fn(f64) -> f64, steps: usize) -> Srgb {
+    (0..steps).fold(color::black(), |sum, i| {
+        let lambda = sample_wavelength((i as f64 + 0.5) / steps as f64);
+        let color = spectrum_to_rgb(reflectance(lambda) as f32, lambda);
+        color::add(sum, color::scale(color, 1.0 / steps as f32))
+    })
+}
+
+// Color along a camera ray, at a single sampled wavelength if the scene is
+// rendered in spectral mode.
+pub fn sample_ray_color(
+    integrator: &dyn Integrator,
+    ray: &Ray,
+    scene: &Config,
+    lights: &[Sphere],
+) -> Srgb {
+    if !scene.spectral || !scene.integrator.renders_light() {
+        return integrator.ray_color(ray, scene, lights);
+    }
+    let lambda = sample_wavelength(random_f64());
+    let color = integrator.ray_color(&ray.with_wavelength(Some(lambda)), scene, lights);
+    spectrum_to_rgb(rgb_to_spectrum(color, lambda), lambda)
+}
+
+#[test]
+fn test_spectrum_round_trip() {
+    let steps = 4000;
+    assert_eq!(rgb_to_spectrum(Srgb::new(1.0, 1.0, 1.0), 500.0), 1.0);
+    for color in [
+        Srgb::new(1.0, 1.0, 1.0),
+        Srgb::new(0.8, 0.3, 0.1),
+        Srgb::new(0.0, 0.0, 1.0),
+    ] {
+        let mut sum = color::black();
+        for i in 0..steps {
+            let lambda = sample_wavelength((i as f64 + 0.5) / steps as f64);
+            let c = spectrum_to_rgb(rgb_to_spectrum(color, lambda), lambda);
+            sum = color::add(sum, color::scale(c, 1.0 / steps as f32));
+        }
+        assert!(
+            (sum.red - color.red).abs() < 1e-3,
+            "{:?} != {:?}",
+            sum,
+            color
+        );
+        assert!(
+            (sum.green - color.green).abs() < 1e-3,
+            "{:?} != {:?}",
+            sum,
+            color
+        );
+        assert!(
+            (sum.blue - color.blue).abs() < 1e-3,
+            "{:?} != {:?}",
+            sum,
+            color
+        );
+    }
+
+    // a flat reflectance is a gray
+    let gray = reflectance_to_rgb(|_| 0.5, 64);
+    for channel in [gray.red, gray.green, gray.blue] {
+        assert!((channel - 0.5).abs() < 1e-2, "{:?}", gray);
+    }
+
+    // short wavelengths look blue, long ones red
+    let violet = spectrum_to_rgb(1.0, 450.0);
+    assert!(violet.blue > violet.red && violet.blue > violet.green);
+    let red = spectrum_to_rgb(1.0, 650.0);
+    assert!(red.red > red.green && red.red > red.blue);
+}
+
+#[test]
+fn test_spectral_path_tracer_matches_rgb() {
+    let scene = Config {
+        width: 8,
+        height: 6,
+        samples_per_pixel: 1,
+        max_depth: 4,
+        spectral: true,
+        sky: Some(Sky::new_default_sky()),
+        fog: None,
+        volumes: Vec::new(),
+        camera: Camera::new(
+            Point3D::new(0.0, 1.0, 3.0),
+            Point3D::new(0.0, 0.0, 0.0),
+            Point3D::new(0.0, 1.0, 0.0),
+            60.0,
+            8.0 / 6.0,
+        ),
+        integrator: IntegratorKind::Path,
+        objects: vec![Sphere::new(
+            Point3D::new(0.0, 0.0, 0.0),
+            1.0,
+            Material::Lambertian(Lambertian::new(Srgb::new(0.8, 0.4, 0.2))),
+        )],
+    };
+    let tracer = PathTracer::new();
+    let ray = Ray::new(Point3D::new(0.0, 0.0, 3.0), Point3D::new(0.0, 0.0, -1.0));
+    let samples = 40000;
+    let mut spectral = color::black();
+    let mut rgb = color::black();
+    for _ in 0..samples {
+        let scale = 1.0 / samples as f32;
+        let c = sample_ray_color(&tracer, &ray, &scene, &[]);
+        spectral = color::add(spectral, color::scale(c, scale));
+        rgb = color::add(
+            rgb,
+            color::scale(tracer.ray_color(&ray, &scene, &[]), scale),
+        );
+    }
+    // the upsampled albedos multiply per wavelength, which isn't the same
+    // as multiplying the colors, so only the overall brightness matches
+    let (spectral, rgb) = (color::luminance(spectral), color::luminance(rgb));
+    assert!(
+        (spectral - rgb).abs() < 0.05 * rgb,
+        "{} != {}",
+        spectral,
+        rgb
+    );
+}
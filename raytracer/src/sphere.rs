@@ -32,6 +32,24 @@ impl Sphere {
     }
 }
 
+impl Sphere {
+    // Hit record at the point of the surface along the outward unit
+    // `normal`, as seen from outside.
+    pub fn surface_hit(&self, normal: Point3D) -> HitRecord<'_> {
+        let (u, v) = u_v_from_sphere_hit_point(normal);
+        HitRecord {
+            t: 0.0,
+            point: self.center + normal * self.radius,
+            normal,
+            front_face: true,
+            material: &self.material,
+            u,
+            v,
+            wavelength: None,
+        }
+    }
+}
+
 fn u_v_from_sphere_hit_point(hit_point_on_sphere: Point3D) -> (f64, f64) {
     let n = hit_point_on_sphere.unit_vector();
     let x = n.x();
@@ -70,6 +88,7 @@ impl Hittable for Sphere {
                         material: &self.material,
                         u,
                         v,
+                        wavelength: ray.wavelength,
                     });
                 }
             }
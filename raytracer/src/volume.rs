@@ -0,0 +1,371 @@
+use palette::Srgb;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+use crate::blackbody::blackbody_color;
+use crate::blackbody::blackbody_luminance;
+use crate::color;
+use crate::medium::HenyeyGreenstein;
+use crate::point3d::Point3D;
+use crate::ray::Ray;
+use crate::sampler::random_f64;
+
+#[cfg(test)]
+use assert_approx_eq::assert_approx_eq;
+
+// Dense voxel grid. The file starts with a JSON header on its own line,
+// e.g. {"resolution":[64,32,64],"channels":["density","temperature"]},
+// followed by one grid of little endian 32-bit floats per channel, with x
+// varying fastest and z slowest. Temperatures are in kelvin.
+#[derive(Debug, Clone)]
+pub struct VoxelGrid {
+    pub resolution: [usize; 3],
+    density: Vec<f32>,
+    temperature: Option<Vec<f32>>,
+    max_density: f32,
+    path: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct VoxelGridHeader {
+    resolution: [usize; 3],
+    channels: Vec<String>,
+}
+
+impl VoxelGrid {
+    pub fn new(resolution: [usize; 3], density: Vec<f32>, temperature: Option<Vec<f32>>) -> Self {
+        let max_density = density.iter().cloned().fold(0.0, f32::max);
+        VoxelGrid {
+            resolution,
+            density,
+            temperature,
+            max_density,
+            path: String::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<VoxelGrid, Error> {
+        let mut grid = VoxelGrid::decode(&fs::read(path)?)?;
+        grid.path = path.to_string();
+        Ok(grid)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<VoxelGrid, Error> {
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+        let end = bytes
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| invalid("missing voxel grid header"))?;
+        let header: VoxelGridHeader = serde_json::from_slice(&bytes[..end])?;
+        let count = header.resolution.iter().product::<usize>();
+        let data = &bytes[end + 1..];
+        if count == 0 || data.len() != count * 4 * header.channels.len() {
+            return Err(invalid("voxel grid size does not match its header"));
+        }
+        let channel = |name: &str| {
+            header.channels.iter().position(|c| c == name).map(|i| {
+                data[i * count * 4..(i + 1) * count * 4]
+                    .chunks(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect::<Vec<f32>>()
+            })
+        };
+        let density = channel("density").ok_or_else(|| invalid("voxel grid has no density"))?;
+        Ok(VoxelGrid::new(
+            header.resolution,
+            density,
+            channel("temperature"),
+        ))
+    }
+
+    // Trilinear interpolation between voxel centers, at a position in the
+    // unit cube.
+    fn lookup(&self, values: &[f32], p: &Point3D) -> f32 {
+        let [nx, ny, nz] = self.resolution;
+        let axis = |x: f64, n: usize| {
+            let x = (x * n as f64 - 0.5).clamp(0.0, (n - 1) as f64);
+            let i = (x as usize).min(n.saturating_sub(2));
+            (i, (i + 1).min(n - 1), (x - i as f64) as f32)
+        };
+        let (x0, x1, fx) = axis(p.x(), nx);
+        let (y0, y1, fy) = axis(p.y(), ny);
+        let (z0, z1, fz) = axis(p.z(), nz);
+        let v = |x: usize, y: usize, z: usize| values[(z * ny + y) * nx + x];
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        lerp(
+            lerp(
+                lerp(v(x0, y0, z0), v(x1, y0, z0), fx),
+                lerp(v(x0, y1, z0), v(x1, y1, z0), fx),
+                fy,
+            ),
+            lerp(
+                lerp(v(x0, y0, z1), v(x1, y0, z1), fx),
+                lerp(v(x0, y1, z1), v(x1, y1, z1), fx),
+                fy,
+            ),
+            fz,
+        )
+    }
+}
+
+serde_with::serde_conv!(
+    VoxelGridAsPath,
+    VoxelGrid,
+    |grid: &VoxelGrid| grid.path.clone(),
+    |value: &str| -> Result<_, Error> { VoxelGrid::load(value) }
+);
+
+// A voxel grid placed in an axis aligned box, as a medium whose extinction
+// is `density` times the grid's density. Voxels with a temperature emit
+// thermal radiation, scaled so that `emission` is the radiance at 1000 K.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Volume {
+    #[serde_as(as = "VoxelGridAsPath")]
+    pub grid: VoxelGrid,
+    pub min: Point3D,
+    pub max: Point3D,
+    pub density: f64,
+    // fraction of the extinction that is scattered rather than absorbed
+    pub albedo: f64,
+    #[serde(default)]
+    pub anisotropy: f64,
+    #[serde(default)]
+    pub emission: f32,
+}
+
+// What light traveling along a ray runs into in a volume.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VolumeEvent {
+    Scatter(f64),
+    // absorbed, along with the light emitted at that point
+    Absorb(f64, Srgb),
+}
+
+impl Volume {
+    pub fn new(grid: VoxelGrid, min: Point3D, max: Point3D, density: f64, albedo: f64) -> Volume {
+        Volume {
+            grid,
+            min,
+            max,
+            density,
+            albedo,
+            anisotropy: 0.0,
+            emission: 0.0,
+        }
+    }
+
+    pub fn phase_function(&self) -> HenyeyGreenstein {
+        HenyeyGreenstein::new(self.anisotropy)
+    }
+
+    // Ray parameters at which the ray enters and leaves the box, clipped
+    // to [0, t_max].
+    fn intersect(&self, ray: &Ray, t_max: f64) -> Option<(f64, f64)> {
+        let mut t0: f64 = 0.0;
+        let mut t1 = t_max;
+        let axes = [
+            (
+                ray.origin.x(),
+                ray.direction.x(),
+                self.min.x(),
+                self.max.x(),
+            ),
+            (
+                ray.origin.y(),
+                ray.direction.y(),
+                self.min.y(),
+                self.max.y(),
+            ),
+            (
+                ray.origin.z(),
+                ray.direction.z(),
+                self.min.z(),
+                self.max.z(),
+            ),
+        ];
+        for (origin, direction, min, max) in axes {
+            let (a, b) = ((min - origin) / direction, (max - origin) / direction);
+            if a.is_nan() || b.is_nan() {
+                // parallel to the slab and on its boundary
+                continue;
+            }
+            t0 = t0.max(a.min(b));
+            t1 = t1.min(a.max(b));
+            if t0 > t1 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+
+    fn local(&self, p: &Point3D) -> Point3D {
+        let size = self.max - self.min;
+        let d = *p - self.min;
+        Point3D::new(d.x() / size.x(), d.y() / size.y(), d.z() / size.z())
+    }
+
+    fn extinction(&self, p: &Point3D) -> f64 {
+        self.density * self.grid.lookup(&self.grid.density, &self.local(p)) as f64
+    }
+
+    fn majorant(&self) -> f64 {
+        self.density * self.grid.max_density as f64
+    }
+
+    fn emitted(&self, p: &Point3D) -> Srgb {
+        match &self.grid.temperature {
+            Some(temperature) if self.emission > 0.0 => {
+                let kelvin = self.grid.lookup(temperature, &self.local(p)) as f64;
+                let radiance = self.emission * blackbody_luminance(kelvin) as f32;
+                color::scale(blackbody_color(kelvin), radiance)
+            }
+            _ => color::black(),
+        }
+    }
+
+    // Delta tracking: samples the first real collision along the ray before
+    // `t_max` against the constant majorant, rejecting the null collisions.
+    pub fn sample_interaction(&self, ray: &Ray, t_max: f64) -> Option<VolumeEvent> {
+        let majorant = self.majorant();
+        if majorant <= 0.0 {
+            return None;
+        }
+        let (mut t, t1) = self.intersect(ray, t_max)?;
+        let rate = majorant * ray.direction.length();
+        loop {
+            t -= (1.0 - random_f64()).ln() / rate;
+            if t >= t1 {
+                return None;
+            }
+            let p = ray.at(t);
+            if random_f64() * majorant < self.extinction(&p) {
+                return match random_f64() < self.albedo {
+                    true => Some(VolumeEvent::Scatter(t)),
+                    false => Some(VolumeEvent::Absorb(t, self.emitted(&p))),
+                };
+            }
+        }
+    }
+
+    // Ratio tracking: an unbiased estimate of the fraction of light passing
+    // along the ray up to `t_max`.
+    pub fn transmittance(&self, ray: &Ray, t_max: f64) -> f64 {
+        let majorant = self.majorant();
+        let (mut t, t1) = match self.intersect(ray, t_max) {
+            Some(range) if majorant > 0.0 => range,
+            _ => return 1.0,
+        };
+        let rate = majorant * ray.direction.length();
+        let mut transmittance = 1.0;
+        loop {
+            t -= (1.0 - random_f64()).ln() / rate;
+            if t >= t1 {
+                return transmittance;
+            }
+            transmittance *= 1.0 - self.extinction(&ray.at(t)) / majorant;
+        }
+    }
+}
+
+#[cfg(test)]
+fn encode_grid(resolution: [usize; 3], channels: &[(&str, Vec<f32>)]) -> Vec<u8> {
+    let names: Vec<&str> = channels.iter().map(|c| c.0).collect();
+    let header = serde_json::json!({ "resolution": resolution, "channels": names });
+    let mut bytes = format!("{}\n", header).into_bytes();
+    for (_, values) in channels {
+        for v in values {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+    bytes
+}
+
+#[test]
+fn test_voxel_grid() {
+    let density: Vec<f32> = (0..8).map(|i| i as f32).collect();
+    let bytes = encode_grid(
+        [2, 2, 2],
+        &[("temperature", vec![1500.0; 8]), ("density", density)],
+    );
+    let grid = VoxelGrid::decode(&bytes).unwrap();
+    assert_eq!(grid.max_density, 7.0);
+    assert!(grid.temperature.is_some());
+    // voxel centers hold their values, the center of the grid the average
+    assert_eq!(
+        grid.lookup(&grid.density, &Point3D::new(0.75, 0.25, 0.25)),
+        1.0
+    );
+    assert_eq!(
+        grid.lookup(&grid.density, &Point3D::new(0.25, 0.25, 0.75)),
+        4.0
+    );
+    assert_eq!(
+        grid.lookup(&grid.density, &Point3D::new(0.5, 0.5, 0.5)),
+        3.5
+    );
+    // and values are clamped outside of them
+    assert_eq!(
+        grid.lookup(&grid.density, &Point3D::new(0.0, 0.0, 0.0)),
+        0.0
+    );
+
+    assert!(VoxelGrid::decode(&bytes[..bytes.len() - 4]).is_err());
+    assert!(VoxelGrid::decode(&encode_grid([1, 1, 1], &[("temperature", vec![1.0])])).is_err());
+}
+
+#[test]
+fn test_volume_tracking() {
+    // a slab of constant density 0.5 between x = 1 and x = 3
+    let grid = VoxelGrid::new([1, 1, 1], vec![1.0], None);
+    let volume = Volume::new(
+        grid,
+        Point3D::new(1.0, -1.0, -1.0),
+        Point3D::new(3.0, 1.0, 1.0),
+        0.5,
+        1.0,
+    );
+    let r = Ray::new(Point3D::new(0.0, 0.0, 0.0), Point3D::new(1.0, 0.0, 0.0));
+    let expected = (-1.0f64).exp();
+    let samples = 20000;
+    let passed = (0..samples)
+        .filter(|_| volume.sample_interaction(&r, f64::INFINITY).is_none())
+        .count();
+    assert_approx_eq!(passed as f64 / samples as f64, expected, 0.02);
+    let mean: f64 = (0..samples)
+        .map(|_| volume.transmittance(&r, f64::INFINITY))
+        .sum::<f64>()
+        / samples as f64;
+    assert_approx_eq!(mean, expected, 0.02);
+
+    // rays that stop before the volume or miss it pass unaffected
+    assert_eq!(volume.transmittance(&r, 0.5), 1.0);
+    let r = Ray::new(Point3D::new(0.0, 2.0, 0.0), Point3D::new(1.0, 0.0, 0.0));
+    assert_eq!(volume.sample_interaction(&r, f64::INFINITY), None);
+}
+
+#[test]
+fn test_volume_to_json() {
+    let bytes = encode_grid(
+        [1, 1, 1],
+        &[("density", vec![1.0]), ("temperature", vec![2000.0])],
+    );
+    fs::write("/tmp/test_volume.vol", bytes).unwrap();
+    let json = "{\"grid\":\"/tmp/test_volume.vol\",\"min\":{\"x\":0.0,\"y\":0.0,\"z\":0.0},\"max\":{\"x\":1.0,\"y\":1.0,\"z\":1.0},\"density\":2.0,\"albedo\":0.0,\"anisotropy\":0.0,\"emission\":0.5}";
+    let volume = serde_json::from_str::<Volume>(json).unwrap();
+    assert_eq!(serde_json::to_string(&volume).unwrap(), json);
+
+    // a hot, purely absorbing volume glows
+    let r = Ray::new(Point3D::new(0.5, 0.5, -1.0), Point3D::new(0.0, 0.0, 1.0));
+    match volume.sample_interaction(&r, f64::INFINITY) {
+        Some(VolumeEvent::Absorb(_, emitted)) => {
+            assert!(emitted.red > emitted.blue);
+            let expected = 0.5 * blackbody_luminance(2000.0);
+            assert_approx_eq!(color::luminance(emitted) as f64 / expected, 1.0, 1e-3);
+        }
+        Some(VolumeEvent::Scatter(_)) => panic!("scattered in an absorbing volume"),
+        None => {}
+    }
+}